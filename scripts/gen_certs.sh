@@ -0,0 +1,26 @@
+#!/bin/sh
+# Generates a CA, and a certificate signed by it for the nodes of a cluster, for RPC over TLS
+# Usage: gen_certs.sh <output dir> [server name]
+set -e
+
+DIR=${1:?usage: gen_certs.sh <output dir> [server name]}
+NAME=${2:-logstore}
+DAYS=3650
+
+mkdir -p "$DIR"
+cd "$DIR"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days $DAYS -subj "/CN=logstore CA" \
+    -keyout ca.key -out ca.pem
+
+openssl req -newkey rsa:2048 -nodes -subj "/CN=$NAME" -keyout node.rsa.key -out node.csr
+
+printf "subjectAltName=DNS:%s\nextendedKeyUsage=serverAuth,clientAuth\n" "$NAME" > node.ext
+
+openssl x509 -req -in node.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days $DAYS \
+    -extfile node.ext -out node.pem
+
+# rustls reads PKCS8 keys
+openssl pkcs8 -topk8 -nocrypt -in node.rsa.key -out node.key
+
+rm -f node.csr node.ext node.rsa.key ca.srl
@@ -0,0 +1,255 @@
+use std::cmp;
+
+use unicode_normalization::UnicodeNormalization;
+
+use ::log_value::LogValue;
+
+/// The low bits of a text field's posting hold the position of the term, below the location of the log
+pub const POSITION_BITS: u32 = 16;
+const MAX_POSITION: u64 = (1 << POSITION_BITS) - 1;
+
+/// The gap between the positions of the elements of an array, so a phrase doesn't match across them
+const POSITION_GAP: u32 = 100;
+
+/// Common English words dropped by the stop analyzer, the same as Lucene's
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it", "no", "not", "of",
+    "on", "or", "such", "that", "the", "their", "then", "there", "these", "they", "this", "to", "was", "will", "with"
+];
+
+/// How the values of a text field are split into terms, at index and query time
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Analyzer {
+    Standard,   // splits on anything but letters, digits and _, and lowercases
+    Whitespace, // splits on whitespace, keeping the case
+    Stop        // the standard analyzer, dropping stop words
+}
+
+impl Analyzer {
+    pub fn parse(name: &str) -> Option<Analyzer> {
+        match name {
+            "standard" => Some(Analyzer::Standard),
+            "whitespace" => Some(Analyzer::Whitespace),
+            "stop" => Some(Analyzer::Stop),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Analyzer::Standard => "standard",
+            Analyzer::Whitespace => "whitespace",
+            Analyzer::Stop => "stop"
+        }
+    }
+
+    /// Splits text into terms, each with its position
+    /// Stop words keep their positions, so a phrase only matches words that were next to each other
+    pub fn tokens(&self, text: &str) -> Vec<(String, u32)> {
+        let words = match *self {
+            Analyzer::Whitespace => text.split_whitespace().collect::<Vec<_>>(),
+            _ => text.split(|c: char| !c.is_alphanumeric() && c != '_').filter(|w| !w.is_empty()).collect()
+        };
+
+        words.into_iter().enumerate().filter_map(|(pos, word)| {
+            let term = match *self {
+                Analyzer::Whitespace => word.to_owned(),
+                _ => word.to_lowercase()
+            };
+
+            if *self == Analyzer::Stop && STOP_WORDS.contains(&term.as_str()) {
+                None
+            } else {
+                Some( (term, pos as u32) )
+            }
+        }).collect()
+    }
+
+    /// The terms of a value of a text field, the elements of an array are analyzed one after the other
+    pub fn value_tokens(&self, value: &LogValue) -> Vec<(String, u32)> {
+        match *value {
+            LogValue::String(ref text) => self.tokens(text),
+            LogValue::Array(ref values) => {
+                let mut ret = Vec::new();
+                let mut start = 0;
+
+                for value in values {
+                    let tokens = self.value_tokens(value);
+
+                    if let Some(&(_, last)) = tokens.last() {
+                        ret.extend(tokens.into_iter().map(|(term, pos)| (term, start + pos)));
+                        start += last + 1 + POSITION_GAP;
+                    }
+                }
+
+                ret
+            },
+            _ => Vec::new()
+        }
+    }
+}
+
+/// How the values of a keyword field are changed before they're indexed, and the values searched for
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Normalizer {
+    Lowercase,
+    Nfkc,      // Unicode compatibility composition, so `ｅｒｒｏｒ` and `error` are the same
+    Trim       // drops leading and trailing whitespace
+}
+
+impl Normalizer {
+    pub fn parse(name: &str) -> Option<Normalizer> {
+        match name {
+            "lowercase" => Some(Normalizer::Lowercase),
+            "nfkc" => Some(Normalizer::Nfkc),
+            "trim" => Some(Normalizer::Trim),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Normalizer::Lowercase => "lowercase",
+            Normalizer::Nfkc => "nfkc",
+            Normalizer::Trim => "trim"
+        }
+    }
+
+    fn apply(&self, text: &str) -> String {
+        match *self {
+            Normalizer::Lowercase => text.to_lowercase(),
+            Normalizer::Nfkc => text.nfkc().collect(),
+            Normalizer::Trim => text.trim().to_owned()
+        }
+    }
+}
+
+/// Applies the normalizers in order to a string, or to each string in an array, leaving other values as they are
+pub fn normalize(normalizers: &[Normalizer], value: &LogValue) -> LogValue {
+    match *value {
+        LogValue::String(ref text) => LogValue::String(normalize_str(normalizers, text)),
+        LogValue::Array(ref values) => LogValue::Array(values.iter().map(|v| normalize(normalizers, v)).collect()),
+        ref v => v.clone()
+    }
+}
+
+pub fn normalize_str(normalizers: &[Normalizer], text: &str) -> String {
+    normalizers.iter().fold(text.to_owned(), |text, n| n.apply(&text))
+}
+
+/// The name of the index holding the terms of a text field, which can't be a field's name
+pub fn text_index(field: &str) -> String {
+    format!("__text.{}", field)
+}
+
+/// A posting of a text field: the log's location and the term's position
+/// Terms past the last position share it, so they can still be found, but not in a phrase
+pub fn posting(loc: u64, position: u32) -> u64 {
+    (loc << POSITION_BITS) | cmp::min(position as u64, MAX_POSITION)
+}
+
+/// The log's location from a posting of a text field
+pub fn location(posting: u64) -> u64 {
+    posting >> POSITION_BITS
+}
+
+/// The locations of the logs with a posting in every list, in file order
+pub fn all_of(postings: Vec<Vec<u64>>) -> Vec<u64> {
+    let mut lists = postings.into_iter().map(|list| {
+        let mut locs = list.into_iter().map(location).collect::<Vec<_>>();
+
+        locs.dedup();
+        locs
+    });
+
+    let first = match lists.next() {
+        Some(locs) => locs,
+        None => return Vec::new()
+    };
+
+    lists.fold(first, |acc, locs| acc.into_iter().filter(|loc| locs.binary_search(loc).is_ok()).collect())
+}
+
+/// The locations of the logs with each term of a phrase at its offset from the first, in file order
+/// Takes the postings and offset of each term, the first term's offset is 0
+pub fn phrase(terms: Vec<(Vec<u64>, u32)>) -> Vec<u64> {
+    let mut terms = terms.into_iter();
+
+    let first = match terms.next() {
+        Some( (postings, _) ) => postings,
+        None => return Vec::new()
+    };
+
+    let rest = terms.collect::<Vec<_>>();
+
+    let mut ret = first.into_iter().filter(|&p| {
+        rest.iter().all(|&(ref postings, offset)| {
+            // a position past the last would be in another log
+            (p & MAX_POSITION) + (offset as u64) < MAX_POSITION && postings.binary_search(&(p + offset as u64)).is_ok()
+        })
+    }).map(location).collect::<Vec<_>>();
+
+    ret.dedup();
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use ::analysis::{all_of, normalize, phrase, posting, Analyzer, Normalizer};
+    use ::log_value::LogValue;
+
+    fn terms(tokens: Vec<(String, u32)>) -> Vec<String> {
+        tokens.into_iter().map(|(t, _)| t).collect()
+    }
+
+    #[test]
+    fn analyzers() {
+        let text = "Connection to db-1 TIMED out, after_retry: 3.5s";
+
+        assert_eq!(terms(Analyzer::Standard.tokens(text)), vec!["connection", "to", "db", "1", "timed", "out", "after_retry", "3", "5s"]);
+        assert_eq!(terms(Analyzer::Whitespace.tokens(text)), vec!["Connection", "to", "db-1", "TIMED", "out,", "after_retry:", "3.5s"]);
+
+        // stop words keep their positions
+        assert_eq!(Analyzer::Stop.tokens("the end of it"), vec![(String::from("end"), 1)]);
+
+        let value = LogValue::Array(vec![LogValue::String(String::from("a b")), LogValue::String(String::from("c"))]);
+
+        assert_eq!(Analyzer::Standard.value_tokens(&value), vec![(String::from("a"), 0), (String::from("b"), 1), (String::from("c"), 102)]);
+    }
+
+    #[test]
+    fn normalizers() {
+        let value = LogValue::Array(vec![LogValue::String(String::from(" ＥＲＲＯＲ ")), LogValue::Bool(true)]);
+        let normalized = LogValue::Array(vec![LogValue::String(String::from("error")), LogValue::Bool(true)]);
+
+        assert_eq!(normalize(&[Normalizer::Nfkc, Normalizer::Lowercase, Normalizer::Trim], &value), normalized);
+        assert_eq!(normalize(&[], &value), value);
+
+        // compatibility characters are only folded by nfkc
+        assert_eq!(normalize(&[Normalizer::Lowercase], &LogValue::String(String::from("ﬁle"))), LogValue::String(String::from("ﬁle")));
+        assert_eq!(normalize(&[Normalizer::Nfkc], &LogValue::String(String::from("ﬁle"))), LogValue::String(String::from("file")));
+    }
+
+    #[test]
+    fn phrases() {
+        // "connection timed out" at 8, "timed connection" at 16
+        let connection = vec![posting(8, 0), posting(16, 1)];
+        let timed = vec![posting(8, 1), posting(16, 0)];
+        let out = vec![posting(8, 2)];
+
+        assert_eq!(all_of(vec![connection.clone(), timed.clone()]), vec![8, 16]);
+        assert_eq!(all_of(vec![connection.clone(), out.clone()]), vec![8]);
+        assert_eq!(all_of(Vec::new()), Vec::<u64>::new());
+
+        assert_eq!(phrase(vec![(connection.clone(), 0), (timed.clone(), 1)]), vec![8]);
+        assert_eq!(phrase(vec![(timed.clone(), 0), (connection.clone(), 1)]), vec![16]);
+        assert_eq!(phrase(vec![(connection.clone(), 0), (out.clone(), 2)]), vec![8]);
+        assert_eq!(phrase(vec![(connection, 0), (out, 1)]), Vec::<u64>::new());
+
+        // the position doesn't carry into the next log
+        assert_eq!(phrase(vec![(vec![posting(8, 70000)], 0), (vec![posting(9, 0)], 1)]), Vec::<u64>::new());
+    }
+}
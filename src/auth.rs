@@ -0,0 +1,267 @@
+use base64;
+use rand::{OsRng, Rng};
+use serde_json;
+use sha2::{Digest, Sha256};
+
+use std::fs::{rename, File};
+use std::io::{Error as IOError, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Number of random bytes in a key's id, salt and secret
+const ID_LEN: usize = 8;
+const SALT_LEN: usize = 16;
+const SECRET_LEN: usize = 24;
+
+/// What a key is allowed to do; Admin can do everything
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Ingest,
+    Read,
+    Admin
+}
+
+/// The kind of operation a request performs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Info,   // the version banner, which shippers check before sending
+    Ingest,
+    Read,
+    Admin
+}
+
+impl Role {
+    pub fn permits(&self, op: Operation) -> bool {
+        match (*self, op) {
+            (Role::Admin, _) | (_, Operation::Info) => true,
+            (Role::Ingest, Operation::Ingest) | (Role::Read, Operation::Read) => true,
+            _ => false
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "ingest" => Some(Role::Ingest),
+            "read" => Some(Role::Read),
+            "admin" => Some(Role::Admin),
+            _ => None
+        }
+    }
+}
+
+/// An API key, only the salted SHA-256 hash of its secret is stored
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub salt: String,
+    pub hash: String,
+    pub role: Role,
+    pub indices: Vec<String> // the logical indices the key can use, a trailing * matches any suffix
+}
+
+impl ApiKey {
+    /// Checks the key can perform the operation on all of the indices
+    pub fn authorize(&self, op: Operation, indices: &[String]) -> bool {
+        self.role.permits(op) && indices.iter().all(|index| self.indices.iter().any(|p| index_matches(p, index)))
+    }
+
+    fn verify(&self, secret: &str) -> bool {
+        constant_time_eq(hash_secret(&self.salt, secret).as_bytes(), self.hash.as_bytes())
+    }
+}
+
+/// Why a request was refused
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthError {
+    Missing,   // no credentials were sent
+    Invalid,   // the credentials don't match a key
+    Forbidden, // the key can't perform the operation
+    Remote     // keys can only be managed from the node itself until the first one exists
+}
+
+/// The API keys allowed to use the HTTP endpoint, stored as JSON
+/// Authentication is disabled until the first key is added, which only a client on the node itself can do
+pub struct KeyStore {
+    path: PathBuf,
+    keys: Vec<ApiKey>
+}
+
+impl KeyStore {
+    /// Loads the keys from the file, an empty store is created if it does not exist
+    pub fn load(path: &Path) -> Result<KeyStore, IOError> {
+        let keys = match File::open(path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| IOError::new(ErrorKind::InvalidData, e))?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e)
+        };
+
+        Ok(KeyStore { path: path.to_path_buf(), keys })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn keys(&self) -> &[ApiKey] {
+        &self.keys
+    }
+
+    /// Creates a new key, returning it along with its secret, which is not stored
+    pub fn add(&mut self, role: Role, indices: Vec<String>) -> Result<(ApiKey, String), IOError> {
+        let mut rng = OsRng::new()?;
+
+        let salt = random_hex(&mut rng, SALT_LEN);
+        let secret = random_hex(&mut rng, SECRET_LEN);
+
+        let key = ApiKey {
+            id: random_hex(&mut rng, ID_LEN),
+            hash: hash_secret(&salt, &secret),
+            salt,
+            role,
+            indices
+        };
+
+        self.keys.push(key.clone());
+        self.save()?;
+
+        Ok( (key, secret) )
+    }
+
+    /// Removes a key, returning false if there is no key with the id
+    pub fn remove(&mut self, id: &str) -> Result<bool, IOError> {
+        let len = self.keys.len();
+
+        self.keys.retain(|k| k.id != id);
+
+        if self.keys.len() == len {
+            return Ok(false);
+        }
+
+        self.save()?;
+
+        Ok(true)
+    }
+
+    /// Finds the key for the value of an Authorization header
+    /// Both `ApiKey base64(id:secret)` and `Basic base64(id:secret)` are accepted
+    pub fn authenticate(&self, header: Option<&str>) -> Result<&ApiKey, AuthError> {
+        let header = header.ok_or(AuthError::Missing)?;
+        let mut parts = header.splitn(2, ' ');
+
+        let encoded = match (parts.next(), parts.next()) {
+            (Some(scheme), Some(creds)) if scheme == "ApiKey" || scheme == "Basic" => creds.trim(),
+            _ => return Err(AuthError::Invalid)
+        };
+
+        let decoded = base64::decode(encoded).map_err(|_| AuthError::Invalid)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| AuthError::Invalid)?;
+        let mut creds = decoded.splitn(2, ':');
+
+        let (id, secret) = match (creds.next(), creds.next()) {
+            (Some(id), Some(secret)) => (id, secret),
+            _ => return Err(AuthError::Invalid)
+        };
+
+        match self.keys.iter().find(|k| k.id == id) {
+            Some(key) if key.verify(secret) => Ok(key),
+            _ => Err(AuthError::Invalid)
+        }
+    }
+
+    /// Writes the keys to a temporary file, then moves it into place
+    fn save(&self) -> Result<(), IOError> {
+        let tmp_path = self.path.with_extension("tmp");
+
+        {
+            let file = File::create(&tmp_path)?;
+
+            serde_json::to_writer_pretty(file, &self.keys).map_err(|e| IOError::new(ErrorKind::Other, e))?;
+        }
+
+        rename(tmp_path, &self.path)
+    }
+}
+
+/// The value a client sends to use the key: base64(id:secret)
+pub fn encode_key(key: &ApiKey, secret: &str) -> String {
+    base64::encode(&format!("{}:{}", key.id, secret))
+}
+
+/// The hex SHA-256 of the salt followed by the secret
+pub fn hash_secret(salt: &str, secret: &str) -> String {
+    let mut hasher = Sha256::default();
+
+    hasher.input(salt.as_bytes());
+    hasher.input(secret.as_bytes());
+
+    to_hex(&hasher.result())
+}
+
+fn index_matches(pattern: &str, index: &str) -> bool {
+    if pattern.ends_with('*') {
+        index.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == index
+    }
+}
+
+fn random_hex(rng: &mut OsRng, len: usize) -> String {
+    let mut buf = vec![0; len];
+
+    rng.fill_bytes(&mut buf);
+
+    to_hex(&buf)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares without exiting early, so the time taken doesn't reveal how much of a hash matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use ::auth::{encode_key, AuthError, KeyStore, Operation, Role};
+
+    use std::fs::remove_file;
+    use std::path::Path;
+
+    #[test]
+    fn add_authenticate() {
+        let path = Path::new("/tmp/api_keys_test.json");
+
+        remove_file(path).ok();
+
+        let mut store = KeyStore::load(path).unwrap();
+
+        assert!(!store.is_enabled());
+
+        let (key, secret) = store.add(Role::Ingest, vec![String::from("logs-*")]).unwrap();
+        let header = format!("ApiKey {}", encode_key(&key, &secret));
+
+        // the secret is never stored, and the keys survive a reload
+        let store = KeyStore::load(path).unwrap();
+
+        assert!(!store.keys()[0].hash.contains(&secret));
+        assert_eq!(store.authenticate(Some(&header)), Ok(&key));
+        assert_eq!(store.authenticate(Some(&format!("Basic {}", encode_key(&key, &secret)))), Ok(&key));
+        assert_eq!(store.authenticate(Some(&format!("ApiKey {}", encode_key(&key, "wrong")))), Err(AuthError::Invalid));
+        assert_eq!(store.authenticate(None), Err(AuthError::Missing));
+
+        assert!(key.authorize(Operation::Ingest, &[String::from("logs-app")]));
+        assert!(!key.authorize(Operation::Ingest, &[String::from("metrics")]));
+        assert!(!key.authorize(Operation::Read, &[String::from("logs-app")]));
+        assert!(key.authorize(Operation::Info, &[]));
+    }
+
+    #[test]
+    fn roles() {
+        assert!(Role::Admin.permits(Operation::Read));
+        assert!(Role::Read.permits(Operation::Read));
+        assert!(!Role::Read.permits(Operation::Ingest));
+        assert!(!Role::Ingest.permits(Operation::Admin));
+    }
+}
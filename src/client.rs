@@ -0,0 +1,509 @@
+use futures::{future, stream, Future, Stream};
+use futures::future::{Either, Loop};
+use serde_json::Value;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_proto::TcpClient;
+use tokio_proto::pipeline::ClientService;
+use tokio_service::Service;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::io::{Error as IOError, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::cluster::{placement_key, read_buckets, replica_buckets, NodeState, REPLICATION_FACTOR};
+use ::json::{map2json, object2map};
+use ::log_value::LogValue;
+use ::rpc_codec::{frame_chunks, log_size, RequestMessage, ResponseMessage, DEFAULT_MAX_FRAME_SIZE};
+use ::rpc_server::MessageProto;
+use ::tls::RpcTls;
+
+type Connection = ClientService<TcpStream, MessageProto>;
+
+type Log = HashMap<String, LogValue>;
+
+/// How a Client connects to a cluster
+pub struct ClientOptions {
+    pub nodes: Vec<String>,          // the RPC address of each node, in the order of their ids
+    pub connections_per_node: usize, // requests are spread over this many connections to each node
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub max_retries: u32,            // times a failed or overloaded request is retried
+    pub retry_delay: Duration,       // delay before the first retry, doubled for each one after
+    pub max_frame_size: usize,
+    pub tls: Option<Arc<RpcTls>>
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            nodes: Vec::new(),
+            connections_per_node: 2,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            tls: None
+        }
+    }
+}
+
+/// A pool of connections to a single node
+struct Node {
+    address: String,
+    socket_addr: SocketAddr,
+    conns: RefCell<Vec<Option<Connection>>>, // None until first used, or after a failure
+    next: Cell<usize>,                       // the connection to use for the next request
+    down: Cell<bool>                         // set when the last request failed after every retry
+}
+
+struct Inner {
+    handle: Handle,
+    nodes: Vec<Node>,
+    options: ClientOptions
+}
+
+/// An asynchronous client that writes to and queries a cluster directly over RPC
+/// Logs are placed on their replicas the same way the HTTP endpoint places them
+/// Connections are opened as they are needed, and reopened after a failure
+#[derive(Clone)]
+pub struct Client {
+    inner: Rc<Inner>
+}
+
+impl Client {
+    pub fn new(handle: &Handle, options: ClientOptions) -> Result<Client, IOError> {
+        if options.nodes.is_empty() || options.connections_per_node == 0 {
+            return Err(IOError::new(ErrorKind::InvalidInput, "At least one node and connection are required"));
+        }
+
+        let nodes = options.nodes.iter().map(|address| {
+            let socket_addr = address.parse::<SocketAddr>()
+                .map_err(|e| IOError::new(ErrorKind::InvalidInput, format!("Invalid node address {}: {}", address, e)))?;
+
+            Ok(Node {
+                address: address.clone(),
+                socket_addr,
+                conns: RefCell::new(vec![None; options.connections_per_node]),
+                next: Cell::new(0),
+                down: Cell::new(false)
+            })
+        }).collect::<Result<Vec<_>, IOError>>()?;
+
+        Ok(Client { inner: Rc::new(Inner { handle: handle.clone(), nodes, options }) })
+    }
+
+    /// Inserts a single log, resolving to its __id
+    pub fn insert(&self, log: &Value) -> Box<Future<Item=String, Error=IOError>> {
+        Box::new(self.insert_all(&[log.clone()]).and_then(|mut results| {
+            results.pop().unwrap().map_err(|e| IOError::new(ErrorKind::Other, e))
+        }))
+    }
+
+    /// Inserts a batch of logs, resolving to the __id of each log, or why it was not stored
+    /// A log is stored once any of its replicas has it
+    pub fn insert_all(&self, logs: &[Value]) -> Box<Future<Item=Vec<Result<String, String>>, Error=IOError>> {
+        let num_buckets = self.inner.nodes.len() as u32;
+        let mut results = Vec::with_capacity(logs.len());
+        let mut prepared = Vec::with_capacity(logs.len());
+        let mut batches = HashMap::<u32, Vec<usize>>::new();
+
+        for log in logs {
+            let map = match log.as_object().map(object2map) {
+                Some(Ok(m)) => m,
+                Some(Err(e)) => { results.push(Err(e.to_string())); continue },
+                None => { results.push(Err(String::from("Logs must be JSON objects"))); continue }
+            };
+
+            let id = match map.get("__id") {
+                Some(&LogValue::String(ref id)) => id.clone(),
+                _ => unreachable!() // always added
+            };
+
+            for bucket in replica_buckets(placement_key(&id), num_buckets, REPLICATION_FACTOR) {
+                batches.entry(bucket).or_insert_with(Vec::new).push(prepared.len());
+            }
+
+            results.push(Err(String::from("No replica stored the log")));
+            prepared.push((results.len() - 1, id, map));
+        }
+
+        let prepared = Rc::new(prepared);
+
+        // split each node's batch so every InsertAll fits in a frame
+        let batches = batches.into_iter().flat_map(|(bucket, indices)| {
+            frame_chunks(indices, self.inner.options.max_frame_size, |i| log_size(&prepared[*i].2))
+                .into_iter()
+                .map(move |chunk| (bucket, chunk))
+        }).collect::<Vec<_>>();
+
+        let batch_futures = batches.into_iter().map(|(bucket, indices)| {
+            let batch = indices.iter().map(|i| prepared[*i].2.clone()).collect::<Vec<_>>();
+            let address = self.inner.nodes[bucket as usize].address.clone();
+
+            request(&self.inner, bucket, RequestMessage::InsertAll(batch)).then(move |res| {
+                let stored = match res {
+                    Ok(ResponseMessage::InsertResults(r)) => r,
+                    Ok(ResponseMessage::Error { message, .. }) => vec![Err(message); indices.len()],
+                    Ok(r) => vec![Err(format!("Unexpected response: {:?}", r)); indices.len()],
+                    Err(e) => {
+                        warn!("Unable to insert batch on {}: {}", address, e);
+                        vec![Err(e.to_string()); indices.len()]
+                    }
+                };
+
+                Ok::<_, IOError>(indices.into_iter().zip(stored.into_iter()).collect::<Vec<_>>())
+            })
+        }).collect::<Vec<_>>();
+
+        Box::new(future::join_all(batch_futures).map(move |batch_results| {
+            for (i, res) in batch_results.into_iter().flat_map(|r| r) {
+                let (pos, ref id, _) = prepared[i];
+
+                match res {
+                    Ok(()) => results[pos] = Ok(id.clone()),
+                    // report the error, unless a replica stored the log
+                    Err(e) => if results[pos].is_err() { results[pos] = Err(e) }
+                }
+            }
+
+            results
+        }))
+    }
+
+    /// Streams the logs where key is value a page at a time, without duplicates from replicas
+    /// Each node that is down is covered by one of its replicas
+    pub fn stream(&self, key: &str, value: &Value) -> Box<Stream<Item=Vec<Value>, Error=IOError>> {
+        let inner = self.inner.clone();
+        let key = key.to_owned();
+        let value = LogValue::from(value);
+
+        let buckets = read_buckets(inner.nodes.len() as u32, REPLICATION_FACTOR, |b| {
+            if inner.nodes[b as usize].down.get() { NodeState::Down } else { NodeState::Up }
+        });
+
+        let node_streams = buckets.into_iter().map(move |bucket| node_pages(inner.clone(), bucket, key.clone(), value.clone()));
+        let mut seen = HashSet::new();
+
+        Box::new(stream::iter_ok::<_, IOError>(node_streams).flatten().map(move |logs| {
+            logs.into_iter().filter(|log| {
+                match log.get("__id") {
+                    Some(&LogValue::String(ref id)) => seen.insert(id.clone()),
+                    _ => true
+                }
+            }).map(map2json).collect::<Vec<_>>()
+        }))
+    }
+
+    /// Gets all of the logs where key is value
+    pub fn query(&self, key: &str, value: &Value) -> Box<Future<Item=Vec<Value>, Error=IOError>> {
+        Box::new(self.stream(key, value).concat2())
+    }
+}
+
+/// Reads every page of a query from a single node
+fn node_pages(inner: Rc<Inner>, bucket: u32, key: String, value: LogValue) -> Box<Stream<Item=Vec<Log>, Error=IOError>> {
+    Box::new(stream::unfold(Some(0), move |offset| {
+        let offset = offset?;
+
+        Some(request(&inner, bucket, RequestMessage::GetFrom(key.clone(), value.clone(), offset)).and_then(|resp| {
+            match resp {
+                ResponseMessage::LogsPage(logs, next) => Ok( (logs, Some(next)) ),
+                ResponseMessage::LogsDone(logs, _) => Ok( (logs, None) ),
+                ResponseMessage::Error { message, .. } => Err(IOError::new(ErrorKind::Other, message)),
+                r => Err(IOError::new(ErrorKind::InvalidData, format!("Unexpected response to GetFrom: {:?}", r)))
+            }
+        }))
+    }))
+}
+
+/// Sends a request to a node, retrying failures and retryable errors with an increasing delay
+fn request(inner: &Rc<Inner>, bucket: u32, req: RequestMessage) -> Box<Future<Item=ResponseMessage, Error=IOError>> {
+    let inner = inner.clone();
+
+    Box::new(future::loop_fn(0, move |attempt| {
+        let inner = inner.clone();
+
+        attempt_request(&inner, bucket, req.clone()).then(move |res| -> Box<Future<Item=Loop<ResponseMessage, u32>, Error=IOError>> {
+            let node = &inner.nodes[bucket as usize];
+            let options = &inner.options;
+
+            match res {
+                Ok(ResponseMessage::Error { ref message, retryable: true, .. }) if attempt < options.max_retries => {
+                    debug!("Retrying request to {}: {}", node.address, message);
+                },
+                Err(ref e) if attempt < options.max_retries => {
+                    debug!("Retrying request to {}: {}", node.address, e);
+                },
+                Ok(resp) => {
+                    node.down.set(false);
+                    return Box::new(future::ok(Loop::Break(resp)));
+                },
+                Err(e) => {
+                    node.down.set(true);
+                    return Box::new(future::err(e));
+                }
+            }
+
+            let delay = options.retry_delay * 2u32.pow(attempt);
+            let timeout = future::result(Timeout::new(delay, &inner.handle)).flatten();
+
+            Box::new(timeout.map(move |_| Loop::Continue(attempt + 1)))
+        })
+    }))
+}
+
+/// Sends a request over the next connection in the node's pool, opening the connection if needed
+fn attempt_request(inner: &Rc<Inner>, bucket: u32, req: RequestMessage) -> Box<Future<Item=ResponseMessage, Error=IOError>> {
+    let node = &inner.nodes[bucket as usize];
+    let slot = node.next.get();
+
+    node.next.set((slot + 1) % inner.options.connections_per_node);
+
+    let existing = node.conns.borrow()[slot].clone();
+
+    let conn_future: Box<Future<Item=Connection, Error=IOError>> = match existing {
+        Some(conn) => Box::new(future::ok(conn)),
+        None => {
+            let proto = MessageProto::new(inner.options.max_frame_size, inner.options.tls.clone());
+            let connect = TcpClient::new(proto).connect(&node.socket_addr, &inner.handle);
+
+            Box::new(with_timeout(connect, inner.options.connect_timeout, &inner.handle))
+        }
+    };
+
+    let inner = inner.clone();
+    let request_timeout = inner.options.request_timeout;
+
+    Box::new(conn_future.and_then(move |conn| {
+        inner.nodes[bucket as usize].conns.borrow_mut()[slot] = Some(conn.clone());
+
+        with_timeout(conn.call(req), request_timeout, &inner.handle).then(move |res| {
+            // the connection may be broken, so the next request on this slot opens a new one
+            if res.is_err() {
+                inner.nodes[bucket as usize].conns.borrow_mut()[slot] = None;
+            }
+
+            res
+        })
+    }))
+}
+
+fn with_timeout<F>(f: F, duration: Duration, handle: &Handle) -> Box<Future<Item=F::Item, Error=IOError>>
+    where F: Future<Error=IOError> + 'static, F::Item: 'static {
+    let timeout = match Timeout::new(duration, handle) {
+        Ok(t) => t,
+        Err(e) => return Box::new(future::err(e))
+    };
+
+    Box::new(f.select2(timeout).then(|res| {
+        match res {
+            Ok(Either::A((item, _))) => Ok(item),
+            Ok(Either::B(_)) => Err(IOError::new(ErrorKind::TimedOut, "Request timed out")),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e)
+        }
+    }))
+}
+
+/// A client that blocks on each call, running its own event loop
+pub struct BlockingClient {
+    core: Core,
+    client: Client
+}
+
+impl BlockingClient {
+    pub fn new(options: ClientOptions) -> Result<BlockingClient, IOError> {
+        let core = Core::new()?;
+        let client = Client::new(&core.handle(), options)?;
+
+        Ok(BlockingClient { core, client })
+    }
+
+    pub fn insert(&mut self, log: &Value) -> Result<String, IOError> {
+        self.core.run(self.client.insert(log))
+    }
+
+    pub fn insert_all(&mut self, logs: &[Value]) -> Result<Vec<Result<String, String>>, IOError> {
+        self.core.run(self.client.insert_all(logs))
+    }
+
+    pub fn query(&mut self, key: &str, value: &Value) -> Result<Vec<Value>, IOError> {
+        self.core.run(self.client.query(key, value))
+    }
+
+    /// Iterates over the pages of logs where key is value, reading each page as it's needed
+    pub fn stream(&mut self, key: &str, value: &Value) -> Pages {
+        let stream = self.client.stream(key, value);
+
+        Pages { core: &mut self.core, stream: Some(stream) }
+    }
+}
+
+/// The pages of a query made with a BlockingClient
+pub struct Pages<'a> {
+    core: &'a mut Core,
+    stream: Option<Box<Stream<Item=Vec<Value>, Error=IOError>>>
+}
+
+impl <'a> Iterator for Pages<'a> {
+    type Item = Result<Vec<Value>, IOError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let stream = self.stream.take()?;
+
+        match self.core.run(stream.into_future()) {
+            Ok((Some(page), rest)) => {
+                self.stream = Some(rest);
+                Some(Ok(page))
+            },
+            Ok((None, _)) => None,
+            Err((e, _)) => Some(Err(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::client::{BlockingClient, ClientOptions};
+    use ::data_manager::DataManager;
+    use ::metrics::Metrics;
+    use ::rpc_codec::DEFAULT_MAX_FRAME_SIZE;
+    use ::rpc_server::run_rpc_server;
+
+    use futures::sync::oneshot;
+
+    use std::collections::HashSet;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    /// Runs a node with its own data directory, starting it after the delay
+    /// Sending on the returned channel stops it
+    fn start_node(name: &str, port: u16, delay: Duration) -> (oneshot::Sender<()>, JoinHandle<()>) {
+        let dir = PathBuf::from(format!("/tmp/{}/{}", name, port));
+
+        remove_dir_all(&dir).ok();
+        create_dir_all(&dir).unwrap();
+
+        let dm = Arc::new(DataManager::new(&dir).unwrap());
+        let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let node = thread::spawn(move || {
+            thread::sleep(delay);
+            run_rpc_server(dm, addr, None, DEFAULT_MAX_FRAME_SIZE, Arc::new(Metrics::default()), stopped, Duration::from_secs(1)).unwrap();
+        });
+
+        (stop, node)
+    }
+
+    fn stop_nodes(nodes: Vec<(oneshot::Sender<()>, JoinHandle<()>)>) {
+        for (stop, node) in nodes {
+            stop.send(()).ok();
+            node.join().unwrap();
+        }
+    }
+
+    fn options(ports: &[u16]) -> ClientOptions {
+        ClientOptions {
+            nodes: ports.iter().map(|p| format!("127.0.0.1:{}", p)).collect(),
+            connect_timeout: Duration::from_secs(1),
+            retry_delay: Duration::from_millis(10),
+            ..ClientOptions::default()
+        }
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(BlockingClient::new(ClientOptions::default()).is_err());
+
+        let options = ClientOptions { nodes: vec![String::from("not an address")], ..ClientOptions::default() };
+
+        assert!(BlockingClient::new(options).is_err());
+    }
+
+    #[test]
+    fn invalid_logs_rejected() {
+        // nothing is sent for a log that can't be stored, so no node is needed
+        let options = ClientOptions { nodes: vec![String::from("127.0.0.1:1")], ..ClientOptions::default() };
+        let mut client = BlockingClient::new(options).unwrap();
+
+        let results = client.insert_all(&[json!("not an object"), json!({ "__id": "reserved" })]).unwrap();
+
+        assert!(results.iter().all(|r| r.is_err()));
+    }
+
+    #[test]
+    fn round_trip() {
+        let ports = [24300, 24301, 24302];
+        let nodes = ports.iter().map(|p| start_node("client_round_trip_test", *p, Duration::from_millis(0))).collect();
+
+        thread::sleep(Duration::from_millis(500)); // let the nodes start listening
+
+        let mut client = BlockingClient::new(options(&ports)).unwrap();
+        let logs = (0..50).map(|i| json!({ "host": "web", "n": i })).collect::<Vec<_>>();
+
+        let ids = client.insert_all(&logs).unwrap().into_iter().map(|r| r.unwrap()).collect::<HashSet<_>>();
+
+        assert_eq!(ids.len(), 50);
+
+        // each log is held by two nodes, but returned once
+        let found = client.query("host", &json!("web")).unwrap();
+
+        assert_eq!(found.len(), 50);
+        assert!(found.iter().all(|log| ids.contains(log["__id"].as_str().unwrap())));
+
+        let id = client.insert(&json!({ "host": "api" })).unwrap();
+        let found = client.query("__id", &json!(id)).unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0]["host"], json!("api"));
+
+        assert_eq!(client.stream("host", &json!("web")).map(|page| page.unwrap().len()).sum::<usize>(), 50);
+
+        stop_nodes(nodes);
+    }
+
+    #[test]
+    fn node_down() {
+        // nothing listens on the last port
+        let ports = [24310, 24311, 24312];
+        let nodes = ports[..2].iter().map(|p| start_node("client_node_down_test", *p, Duration::from_millis(0))).collect();
+
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = BlockingClient::new(options(&ports)).unwrap();
+        let logs = (0..50).map(|i| json!({ "host": "web", "n": i })).collect::<Vec<_>>();
+
+        // every log has a replica on a live node
+        assert!(client.insert_all(&logs).unwrap().iter().all(|r| r.is_ok()));
+        assert!(client.client.inner.nodes[2].down.get());
+
+        // the node that is down is read from its replica
+        assert_eq!(client.query("host", &json!("web")).unwrap().len(), 50);
+
+        stop_nodes(nodes);
+    }
+
+    #[test]
+    fn retries_until_up() {
+        // the node starts after the first attempts fail
+        let nodes = vec![start_node("client_retry_test", 24320, Duration::from_millis(300))];
+        let options = ClientOptions { max_retries: 6, retry_delay: Duration::from_millis(50), ..options(&[24320]) };
+        let mut client = BlockingClient::new(options).unwrap();
+
+        let id = client.insert(&json!({ "host": "web" })).unwrap();
+
+        assert_eq!(client.query("__id", &json!(id)).unwrap().len(), 1);
+        assert!(!client.client.inner.nodes[0].down.get());
+
+        stop_nodes(nodes);
+    }
+}
@@ -0,0 +1,232 @@
+use twox_hash::XxHash;
+
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+
+/// How often each node is sent a heartbeat
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 2;
+
+/// How long to wait for a heartbeat response before counting it as missed
+pub const HEARTBEAT_TIMEOUT_SECS: u64 = 1;
+
+/// Number of consecutive missed heartbeats (or failed requests) before a node is considered down
+pub const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// Number of nodes each log is written to, including the primary
+pub const REPLICATION_FACTOR: u32 = 2;
+
+/// The state of a node as seen by this coordinator
+/// Up -> Suspect after a single failure, Suspect -> Down after MAX_MISSED_HEARTBEATS
+#[derive(Clone, Copy, Serialize, Debug, PartialEq)]
+pub enum NodeState {
+    Up,
+    Suspect,
+    Down
+}
+
+/// The health of the cluster, named as Elasticsearch names it
+/// Green when every node is up, Yellow when every log can still be read, and Red when some can't
+#[derive(Clone, Copy, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClusterStatus {
+    Green,
+    Yellow,
+    Red
+}
+
+/// Tracks the health of a single node
+#[derive(Clone, Debug)]
+pub struct NodeHealth {
+    state: NodeState,
+    missed: u32,                // consecutive failures
+    last_seen: Option<Instant>, // last time the node successfully responded
+    errors: u64,                // failed requests and heartbeats, ever
+}
+
+impl NodeHealth {
+    pub fn new(state: NodeState) -> NodeHealth {
+        let last_seen = if state == NodeState::Up { Some(Instant::now()) } else { None };
+
+        NodeHealth { state, missed: 0, last_seen, errors: 0 }
+    }
+
+    pub fn state(&self) -> NodeState {
+        self.state
+    }
+
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// The time since the node last responded, if it ever has
+    pub fn since_last_seen(&self) -> Option<Duration> {
+        self.last_seen.map(|t| t.elapsed())
+    }
+
+    /// Records a successful response from the node, marking it as up
+    pub fn record_success(&mut self) {
+        if self.state != NodeState::Up {
+            info!("Node is back up after {} missed heartbeats", self.missed);
+        }
+
+        self.state = NodeState::Up;
+        self.missed = 0;
+        self.last_seen = Some(Instant::now());
+    }
+
+    /// Records a failed request or missed heartbeat, returning the new state
+    pub fn record_failure(&mut self) -> NodeState {
+        self.missed += 1;
+        self.errors += 1;
+
+        self.state = if self.missed >= MAX_MISSED_HEARTBEATS {
+            NodeState::Down
+        } else {
+            NodeState::Suspect
+        };
+
+        self.state
+    }
+}
+
+/// Jump Consistent Hash: https://arxiv.org/pdf/1406.2294v1.pdf
+pub fn jump_hash(mut key: u64, num_buckets: u32) -> u32 {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+
+    while j < num_buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+
+    b as u32
+}
+
+/// Converts a log's __id into the key used for placement
+pub fn placement_key(id: &str) -> u64 {
+    let mut hash: XxHash = XxHash::with_seed(0xBEDBEEF);
+
+    hash.write(id.as_bytes());
+
+    hash.finish()
+}
+
+/// Returns the primary bucket for a log's __id
+pub fn primary_bucket(id: &str, num_buckets: u32) -> u32 {
+    jump_hash(placement_key(id), num_buckets)
+}
+
+/// Returns the buckets holding a key, primary first, followed by its replicas
+/// Replicas are placed on the buckets following the primary
+pub fn replica_buckets(key: u64, num_buckets: u32, replication: u32) -> Vec<u32> {
+    if num_buckets == 0 {
+        return Vec::new();
+    }
+
+    let primary = jump_hash(key, num_buckets);
+    let count = if replication < num_buckets { replication } else { num_buckets };
+
+    (0..count).map(|i| (primary + i) % num_buckets).collect()
+}
+
+/// Given the state of each bucket, returns the set of buckets to query so every bucket's data is covered
+/// Down buckets are covered by the first live bucket holding their replicas
+pub fn read_buckets<F>(num_buckets: u32, replication: u32, state: F) -> Vec<u32> where F: Fn(u32) -> NodeState {
+    let mut ret = Vec::new();
+    let count = if replication < num_buckets { replication } else { num_buckets };
+
+    for bucket in 0..num_buckets {
+        let holder = (0..count)
+            .map(|i| (bucket + i) % num_buckets)
+            .find(|b| state(*b) != NodeState::Down);
+
+        match holder {
+            Some(b) => if !ret.contains(&b) { ret.push(b) },
+            None => warn!("No live replica for bucket {}; results will be incomplete", bucket)
+        }
+    }
+
+    ret
+}
+
+/// Given the state of each bucket, returns the health of the cluster
+pub fn cluster_status<F>(num_buckets: u32, replication: u32, state: F) -> ClusterStatus where F: Fn(u32) -> NodeState {
+    let count = if replication < num_buckets { replication } else { num_buckets };
+
+    // a bucket's logs are lost to reads when it and all of its replicas are down
+    let unreadable = (0..num_buckets).any(|bucket| (0..count).all(|i| state((bucket + i) % num_buckets) == NodeState::Down));
+
+    if unreadable {
+        ClusterStatus::Red
+    } else if (0..num_buckets).all(|b| state(b) == NodeState::Up) {
+        ClusterStatus::Green
+    } else {
+        ClusterStatus::Yellow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::cluster::{jump_hash, replica_buckets, read_buckets, cluster_status, ClusterStatus, NodeHealth, NodeState, MAX_MISSED_HEARTBEATS};
+
+    #[test]
+    fn jump_hash_in_range() {
+        for key in 0..1000 {
+            assert!(jump_hash(key, 7) < 7);
+        }
+
+        assert_eq!(jump_hash(12345, 1), 0);
+    }
+
+    #[test]
+    fn replicas_wrap() {
+        let buckets = replica_buckets(12345, 3, 2);
+
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[1], (buckets[0] + 1) % 3);
+
+        // never more replicas than buckets
+        assert_eq!(replica_buckets(12345, 1, 2), vec![0]);
+    }
+
+    #[test]
+    fn health_transitions() {
+        let mut health = NodeHealth::new(NodeState::Up);
+
+        assert_eq!(health.record_failure(), NodeState::Suspect);
+
+        for _ in 1..MAX_MISSED_HEARTBEATS {
+            health.record_failure();
+        }
+
+        assert_eq!(health.state(), NodeState::Down);
+
+        health.record_success();
+
+        assert_eq!(health.state(), NodeState::Up);
+        assert_eq!(health.missed(), 0);
+    }
+
+    #[test]
+    fn read_skips_down() {
+        // bucket 1 is down, so bucket 2 must cover it
+        let buckets = read_buckets(3, 2, |b| if b == 1 { NodeState::Down } else { NodeState::Up });
+
+        assert_eq!(buckets, vec![0, 2]);
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(cluster_status(3, 2, |_| NodeState::Up), ClusterStatus::Green);
+        assert_eq!(cluster_status(3, 2, |b| if b == 1 { NodeState::Suspect } else { NodeState::Up }), ClusterStatus::Yellow);
+        assert_eq!(cluster_status(3, 2, |b| if b == 1 { NodeState::Down } else { NodeState::Up }), ClusterStatus::Yellow);
+
+        // buckets 1 and 2 hold the only copies of bucket 1's logs
+        assert_eq!(cluster_status(3, 2, |b| if b > 0 { NodeState::Down } else { NodeState::Up }), ClusterStatus::Red);
+    }
+}
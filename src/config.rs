@@ -0,0 +1,189 @@
+use log::Level;
+use toml;
+
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ::rpc_codec::{DEFAULT_MAX_FRAME_SIZE, MIN_MAX_FRAME_SIZE};
+use ::tls::DEFAULT_SERVER_NAME;
+
+/// When writes to the log file reach the disk
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    Buffered, // left to the OS, a crash may lose the most recent logs
+    Fsync     // every write, or batch of writes, is synced before it's acknowledged
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Durability, String> {
+        match s {
+            "buffered" => Ok(Durability::Buffered),
+            "fsync" => Ok(Durability::Fsync),
+            _ => Err(format!("durability must be buffered or fsync, not {}", s))
+        }
+    }
+}
+
+/// The certificates used for RPC over TLS
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default = "default_server_name")]
+    pub server_name: String
+}
+
+fn default_server_name() -> String {
+    String::from(DEFAULT_SERVER_NAME)
+}
+
+/// The server's configuration, read from a TOML file, with any missing settings left at their defaults
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub rpc_addr: String,
+    pub http_addr: String,
+    pub log_level: String,
+    pub flush_interval_secs: u64, // how often indices are flushed to disk, 0 to only flush for the memory budget
+    pub index_memory_budget: usize, // index entries held in memory across all indices before the largest are flushed
+    pub durability: Durability,
+    pub retention_days: Option<u64>, // logs with a __ts older than this are removed, kept forever when None
+    pub max_frame_size: usize,    // largest RPC frame sent or accepted, every node must use the same size
+    pub nodes: Vec<String>,       // the RPC address of every node in the cluster, in the order of their ids
+    pub shutdown_timeout_secs: u64, // how long requests in flight are given to finish when shutting down
+    pub tls: Option<TlsSettings>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            data_dir: PathBuf::from("/tmp"),
+            rpc_addr: String::from("0.0.0.0:12345"),
+            http_addr: String::from("127.0.0.1:9200"),
+            log_level: String::from("debug"),
+            flush_interval_secs: 10,
+            index_memory_budget: 1_000_000,
+            durability: Durability::Buffered,
+            retention_days: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            nodes: vec![String::from("127.0.0.1:12345")],
+            shutdown_timeout_secs: 30,
+            tls: None
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from a file
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let mut contents = String::new();
+
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("Unable to read config file {}: {}", path.display(), e))?;
+
+        Config::parse(&contents).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    /// Checks every setting, returning a description of each one that's invalid
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if !self.data_dir.is_dir() {
+            errors.push(format!("data_dir {} is not a directory", self.data_dir.display()));
+        }
+
+        for &(name, addr) in &[("rpc_addr", &self.rpc_addr), ("http_addr", &self.http_addr)] {
+            if addr.parse::<SocketAddr>().is_err() {
+                errors.push(format!("{} {} is not an address and port", name, addr));
+            }
+        }
+
+        if Level::from_str(&self.log_level).is_err() {
+            errors.push(format!("log_level must be one of error, warn, info, debug or trace, not {}", self.log_level));
+        }
+
+        if self.index_memory_budget == 0 {
+            errors.push(String::from("index_memory_budget must be at least 1"));
+        }
+
+        if self.retention_days == Some(0) {
+            errors.push(String::from("retention_days must be at least 1"));
+        }
+
+        // the high bit of a frame's length marks it as compressed
+        if self.max_frame_size < MIN_MAX_FRAME_SIZE || self.max_frame_size > i32::max_value() as usize {
+            errors.push(format!("max_frame_size must be between {} and {} bytes, not {}", MIN_MAX_FRAME_SIZE, i32::max_value(), self.max_frame_size));
+        }
+
+        if self.nodes.is_empty() {
+            errors.push(String::from("nodes must list at least one node"));
+        }
+
+        for node in self.nodes.iter().filter(|n| n.parse::<SocketAddr>().is_err()) {
+            errors.push(format!("node {} is not an address and port", node));
+        }
+
+        if let Some(ref tls) = self.tls {
+            for path in &[&tls.ca, &tls.cert, &tls.key] {
+                if !path.is_file() {
+                    errors.push(format!("TLS file {} does not exist", path.display()));
+                }
+            }
+        }
+
+        if errors.is_empty() { Ok( () ) } else { Err(errors) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::config::{Config, Durability};
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_defaults() {
+        let config = Config::parse(r#"
+            data_dir = "/var/tmp"
+            durability = "fsync"
+            nodes = ["10.0.0.1:12345", "10.0.0.2:12345"]
+        "#).unwrap();
+
+        assert_eq!(config.data_dir, PathBuf::from("/var/tmp"));
+        assert_eq!(config.durability, Durability::Fsync);
+        assert_eq!(config.nodes.len(), 2);
+        assert_eq!(config.http_addr, Config::default().http_addr);
+
+        assert!(Config::parse("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config {
+            rpc_addr: String::from("nowhere"),
+            log_level: String::from("loud"),
+            nodes: Vec::new(),
+            max_frame_size: 1024,
+            retention_days: Some(0),
+            ..Config::default()
+        };
+
+        assert_eq!(config.validate().unwrap_err().len(), 5);
+    }
+}
@@ -1,17 +1,67 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io::{Error as IOError, ErrorKind};
 use std::path::{Path, PathBuf};
-use std::fs::read_dir;
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 use rayon::prelude::*;
 
+use ::analysis::{self, text_index};
 use ::log_file::LogFile;
 use ::index_file::IndexFile;
+use ::json::INDEX_FIELD;
 use ::log_value::LogValue;
+use ::mapping::{Mapping, MappingUpdate};
+use ::metrics::{Counter, Exposition, Histogram};
+use ::pattern::{TermPattern, MAX_EXPANSIONS, MAX_TERMS_SCANNED, TERM_SCAN_BATCH};
+use ::query::Query;
 use ::record_error::RecordError;
 
+/// The file in the data directory holding the mapping
+const MAPPING_FILE: &str = "mapping.json";
+
+/// The directory in the data directory the files are rewritten in when logs are expired
+const EXPIRE_DIR: &str = "expire.tmp";
+
+/// Logs read from the log file at a time when expiring logs
+const EXPIRE_BATCH: usize = 1000;
+
+/// The state of a field index, for monitoring
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct IndexStats {
+    pub field: String,
+    pub terms: usize,
+    pub mem_postings: usize,        // entries held in memory waiting to be flushed
+    pub flushing: bool,
+    pub last_flush_ms: Option<u64>, // when the index was last flushed, since the epoch
+    pub size: u64                   // bytes on disk
+}
+
+/// The state of a node's storage, for monitoring
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct StorageStats {
+    pub logs: u32,
+    pub log_file_size: u64,
+    pub mem_postings: usize,
+    pub indices: Vec<IndexStats>,               // sorted by field
+    pub logical_indices: Vec<(String, usize)>   // the number of logs in each logical index, sorted by name
+}
+
+/// Queries and inserts can run concurrently from many threads
+/// Reads of the log file and indices share their locks, so only the moment an entry is
+/// appended or added excludes them
 pub struct DataManager {
-    log_file: LogFile,
-    indices: HashMap<String, IndexFile>,
+    log_file: RwLock<LogFile>,
+    indices: RwLock<HashMap<String, RwLock<IndexFile>>>, // written only when a new index is created
+    mapping: RwLock<Mapping>,
+    mem_postings: AtomicUsize, // index entries held in memory, across all of the indices
+    claiming: Mutex<HashSet<String>>, // __ids of the logs being inserted, so a log isn't stored twice before it's indexed
+    logs_written: Counter,
+    bytes_written: Counter,
+    flush_seconds: Histogram,
+    expiring: RwLock<()>,      // held to read while an index is flushed, and to write while logs are expired
+    generation: AtomicUsize,   // counts the expiries, as each one moves the logs in the log file
     dir_path: PathBuf
 }
 
@@ -25,7 +75,7 @@ impl DataManager {
         }
 
         let log_file = LogFile::new(dir_path)?;
-        let mut indices = HashMap::<String, IndexFile>::new();
+        let mut indices = HashMap::<String, RwLock<IndexFile>>::new();
 
         info!("Loading files from: {}", dir_path.display());
 
@@ -39,56 +89,577 @@ impl DataManager {
 
                 info!("Loading index file: {}", path.display());
 
-                indices.insert(index_name.to_owned(), IndexFile::new(&dir_path, index_name.as_str())?);
+                indices.insert(index_name.to_owned(), RwLock::new(IndexFile::new(&dir_path, index_name.as_str())?));
             }
         }
 
-        Ok( DataManager{ log_file, indices, dir_path: PathBuf::from(dir_path) })
+        let mapping = Mapping::load(&dir_path.join(MAPPING_FILE))?;
+
+        Ok( DataManager{
+            log_file: RwLock::new(log_file),
+            indices: RwLock::new(indices),
+            mapping: RwLock::new(mapping),
+            mem_postings: AtomicUsize::new(0),
+            claiming: Mutex::new(HashSet::new()),
+            logs_written: Counter::default(),
+            bytes_written: Counter::default(),
+            flush_seconds: Histogram::default(),
+            expiring: RwLock::new(()),
+            generation: AtomicUsize::new(0),
+            dir_path: PathBuf::from(dir_path)
+        })
+    }
+
+    /// Sets whether each write to the log file is synced to disk before it returns
+    pub fn set_sync(&self, sync: bool) {
+        self.log_file.write().unwrap().set_sync(sync);
     }
 
-    pub fn insert(&mut self, log: &HashMap<String, LogValue>) -> Result<(), RecordError> {
+    /// Inserts a log, unless a log with its __id is already stored, as when a hint, rebalance or repair sends it again
+    pub fn insert(&self, log: &HashMap<String, LogValue>) -> Result<(), RecordError> {
+        self.check(log)?;
+
+        if !self.claim_ids(&[log])?[0] {
+            return Ok( () );
+        }
+
         // add to the log file first
-        let loc = self.log_file.add(log)?;
+        let ret = self.write(log).and_then(|loc| self.index(log, loc));
 
-        // go through each key and create or add to index
-        for (key, value) in log.iter() {
-            if !self.indices.contains_key(key) {
-                self.indices.insert(key.to_owned(), IndexFile::new(&self.dir_path, key)?);
+        self.release_ids(&[log]);
+
+        ret
+    }
+
+    /// Inserts a batch of logs with a single write to the log file
+    /// Returns the result of inserting each log, or an error if the batch could not be written
+    /// Logs the mapping rejects are not written, nor are logs whose __id is already stored, which succeed
+    pub fn insert_all(&self, logs: &[HashMap<String, LogValue>]) -> Result<Vec<Result<(), RecordError>>, RecordError> {
+        let checks = logs.iter().map(|log| self.check(log)).collect::<Vec<_>>();
+        let checked = logs.iter().zip(checks.iter()).filter(|&(_, c)| c.is_ok()).map(|(log, _)| log).collect::<Vec<_>>();
+        let claimed = self.claim_ids(&checked)?;
+        let valid = checked.iter().zip(claimed.iter()).filter(|&(_, c)| *c).map(|(log, _)| *log).collect::<Vec<_>>();
+
+        let locs = match self.write_all(&valid) {
+            Ok(locs) => locs,
+            Err(e) => {
+                self.release_ids(&valid);
+                return Err(e);
             }
+        };
 
-            let mut index_file = self.indices.get_mut(key).unwrap();
+        let mut claimed = claimed.into_iter();
+        let mut locs = locs.into_iter();
+        let mut ret = Vec::with_capacity(logs.len());
 
-            index_file.add(value.to_owned(), loc);
+        for (log, check) in logs.iter().zip(checks.into_iter()) {
+            ret.push(match check.map(|()| claimed.next().unwrap()) {
+                Ok(true) => locs.next().unwrap().and_then(|loc| self.index(log, loc)),
+                Ok(false) => Ok( () ),
+                Err(e) => Err(e)
+            });
         }
 
+        self.release_ids(&valid);
+
+        Ok(ret)
+    }
+
+    /// Adds the log to the log file, returning its location
+    fn write(&self, log: &HashMap<String, LogValue>) -> Result<u64, RecordError> {
+        let mut log_file = self.log_file.write().unwrap();
+        let size = log_file.size();
+        let loc = log_file.add(log)?;
+
+        self.logs_written.add(1);
+        self.bytes_written.add((log_file.size() - size) as usize);
+
+        Ok(loc)
+    }
+
+    /// Adds the logs to the log file with a single write, returning the location of each
+    fn write_all(&self, logs: &[&HashMap<String, LogValue>]) -> Result<Vec<Result<u64, RecordError>>, RecordError> {
+        let mut log_file = self.log_file.write().unwrap();
+        let size = log_file.size();
+        let locs = log_file.add_all(logs)?;
+
+        self.logs_written.add(locs.iter().filter(|l| l.is_ok()).count());
+        self.bytes_written.add((log_file.size() - size) as usize);
+
+        Ok(locs)
+    }
+
+    /// Claims the __id of each log, returning false for a log whose __id is already stored or being stored
+    /// The __ids stay claimed until release_ids, once their logs are indexed and can be found
+    fn claim_ids(&self, logs: &[&HashMap<String, LogValue>]) -> Result<Vec<bool>, RecordError> {
+        let mut claiming = self.claiming.lock().unwrap();
+        let mut ret = Vec::with_capacity(logs.len());
+
+        for log in logs {
+            let id = match log.get("__id") {
+                Some(&LogValue::String(ref id)) => id,
+                _ => {
+                    ret.push(true);
+                    continue;
+                }
+            };
+
+            let stored = match self.postings("__id", &LogValue::String(id.to_owned())) {
+                Ok(locs) => !locs.is_empty(),
+                Err(e) => {
+                    for (log, claimed) in logs.iter().zip(ret.iter()) {
+                        if let (true, Some(&LogValue::String(ref id))) = (*claimed, log.get("__id")) {
+                            claiming.remove(id);
+                        }
+                    }
+
+                    return Err(e);
+                }
+            };
+
+            if stored || claiming.contains(id) {
+                debug!("Skipping log {}, it's already stored", id);
+                ret.push(false);
+            } else {
+                claiming.insert(id.to_owned());
+                ret.push(true);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn release_ids(&self, logs: &[&HashMap<String, LogValue>]) {
+        let mut claiming = self.claiming.lock().unwrap();
+
+        for log in logs {
+            if let Some(&LogValue::String(ref id)) = log.get("__id") {
+                claiming.remove(id);
+            }
+        }
+    }
+
+    /// Checks the mapping accepts the log
+    fn check(&self, log: &HashMap<String, LogValue>) -> Result<(), RecordError> {
+        self.mapping.read().unwrap().check(log).map_err(invalid_input)
+    }
+
+    /// Adds the log at the location in the log file to the indices of the fields the mapping indexes
+    fn index(&self, log: &HashMap<String, LogValue>, loc: u64) -> Result<(), RecordError> {
+        let entries = self.entries(log, loc);
+        let postings = entries.len();
+
+        // go through each entry and create or add to index
+        for (key, value, posting) in entries {
+            if let Some(index_file) = self.indices.read().unwrap().get(&key) {
+                index_file.write().unwrap().add(value, posting);
+                continue;
+            }
+
+            let mut indices = self.indices.write().unwrap();
+
+            // another insert may have created the index while we waited for the lock
+            if !indices.contains_key(&key) {
+                let index_file = IndexFile::new(&self.dir_path, &key)?;
+
+                indices.insert(key.to_owned(), RwLock::new(index_file));
+            }
+
+            indices.get_mut(&key).unwrap().get_mut().unwrap().add(value, posting);
+        }
+
+        self.mem_postings.fetch_add(postings, Ordering::SeqCst);
+
         Ok( () )
     }
 
-    pub fn get(&mut self, key: &str, value: &LogValue) -> Result<Vec<HashMap<String, LogValue>>, RecordError> {
-        // get the locations from the index, or return if the key is not found
-        let locs: Vec<u64> = match self.indices.get_mut(key) {
-            Some(i) => i.get(value)?,
-            None => return Ok(Vec::new())
-        };
+    /// The index, term and posting of each entry for the log at the location in the log file
+    /// The words of a text field are added to its text index, with their positions, and each element of an array on its own
+    /// The values of a keyword field are normalized first, while the stored log keeps them as they were
+    fn entries(&self, log: &HashMap<String, LogValue>, loc: u64) -> Vec<(String, LogValue, u64)> {
+        let mapping = self.mapping.read().unwrap();
+        let mut entries = Vec::with_capacity(log.len());
+
+        for (key, value) in log.iter().filter(|&(k, _)| mapping.is_indexed(k)) {
+            match mapping.analyzer(key) {
+                Some(analyzer) => {
+                    let index = text_index(key);
+
+                    for (term, pos) in analyzer.value_tokens(value) {
+                        entries.push( (index.clone(), LogValue::String(term), analysis::posting(loc, pos)) );
+                    }
+                },
+                None => {
+                    for term in terms(&analysis::normalize(mapping.normalizers(key), value)) {
+                        entries.push( (key.to_owned(), term, loc) );
+                    }
+                }
+            }
+        }
+
+        entries
+    }
+
+    pub fn get(&self, key: &str, value: &LogValue) -> Result<Vec<HashMap<String, LogValue>>, RecordError> {
+        let locs = self.locations(key, value)?;
 
-        let log_file = &self.log_file;
+        let log_file = self.log_file.read().unwrap();
 
-        // fetch the records
+        // fetch the records, reads don't take the file's position so they run in parallel
         let ret :Result<Vec<HashMap<String, LogValue>>, RecordError> = locs.into_par_iter().map(|loc| {
-            log_file.get(loc) //TODO: figure out how to throw error here
+            log_file.get(loc)
         }).collect();
 
         ret
     }
 
-    /// This method is temporary until TcpServer can be closed gracefully
-    pub fn close(&mut self) {
+    /// Returns the location in the log file of every log matching the key and value, in file order
+    /// A value matches a log holding it, alone or in an array, and an array matches a log holding all of its elements
+    /// For a text field, the logs with every word of the value
+    pub fn locations(&self, key: &str, value: &LogValue) -> Result<Vec<u64>, RecordError> {
+        let analyzer = self.mapping.read().unwrap().analyzer(key);
+        let value = &self.normalize(key, value);
+
+        match (analyzer, value) {
+            (Some(analyzer), &LogValue::String(_)) | (Some(analyzer), &LogValue::Array(_)) => {
+                let index = text_index(key);
+                let postings = analyzer.value_tokens(value).into_iter()
+                    .map(|(term, _)| self.postings(&index, &LogValue::String(term)))
+                    .collect::<Result<Vec<_>, RecordError>>()?;
+
+                Ok(analysis::all_of(postings))
+            },
+            (_, &LogValue::Array(ref values)) => self.all_locations(key, values),
+            _ => self.postings(key, value)
+        }
+    }
+
+    /// The logs holding every one of the values in the field
+    /// Logs indexed before array elements were indexed on their own are found by their whole array, which was sorted
+    fn all_locations(&self, key: &str, values: &[LogValue]) -> Result<Vec<u64>, RecordError> {
+        let postings = terms(&LogValue::Array(values.to_vec())).iter()
+            .map(|term| self.postings(key, term))
+            .collect::<Result<Vec<_>, RecordError>>()?;
+
+        let mut locs = intersect(postings);
+        let mut sorted = values.to_vec();
+
+        sorted.sort();
+
+        locs.append(&mut self.postings(key, &LogValue::Array(sorted))?);
+        locs.sort_unstable();
+        locs.dedup();
+
+        Ok(locs)
+    }
+
+    /// Returns the location in the log file of every log matching the query, in file order
+    pub fn query(&self, query: &Query) -> Result<Vec<u64>, RecordError> {
+        match *query {
+            Query::Term(ref key, ref value) => self.locations(key, value),
+            Query::Phrase(ref key, ref text) => self.phrase_locations(key, text),
+            Query::Wildcard(ref key, ref pattern) => {
+                let pattern = analysis::normalize_str(self.mapping.read().unwrap().normalizers(key), pattern);
+
+                self.pattern_locations(key, TermPattern::wildcard(&pattern))
+            },
+            Query::Regex(ref key, ref pattern) => self.pattern_locations(key, TermPattern::regex(pattern)),
+            Query::And(ref queries) => Ok(intersect(queries.iter().map(|q| self.query(q)).collect::<Result<Vec<_>, RecordError>>()?))
+        }
+    }
+
+    /// The logs with a term matching the pattern, found from the index's terms rather than by reading the logs
+    /// For a text field, the pattern is matched against each word
+    /// The index is only locked while a batch of its terms is read, not while the pattern is tested against them
+    fn pattern_locations(&self, key: &str, pattern: Result<TermPattern, String>) -> Result<Vec<u64>, RecordError> {
+        let pattern = pattern.map_err(invalid_input)?;
+        let is_text = self.mapping.read().unwrap().analyzer(key).is_some();
+        let index = if is_text { text_index(key) } else { key.to_owned() };
+        let prefix = pattern.prefix();
+
+        let mem_terms = match self.indices.read().unwrap().get(&index) {
+            Some(i) => i.read().unwrap().mem_terms(prefix, MAX_TERMS_SCANNED + 1),
+            None => return Ok(Vec::new())
+        };
+
+        let mut terms = BTreeSet::new();
+        let mut scanned = 0;
+
+        match_terms(key, &pattern, mem_terms, &mut scanned, &mut terms)?;
+
+        let mut after: Option<String> = None;
+
+        loop {
+            let batch = match self.indices.read().unwrap().get(&index) {
+                Some(i) => i.read().unwrap().disk_terms(prefix, after.as_ref().map(|a| a.as_str()), TERM_SCAN_BATCH),
+                None => Vec::new()
+            };
+
+            if batch.is_empty() {
+                break;
+            }
+
+            after = batch.last().cloned();
+
+            match_terms(key, &pattern, batch, &mut scanned, &mut terms)?;
+        }
+
+        let mut locs = Vec::new();
+
+        for term in terms {
+            locs.append(&mut self.postings(&index, &LogValue::String(term))?);
+        }
+
+        if is_text {
+            locs = locs.into_iter().map(analysis::location).collect();
+        }
+
+        locs.sort_unstable();
+        locs.dedup();
+
+        Ok(locs)
+    }
+
+    /// The logs with the words of the phrase next to each other and in order, using their positions in the text index
+    /// A field that isn't text must have the phrase as its value
+    fn phrase_locations(&self, key: &str, text: &str) -> Result<Vec<u64>, RecordError> {
+        // the mapping's lock is released before normalizing takes it again
+        let analyzer = self.mapping.read().unwrap().analyzer(key);
+        let analyzer = match analyzer {
+            Some(a) => a,
+            None => return self.postings(key, &self.normalize(key, &LogValue::String(text.to_owned())))
+        };
+
+        let tokens = analyzer.tokens(text);
+
+        let first = match tokens.first() {
+            Some(&(_, pos)) => pos,
+            None => return Ok(Vec::new())
+        };
+
+        let index = text_index(key);
+        let mut terms = Vec::with_capacity(tokens.len());
+
+        for (term, pos) in tokens {
+            terms.push( (self.postings(&index, &LogValue::String(term))?, pos - first) );
+        }
+
+        Ok(analysis::phrase(terms))
+    }
+
+    /// The value as the field's normalizers would index it
+    fn normalize(&self, key: &str, value: &LogValue) -> LogValue {
+        analysis::normalize(self.mapping.read().unwrap().normalizers(key), value)
+    }
+
+    /// The postings of a value in an index, sorted
+    fn postings(&self, index: &str, value: &LogValue) -> Result<Vec<u64>, RecordError> {
+        // get the postings from the index, or return if the index is not found
+        match self.indices.read().unwrap().get(index) {
+            Some(i) => {
+                let index_file = i.read().unwrap();
+
+                index_file.get(value)
+            },
+            None => Ok(Vec::new())
+        }
+    }
+
+    /// The mapping applied to the logs inserted on this node
+    pub fn mapping(&self) -> Mapping {
+        self.mapping.read().unwrap().clone()
+    }
+
+    /// Applies an update to the mapping and saves it, returning the new mapping
+    /// Logs already inserted keep the indices they were given
+    pub fn put_mapping(&self, update: &MappingUpdate) -> Result<Mapping, RecordError> {
+        let mut mapping = self.mapping.write().unwrap();
+        let mut updated = mapping.clone();
+
+        updated.apply(update).map_err(invalid_input)?;
+        updated.save(&self.dir_path.join(MAPPING_FILE))?;
+
+        *mapping = updated.clone();
+
+        Ok(updated)
+    }
+
+    /// Reads the log at a location returned by `locations`
+    pub fn get_log(&self, location: u64) -> Result<HashMap<String, LogValue>, RecordError> {
+        self.log_file.read().unwrap().get(location)
+    }
+
+    /// Reads up to `limit` logs, or `max_bytes` of logs, from the log file starting at `location`, see LogFile::scan
+    pub fn scan(&self, location: u64, limit: usize, max_bytes: usize) -> Result<(Vec<HashMap<String, LogValue>>, Option<u64>), RecordError> {
+        self.log_file.read().unwrap().scan(location, limit, max_bytes)
+    }
+
+    /// Returns the __id and __ts of every log with a __ts in the range [start, end)
+    pub fn ids_in_range(&self, start: u64, end: u64) -> Result<Vec<(String, u64)>, RecordError> {
+        let indices = self.indices.read().unwrap();
+
+        let ts_index = match indices.get("__ts") {
+            Some(i) => i.read().unwrap(),
+            None => return Ok(Vec::new())
+        };
+
+        let mut locs = Vec::new();
+
+        for term in ts_index.terms() {
+            let ts = match term {
+                LogValue::Number(ref n) => n.as_u64().unwrap_or(0),
+                _ => continue
+            };
+
+            if ts >= start && ts < end {
+                locs.append(&mut ts_index.get(&term)?);
+            }
+        }
+
+        drop(ts_index);
+
+        let log_file = self.log_file.read().unwrap();
+        let mut ret = Vec::with_capacity(locs.len());
+
+        for loc in locs {
+            let log = log_file.get(loc)?;
+
+            let ts = match log.get("__ts") {
+                Some(&LogValue::Number(ref n)) => n.as_u64().unwrap_or(0),
+                _ => continue
+            };
+
+            if let Some(&LogValue::String(ref id)) = log.get("__id") {
+                ret.push((id.to_owned(), ts));
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Removes the logs with a __ts before the cutoff, in milliseconds since the epoch, returning the number removed
+    /// The logs that are kept are copied to a new log file and indexed again, then the new files replace the old ones.
+    /// Inserts, queries and flushes wait until it's done, and the logs move, so a location from before it is no longer valid
+    pub fn expire(&self, cutoff: u64) -> Result<usize, RecordError> {
+        // the __ts index tells if there's anything to remove, without holding up inserts
+        if !self.has_expired(cutoff) {
+            return Ok(0);
+        }
+
+        let _expiring = self.expiring.write().unwrap();
+        let mut indices = self.indices.write().unwrap();
+        let mut log_file = self.log_file.write().unwrap();
+
+        let start = Instant::now();
+        let tmp_dir = self.dir_path.join(EXPIRE_DIR);
+
+        remove_dir_all(&tmp_dir).ok();
+        create_dir_all(&tmp_dir)?;
+
+        let mut expired = 0;
+        let mut new_indices = HashMap::<String, IndexFile>::new();
+
+        {
+            let mut new_log_file = LogFile::new(&tmp_dir)?;
+            let mut location = 0;
+
+            loop {
+                let (logs, next) = log_file.scan(location, EXPIRE_BATCH, usize::max_value())?;
+
+                for log in logs {
+                    if log_ts(&log).map(|ts| ts < cutoff).unwrap_or(false) {
+                        expired += 1;
+                        continue;
+                    }
+
+                    let loc = new_log_file.add(&log)?;
+
+                    for (key, value, posting) in self.entries(&log, loc) {
+                        if !new_indices.contains_key(&key) {
+                            let index_file = IndexFile::new(&tmp_dir, &key)?;
+
+                            new_indices.insert(key.to_owned(), index_file);
+                        }
+
+                        new_indices.get_mut(&key).unwrap().add(value, posting);
+                    }
+                }
+
+                match next {
+                    Some(n) => location = n,
+                    None => break
+                }
+            }
+
+            new_log_file.close();
+        }
+
+        let names = new_indices.keys().cloned().collect::<Vec<_>>();
+
+        // an index is flushed and written out as it's dropped
+        drop(new_indices);
+
+        for (name, index_file) in indices.drain() {
+            drop(index_file);
+            remove_file(self.dir_path.join(name + ".index"))?;
+        }
+
+        for entry in read_dir(&tmp_dir)? {
+            let path = entry?.path();
+
+            rename(&path, self.dir_path.join(path.file_name().unwrap()))?;
+        }
+
+        let sync = log_file.is_synced();
+
+        *log_file = LogFile::new(&self.dir_path)?;
+        log_file.set_sync(sync);
+
+        for name in names {
+            let index_file = IndexFile::new(&self.dir_path, &name)?;
+
+            indices.insert(name, RwLock::new(index_file));
+        }
+
+        remove_dir_all(&tmp_dir)?;
+
+        self.mem_postings.store(0, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        info!("Expired {} logs from before {}, keeping {}, in {:?}", expired, cutoff, log_file.record_count(), start.elapsed());
+
+        Ok(expired)
+    }
+
+    /// Whether any log has a __ts before the cutoff
+    fn has_expired(&self, cutoff: u64) -> bool {
+        match self.indices.read().unwrap().get("__ts") {
+            Some(ts_index) => ts_index.read().unwrap().terms().iter().any(|term| {
+                match *term {
+                    LogValue::Number(ref n) => n.as_u64().map(|ts| ts < cutoff).unwrap_or(false),
+                    _ => false
+                }
+            }),
+            None => false
+        }
+    }
+
+    /// Changes each time logs are expired, so locations are only valid while it's the same
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Flushes every index and writes the headers of all the files
+    /// Called once the servers have stopped, so nothing else is writing
+    pub fn close(&self) {
         // close the log file
-        self.log_file.close();
+        self.log_file.write().unwrap().close();
 
-        for index_file in self.indices.values_mut().into_iter() {
-            index_file.close();
+        for index_file in self.indices.read().unwrap().values() {
+            index_file.write().unwrap().close();
         }
+
+        self.mem_postings.store(0, Ordering::SeqCst);
     }
 
 /*
@@ -107,20 +678,222 @@ impl DataManager {
     }
 */
 
-    pub fn flush(&mut self) -> () {
-        for val in self.indices.values_mut() {
-            val.flush();
+    /// The number of index entries held in memory, including those being flushed
+    pub fn mem_postings(&self) -> usize {
+        self.mem_postings.load(Ordering::SeqCst)
+    }
+
+    /// Flushes every index that has entries in memory
+    pub fn flush(&self) -> () {
+        for name in self.index_names() {
+            self.flush_index(&name);
+        }
+    }
+
+    /// Flushes the indices with the most entries in memory, until the total is within the budget
+    pub fn flush_to(&self, max_postings: usize) -> () {
+        let mut sizes = self.indices.read().unwrap().iter()
+            .map(|(name, index_file)| (name.to_owned(), index_file.read().unwrap().mem_postings()))
+            .collect::<Vec<_>>();
+
+        sizes.sort_by(|a, b| b.1.cmp(&a.1));
+
+        for (name, _) in sizes {
+            if self.mem_postings() <= max_postings {
+                break;
+            }
+
+            self.flush_index(&name);
+        }
+    }
+
+    /// Writes the metrics of the log file and indices
+    pub fn write_metrics(&self, out: &mut Exposition) {
+        out.counter("logstore_logs_written_total", "Logs written to the log file", self.logs_written.get());
+        out.counter("logstore_log_bytes_written_total", "Bytes written to the log file", self.bytes_written.get());
+        out.labeled("logstore_log_file_records", "Logs in each log file", "gauge", "file",
+                    &[(String::from("logs.data"), self.log_file.read().unwrap().record_count() as usize)]);
+
+        let mut postings = self.indices.read().unwrap().iter()
+            .map(|(name, index_file)| (name.to_owned(), index_file.read().unwrap().mem_postings()))
+            .collect::<Vec<_>>();
+
+        postings.sort();
+
+        out.gauge("logstore_indices", "Field indices", postings.len());
+        out.labeled("logstore_index_mem_postings", "Index entries held in memory waiting to be flushed", "gauge", "index", &postings);
+        out.histogram("logstore_index_flush_duration_seconds", "Time taken to flush an index", &self.flush_seconds);
+    }
+
+    /// Returns the state of the log file and every index
+    pub fn stats(&self) -> Result<StorageStats, RecordError> {
+        let (logs, log_file_size) = {
+            let log_file = self.log_file.read().unwrap();
+
+            (log_file.record_count(), log_file.size())
+        };
+
+        let indices = self.indices.read().unwrap();
+
+        let mut index_stats = indices.iter().map(|(field, index_file)| {
+            let index_file = index_file.read().unwrap();
+
+            IndexStats {
+                field: field.to_owned(),
+                terms: index_file.term_count(),
+                mem_postings: index_file.mem_postings(),
+                flushing: index_file.is_flushing(),
+                last_flush_ms: index_file.last_flush(),
+                size: index_file.file_size()
+            }
+        }).collect::<Vec<_>>();
+
+        index_stats.sort_by(|a, b| a.field.cmp(&b.field));
+
+        let mut logical_indices = Vec::new();
+
+        if let Some(index_file) = indices.get(INDEX_FIELD) {
+            let index_file = index_file.read().unwrap();
+
+            for term in index_file.terms() {
+                if let LogValue::String(ref name) = term {
+                    logical_indices.push((name.to_owned(), index_file.get(&term)?.len()));
+                }
+            }
+        }
+
+        logical_indices.sort();
+
+        Ok(StorageStats {
+            logs,
+            log_file_size,
+            mem_postings: self.mem_postings(),
+            indices: index_stats,
+            logical_indices
+        })
+    }
+
+    fn index_names(&self) -> Vec<String> {
+        self.indices.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Flushes an index, only holding its lock to take the entries and to switch to the new file,
+    /// so inserts and queries continue while it's written
+    fn flush_index(&self, name: &str) {
+        let _expiring = self.expiring.read().unwrap();
+
+        let flush = match self.indices.read().unwrap().get(name) {
+            Some(index_file) => index_file.write().unwrap().start_flush(),
+            None => None
+        };
+
+        let flush = match flush {
+            Some(f) => f,
+            None => return
+        };
+
+        let start = Instant::now();
+        let written = flush.write();
+
+        if let Some(index_file) = self.indices.read().unwrap().get(name) {
+            if let Ok(postings) = index_file.write().unwrap().finish_flush(flush, written) {
+                self.mem_postings.fetch_sub(postings, Ordering::SeqCst);
+                self.flush_seconds.observe(start.elapsed());
+
+                debug!("Flushed {} entries of index {} in {:?}", postings, name, start.elapsed());
+            }
+        }
+    }
+}
+
+/// The terms a value is indexed under: the value itself, or the distinct elements of an array, nested arrays flattened
+/// Nulls in an array aren't indexed, as in Elasticsearch
+fn terms(value: &LogValue) -> Vec<LogValue> {
+    fn flatten(value: &LogValue, out: &mut Vec<LogValue>) {
+        match *value {
+            LogValue::Array(ref values) => values.iter().for_each(|v| flatten(v, out)),
+            LogValue::Null => (),
+            ref v => out.push(v.clone())
+        }
+    }
+
+    match *value {
+        LogValue::Array(_) => {
+            let mut ret = Vec::new();
+
+            flatten(value, &mut ret);
+
+            // a log is posted once under each term
+            ret.sort();
+            ret.dedup();
+
+            ret
+        },
+        ref v => vec![v.clone()]
+    }
+}
+
+/// The __ts of a log, in milliseconds since the epoch
+fn log_ts(log: &HashMap<String, LogValue>) -> Option<u64> {
+    match log.get("__ts") {
+        Some(&LogValue::Number(ref n)) => n.as_u64(),
+        _ => None
+    }
+}
+
+/// The postings in every one of the sorted lists
+fn intersect(postings: Vec<Vec<u64>>) -> Vec<u64> {
+    let mut lists = postings.into_iter();
+
+    let first = match lists.next() {
+        Some(list) => list,
+        None => return Vec::new()
+    };
+
+    lists.fold(first, |acc, list| acc.into_iter().filter(|p| list.binary_search(p).is_ok()).collect())
+}
+
+/// Adds the terms the pattern matches to those already found, refusing a pattern that tests or matches too many
+fn match_terms<I>(key: &str, pattern: &TermPattern, batch: I, scanned: &mut usize, terms: &mut BTreeSet<String>) -> Result<(), RecordError>
+    where I: IntoIterator<Item=String> {
+    for term in batch {
+        *scanned += 1;
+
+        if *scanned > MAX_TERMS_SCANNED {
+            return Err(invalid_input(format!("The pattern has to be tested against more than {} terms of {}, start it with more literal characters", MAX_TERMS_SCANNED, key)));
+        }
+
+        if pattern.is_match(&term) {
+            terms.insert(term);
+        }
+
+        if terms.len() > MAX_EXPANSIONS {
+            return Err(invalid_input(format!("The pattern matches more than {} terms of {}", MAX_EXPANSIONS, key)));
         }
     }
+
+    Ok( () )
+}
+
+/// An error in a request, rather than in the storage
+fn invalid_input(message: String) -> RecordError {
+    RecordError::from(IOError::new(ErrorKind::InvalidInput, message))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs::{create_dir_all, remove_dir_all};
     use std::path::Path;
-    use data_manager::DataManager;
+    use std::sync::Arc;
+    use std::thread;
+    use std::collections::BTreeSet;
+    use data_manager::{match_terms, DataManager, EXPIRE_DIR};
     use log_value::LogValue;
+    use mapping::{Dynamic, MappingUpdate};
+    use pattern::{TermPattern, MAX_TERMS_SCANNED};
+    use query::Query;
     use serde_json::Number;
-    use json::json2map;
+    use json::{json2map, INDEX_FIELD};
 
     #[test]
     fn insert_test() {
@@ -137,7 +910,12 @@ mod tests {
         });
 
         let mut log = json2map(&json_str.to_string()).unwrap();
-        let mut dm = DataManager::new(Path::new("/tmp/")).unwrap();
+        let dir = Path::new("/tmp/insert_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = DataManager::new(dir).unwrap();
 
         for i in 0..50 {
             log.insert(String::from("count"), LogValue::Number(Number::from(i)));
@@ -146,5 +924,315 @@ mod tests {
 
     }
 
+    #[test]
+    fn insert_all_test() {
+        let dir = Path::new("/tmp/insert_all_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let log = json2map(&json!({ "host": "localhost", "batch": true }).to_string()).unwrap();
+        let dm = DataManager::new(dir).unwrap();
+
+        let results = dm.insert_all(&vec![log.clone(), log.clone()]).unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.is_ok()));
+
+        // a log with an __id that's already stored succeeds without being stored again
+        dm.insert(&log).unwrap();
+
+        let logs = dm.get("__id", log.get("__id").unwrap()).unwrap();
+
+        assert_eq!(logs.len(), 1);
+        assert_eq!(dm.stats().unwrap().logs, 1);
+    }
+
+    #[test]
+    fn stats_test() {
+        let dir = Path::new("/tmp/stats_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let mut log = json2map(&json!({ "host": "localhost" }).to_string()).unwrap();
+        let dm = DataManager::new(dir).unwrap();
+
+        log.insert(String::from(INDEX_FIELD), LogValue::String(String::from("stats_test")));
+        dm.insert(&log).unwrap();
+
+        let stats = dm.stats().unwrap();
+
+        assert_eq!(stats.logs, 1);
+        assert!(stats.indices.iter().any(|i| i.field == "host" && i.terms == 1));
+        assert_eq!(stats.logical_indices, vec![(String::from("stats_test"), 1)]);
+    }
+
+    #[test]
+    fn expire_test() {
+        let dir = Path::new("/tmp/expire_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = DataManager::new(dir).unwrap();
+
+        for i in 0..10u64 {
+            let mut log = json2map(&json!({ "host": "localhost", "n": i }).to_string()).unwrap();
+
+            log.insert(String::from("__ts"), LogValue::Number(Number::from(1000 + i)));
+            dm.insert(&log).unwrap();
+
+            // half of the entries are on disk
+            if i == 4 {
+                dm.flush();
+            }
+        }
+
+        let generation = dm.generation();
+
+        assert_eq!(dm.expire(1000).unwrap(), 0);
+        assert_eq!(dm.generation(), generation);
+
+        assert_eq!(dm.expire(1003).unwrap(), 3);
+        assert_ne!(dm.generation(), generation);
+
+        assert_eq!(dm.stats().unwrap().logs, 7);
+        assert_eq!(dm.get("host", &LogValue::String(String::from("localhost"))).unwrap().len(), 7);
+        assert!(dm.get("n", &LogValue::Number(Number::from(2))).unwrap().is_empty());
+        assert_eq!(dm.get("n", &LogValue::Number(Number::from(3))).unwrap().len(), 1);
+        assert!(!dir.join(EXPIRE_DIR).exists());
+
+        // the new files are opened again
+        drop(dm);
+
+        let dm = DataManager::new(dir).unwrap();
+
+        assert_eq!(dm.get("host", &LogValue::String(String::from("localhost"))).unwrap().len(), 7);
+    }
+
+    #[test]
+    fn concurrent_test() {
+        let dir = Path::new("/tmp/concurrent_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = Arc::new(DataManager::new(dir).unwrap());
+        let host = LogValue::String(String::from("concurrent"));
+
+        let threads = (0..4).map(|t| {
+            let dm = dm.clone();
+            let host = host.clone();
+
+            // each thread inserts and queries at the same time as the others
+            thread::spawn(move || for i in 0..25 {
+                dm.insert(&json2map(&json!({ "host": "concurrent", "n": t * 25 + i }).to_string()).unwrap()).unwrap();
+                assert!(!dm.get("host", &host).unwrap().is_empty());
+            })
+        }).collect::<Vec<_>>();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(dm.get("host", &host).unwrap().len(), 100);
+    }
+
+    #[test]
+    fn mapping_test() {
+        let dir = Path::new("/tmp/mapping_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = DataManager::new(dir).unwrap();
+
+        dm.put_mapping(&MappingUpdate::from_json(&json!({
+            "dynamic": "strict",
+            "properties": {
+                "host": { "type": "keyword" },
+                "message": { "type": "keyword", "index": false }
+            }
+        })).unwrap()).unwrap();
+
+        let log = json2map(&json!({ "host": "mapped", "message": "not indexed" }).to_string()).unwrap();
+        let unknown = json2map(&json!({ "host": "mapped", "other": 1 }).to_string()).unwrap();
+
+        let results = dm.insert_all(&vec![log.clone(), unknown.clone()]).unwrap();
+
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(dm.insert(&unknown).is_err());
+
+        // the message is stored but not indexed
+        let logs = dm.get("host", log.get("host").unwrap()).unwrap();
+
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].get("message"), log.get("message"));
+        assert!(dm.get("message", log.get("message").unwrap()).unwrap().is_empty());
+
+        // the mapping is reloaded with the data directory
+        dm.close();
+
+        assert_eq!(DataManager::new(dir).unwrap().mapping().dynamic, Dynamic::Reject);
+    }
+
+    #[test]
+    fn text_test() {
+        let dir = Path::new("/tmp/text_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = DataManager::new(dir).unwrap();
+
+        dm.put_mapping(&MappingUpdate::from_json(&json!({ "properties": { "message": { "type": "text", "analyzer": "stop" } } })).unwrap()).unwrap();
+
+        for message in &["Connection to the DB timed out", "Timed out waiting for a connection", "connection reset"] {
+            dm.insert(&json2map(&json!({ "message": message }).to_string()).unwrap()).unwrap();
+        }
+
+        let word = |w: &str| LogValue::String(String::from(w));
+        let phrase = |p: &str| dm.query(&Query::Phrase(String::from("message"), String::from(p))).unwrap().len();
+
+        // every word must match, in any case and order
+        assert_eq!(dm.get("message", &word("connection")).unwrap().len(), 3);
+        assert_eq!(dm.get("message", &word("TIMED connection")).unwrap().len(), 2);
+        assert_eq!(dm.get("message", &word("the")).unwrap().len(), 0);
+
+        // phrases must match in order, with stop words keeping their places
+        assert_eq!(phrase("timed out"), 2);
+        assert_eq!(phrase("connection timed"), 0);
+        assert_eq!(phrase("connection to a db"), 1);
+        assert_eq!(phrase("connection db"), 0);
+
+        // the stored log keeps the original text
+        assert_eq!(dm.get("message", &word("reset")).unwrap()[0].get("message"), Some(&word("connection reset")));
+    }
+
+    #[test]
+    fn pattern_test() {
+        let dir = Path::new("/tmp/pattern_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = DataManager::new(dir).unwrap();
+
+        for host in &["web-1", "web-2", "api-1"] {
+            dm.insert(&json2map(&json!({ "host": host }).to_string()).unwrap()).unwrap();
+        }
+
+        let count = |q: Query| dm.query(&q).unwrap().len();
+
+        assert_eq!(count(Query::Wildcard(String::from("host"), String::from("web-*"))), 2);
+        assert_eq!(count(Query::Wildcard(String::from("host"), String::from("*-1"))), 2);
+        assert_eq!(count(Query::Regex(String::from("host"), String::from("(web|api)-[12]"))), 3);
+        assert_eq!(count(Query::Regex(String::from("missing"), String::from(".*"))), 0);
+
+        assert!(dm.query(&Query::Regex(String::from("host"), String::from("web-["))).is_err());
+
+        // the same terms are found once they're on disk
+        dm.flush();
+
+        assert_eq!(count(Query::Wildcard(String::from("host"), String::from("web-*"))), 2);
+        assert_eq!(count(Query::Regex(String::from("host"), String::from("(web|api)-[12]"))), 3);
+
+        // testing more than MAX_TERMS_SCANNED terms is refused
+        let pattern = TermPattern::regex(".*-1").unwrap();
+        let terms = (0..MAX_TERMS_SCANNED + 1).map(|i| i.to_string());
+        let mut found = BTreeSet::new();
+        let mut scanned = 0;
+
+        assert!(match_terms("host", &pattern, terms, &mut scanned, &mut found).is_err());
+    }
+
+    #[test]
+    fn array_test() {
+        let dir = Path::new("/tmp/array_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = DataManager::new(dir).unwrap();
+
+        for tags in &[json!(["web", "prod"]), json!(["prod", "db", "prod"]), json!("web"), json!([["web"], null])] {
+            dm.insert(&json2map(&json!({ "tags": tags }).to_string()).unwrap()).unwrap();
+        }
+
+        let tag = |t: &str| LogValue::String(String::from(t));
+        let tags = |ts: &[&str]| LogValue::Array(ts.iter().map(|t| tag(t)).collect());
+
+        // an element matches any log containing it
+        assert_eq!(dm.get("tags", &tag("web")).unwrap().len(), 3);
+        assert_eq!(dm.get("tags", &tag("prod")).unwrap().len(), 2);
+        assert_eq!(dm.query(&Query::Wildcard(String::from("tags"), String::from("d*"))).unwrap().len(), 1);
+
+        // an array matches the logs containing all of its elements, in any order
+        assert_eq!(dm.get("tags", &tags(&["prod", "web"])).unwrap().len(), 1);
+        assert_eq!(dm.get("tags", &tags(&["db", "web"])).unwrap().len(), 0);
+
+        // the stored log keeps the original array
+        assert_eq!(dm.get("tags", &tag("db")).unwrap()[0].get("tags"), Some(&tags(&["prod", "db", "prod"])));
+    }
+
+    #[test]
+    fn and_test() {
+        let dir = Path::new("/tmp/and_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = DataManager::new(dir).unwrap();
+
+        for &(index, host) in &[("logs-app", "web-1"), ("logs-app", "web-2"), ("metrics", "web-1")] {
+            let mut log = json2map(&json!({ "host": host, "source": index }).to_string()).unwrap();
+
+            log.insert(String::from(INDEX_FIELD), LogValue::String(String::from(index)));
+            dm.insert(&log).unwrap();
+        }
+
+        let query = Query::Term(String::from("host"), LogValue::String(String::from("web-1")));
+
+        assert_eq!(dm.query(&query).unwrap().len(), 2);
+        assert_eq!(dm.query(&query.clone().in_index("logs-app")).unwrap().len(), 1);
+        assert_eq!(dm.query(&query.clone().in_index("logs-*")).unwrap().len(), 1);
+        assert_eq!(dm.query(&query.clone().in_index("other")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn normalizer_test() {
+        let dir = Path::new("/tmp/normalizer_dm_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = DataManager::new(dir).unwrap();
+
+        dm.put_mapping(&MappingUpdate::from_json(&json!({ "properties": { "level": { "type": "keyword", "normalizer": ["nfkc", "lowercase", "trim"] } } })).unwrap()).unwrap();
+
+        for level in &[json!("ERROR"), json!(" error "), json!("ＥＲＲＯＲ"), json!(["Warn", "Error"]), json!("Info")] {
+            dm.insert(&json2map(&json!({ "level": level, "user": level }).to_string()).unwrap()).unwrap();
+        }
+
+        let level = |l: &str| LogValue::String(String::from(l));
+
+        // the value searched for is normalized too
+        assert_eq!(dm.get("level", &level("error")).unwrap().len(), 4);
+        assert_eq!(dm.get("level", &level("Error ")).unwrap().len(), 4);
+        assert_eq!(dm.get("level", &LogValue::Array(vec![level("WARN"), level("error")])).unwrap().len(), 1);
+        assert_eq!(dm.query(&Query::Phrase(String::from("level"), String::from("INFO"))).unwrap().len(), 1);
+        assert_eq!(dm.query(&Query::Wildcard(String::from("level"), String::from("ERR*"))).unwrap().len(), 4);
+
+        // fields without a normalizer are indexed as they are
+        assert_eq!(dm.get("user", &level("error")).unwrap().len(), 0);
+        assert_eq!(dm.get("user", &level("ERROR")).unwrap().len(), 1);
+
+        // the stored log keeps the original value
+        assert_eq!(dm.get("level", &level("info")).unwrap()[0].get("level"), Some(&level("Info")));
+        assert_eq!(dm.get("user", &level(" error ")).unwrap()[0].get("level"), Some(&level(" error ")));
+    }
+
 }
 
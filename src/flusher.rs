@@ -0,0 +1,95 @@
+use std::io::Error as IOError;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ::data_manager::DataManager;
+
+/// How often the flusher checks the number of index entries held in memory
+const FLUSH_CHECK_MS: u64 = 250;
+
+/// Flushes the indices on a background thread, every interval and whenever the
+/// index entries held in memory exceed the budget
+pub struct Flusher {
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<()>
+}
+
+impl Flusher {
+    /// Starts the flusher thread; with no interval, indices are only flushed to stay within the budget
+    pub fn start(dm: Arc<DataManager>, interval: Option<Duration>, max_postings: usize) -> Result<Flusher, IOError> {
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread_stopping = stopping.clone();
+
+        let thread = thread::Builder::new()
+            .name("index flusher".to_string())
+            .spawn(move || {
+                let mut last_flush = Instant::now();
+
+                while !thread_stopping.load(Ordering::SeqCst) {
+                    thread::park_timeout(Duration::from_millis(FLUSH_CHECK_MS));
+
+                    if interval.map(|i| last_flush.elapsed() >= i).unwrap_or(false) {
+                        dm.flush();
+                        last_flush = Instant::now();
+                    } else if dm.mem_postings() > max_postings {
+                        info!("{} index entries in memory, over the budget of {}", dm.mem_postings(), max_postings);
+
+                        // flush down to half the budget, so the next few inserts don't flush again
+                        dm.flush_to(max_postings / 2);
+                    }
+                }
+            })?;
+
+        Ok(Flusher { stopping, thread })
+    }
+
+    /// Stops the thread, waiting for a flush in progress to finish
+    pub fn stop(self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.thread.thread().unpark();
+
+        if self.thread.join().is_err() {
+            error!("Index flusher panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::data_manager::DataManager;
+    use ::flusher::Flusher;
+    use ::json::json2map;
+    use ::log_value::LogValue;
+    use ::utils::TestDir;
+
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn flushes_over_budget() {
+        let dir = TestDir::new("flusher_test");
+        let dm = Arc::new(DataManager::new(&dir).unwrap());
+        let flusher = Flusher::start(dm.clone(), None, 100).unwrap();
+
+        for i in 0..100 {
+            dm.insert(&json2map(&json!({ "host": "localhost", "count": i }).to_string()).unwrap()).unwrap();
+        }
+
+        for _ in 0..40 {
+            if dm.mem_postings() <= 100 {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        flusher.stop();
+
+        assert!(dm.mem_postings() <= 100);
+        // the flushed entries are still found, once each
+        assert_eq!(dm.get("host", &LogValue::String(String::from("localhost"))).unwrap().len(), 100);
+    }
+}
@@ -0,0 +1,156 @@
+use rmps::encode::to_vec;
+use rmps::decode::from_slice;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{remove_file, rename};
+use std::path::Path;
+use std::rc::Rc;
+
+use ::record_file::RecordFile;
+use ::log_value::LogValue;
+use ::record_error::RecordError;
+
+const FILE_HEADER: &[u8; 12] = b"LOGHINTS\x01\x00\x00\x00";
+
+/// Holds the logs that could not be delivered to a node while it was unavailable
+/// The hints are replayed to the node once it is back up, and only removed once it has acknowledged them
+pub struct HintFile {
+    rec_file: RecordFile,
+    node_id: u32
+}
+
+impl HintFile {
+    /// Creates or opens the hint file for a node
+    pub fn new(dir_path: &Path, node_id: u32) -> Result<HintFile, RecordError> {
+        let file_path = dir_path.join(format!("hints_{}.data", node_id));
+
+        let rec_file = RecordFile::new(&file_path, FILE_HEADER)?;
+
+        if rec_file.record_count > 0 {
+            info!("Found {} hints for node {}", rec_file.record_count, node_id);
+        }
+
+        Ok(HintFile { rec_file, node_id })
+    }
+
+    /// The number of hints waiting to be replayed
+    pub fn len(&self) -> u32 {
+        self.rec_file.record_count
+    }
+
+    /// Adds a log to be replayed later
+    /// The header is updated with each hint, so the hints survive the process crashing
+    pub fn add(&mut self, log: &HashMap<String, LogValue>) -> Result<(), RecordError> {
+        let buff = to_vec(log)?;
+
+        self.rec_file.append(&buff)?;
+        self.rec_file.write_header()?;
+
+        Ok( () )
+    }
+
+    /// Returns all of the hints in the file, in the order they were added, leaving them in the file
+    pub fn read(&mut self) -> Result<Vec<HashMap<String, LogValue>>, RecordError> {
+        let mut ret = Vec::with_capacity(self.rec_file.record_count as usize);
+
+        for rec in (&mut self.rec_file).into_iter() {
+            ret.push(from_slice::<HashMap<String, LogValue>>(&rec)?);
+        }
+
+        debug!("Read {} hints for node {}", ret.len(), self.node_id);
+
+        Ok(ret)
+    }
+
+    /// Removes the first hints, once the node has acknowledged them, keeping any added since they were read
+    /// The hints that remain are written to a new file which replaces this one, so a crash leaves one or the other
+    pub fn remove(&mut self, count: u32) -> Result<(), RecordError> {
+        let remaining = (&mut self.rec_file).into_iter().skip(count as usize).collect::<Vec<_>>();
+
+        let file_path = self.rec_file.file_path.clone();
+        let tmp_path = file_path.with_extension("tmp");
+
+        // a removal that failed part way may have left its file behind
+        if tmp_path.exists() {
+            remove_file(&tmp_path)?;
+        }
+
+        let mut rec_file = RecordFile::new(&tmp_path, FILE_HEADER)?;
+
+        rec_file.append_all(&remaining.iter().map(|r| r.as_slice()).collect::<Vec<_>>())?;
+        rec_file.sync()?;
+
+        rename(&tmp_path, &file_path)?;
+
+        rec_file.file_path = file_path;
+        self.rec_file = rec_file;
+
+        debug!("Removed {} hints for node {}, {} remaining", count, self.node_id, remaining.len());
+
+        Ok( () )
+    }
+
+    /// Writes the header, called once nothing else is adding hints
+    pub fn close(&mut self) {
+        self.rec_file.close();
+    }
+}
+
+/// Stores a hint, returning true if the hint was written
+pub fn store_hint(hints: &Option<Rc<RefCell<HintFile>>>, log: &HashMap<String, LogValue>) -> bool {
+    let hints = match *hints {
+        Some(ref h) => h,
+        None => return false
+    };
+
+    let mut hint_file = hints.borrow_mut();
+
+    match hint_file.add(log) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Unable to store hint for node {}: {}", hint_file.node_id, e.to_string());
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::hint_file::HintFile;
+    use ::json::json2map;
+    use ::utils::TestDir;
+
+    use std::mem;
+
+    #[test]
+    fn add_remove() {
+        let dir = TestDir::new("hints_test");
+        let mut hint_file = HintFile::new(&dir, 99).unwrap();
+        let log = |n: u32| json2map(&json!({ "a": "something", "b": n }).to_string()).unwrap();
+        let logs = (0..4).map(log).collect::<Vec<_>>();
+
+        hint_file.add(&logs[0]).unwrap();
+        hint_file.add(&logs[1]).unwrap();
+
+        assert_eq!(hint_file.len(), 2);
+
+        // reading leaves the hints in place until they're acknowledged
+        assert_eq!(hint_file.read().unwrap(), vec![logs[0].clone(), logs[1].clone()]);
+        assert_eq!(hint_file.len(), 2);
+
+        // a hint stored while replaying is kept
+        hint_file.add(&logs[2]).unwrap();
+        hint_file.remove(2).unwrap();
+
+        assert_eq!(hint_file.read().unwrap(), vec![logs[2].clone()]);
+
+        // the hints survive a crash, when the file is never closed
+        hint_file.add(&logs[3]).unwrap();
+        mem::forget(hint_file);
+
+        let mut hint_file = HintFile::new(&dir, 99).unwrap();
+
+        assert_eq!(hint_file.read().unwrap(), vec![logs[2].clone(), logs[3].clone()]);
+    }
+}
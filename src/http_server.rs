@@ -4,7 +4,7 @@ use futures::future::FutureResult;
 use futures::{Future, Stream};
 use futures::stream;
 
-use std::io::{Error as IOError};
+use std::io::{Error as IOError, ErrorKind};
 
 use hyper;
 use hyper::{Body, Chunk, Method, StatusCode};
@@ -14,22 +14,48 @@ use hyper::server::{Http, Request, Response, Service};
 use tokio_core::reactor::Handle;
 
 use rpc_server::RPCClient;
-use rpc_codec::{RequestMessage, ResponseMessage};
+use rpc_codec::{RequestMessage, ResponseMessage, frame_chunks, log_size};
 use log_value::LogValue;
-use json::{map2json, value2logvalue};
-use serde_json::{Value, Map, from_slice};
+use json::{get_ts, map2json, value2logvalue, INDEX_FIELD};
+use repair::{repair_all, REPAIR_LOOKBACK_MS};
+use rebalance::{start_rebalance, RebalanceState};
+use hint_file::store_hint;
+use auth::{encode_key, ApiKey, AuthError, KeyStore, Operation, Role};
+use shutdown::{Drain, RequestGuard};
+use data_manager::{DataManager, StorageStats};
+use mapping::MappingUpdate;
+use query::Query;
+use metrics::{Exposition, Gauge, GaugeGuard, Metrics};
+use cluster::{cluster_status, ClusterStatus, NodeState, placement_key, replica_buckets, read_buckets, REPLICATION_FACTOR};
+use serde_json::{Value, Map, from_slice, to_value};
 
 use std::rc::Rc;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time;
 use std::str::from_utf8;
+use std::sync::Arc;
+use std::time::Instant;
 
-struct ElasticsearchService(Rc<HashMap<u32, RPCClient>>);
+struct ElasticsearchService {
+    clients: Rc<HashMap<u32, RPCClient>>,
+    handle: Handle,
+    rebalance: RebalanceState,
+    rebalance_path: PathBuf,
+    keys: Rc<RefCell<KeyStore>>,
+    drain: Drain,
+    data_manager: Arc<DataManager>, // this node's storage, for its metrics
+    metrics: Arc<Metrics>,
+    _connection: GaugeGuard         // a service is created for each connection
+}
 
 pub type ResponseStream = Box<Stream<Item = Chunk, Error = Error>>;
 
 static NOTFOUND: &[u8] = b"Not Found";
+static BADREQUEST: &[u8] = b"Bad Request";
 static VERSION_RESPONSE: &[u8] = br#"
 {
   "name" : "logstore",
@@ -44,39 +70,23 @@ static VERSION_RESPONSE: &[u8] = br#"
   "tagline" : "You Know, for Search"
 }"#;
 
-static BULK_RESPONSE: &[u8] = br#"
-{
-   "took": 1,
-   "errors": false,
-   "items": [
-      {
-         "index": {
-            "_index": "test",
-            "_type": "_doc",
-            "_id": "1",
-            "_version": 1,
-            "result": "created",
-            "_shards": {
-               "total": 2,
-               "successful": 1,
-               "failed": 0
-            },
-            "status": 201,
-            "_seq_no" : 0,
-            "_primary_term": 1
-         }
-      }
-   ]
-}
-"#;
-
-
-fn parse_logs(log_chunk: Chunk) -> Box<Stream<Item=RequestMessage, Error=hyper::Error>> {
-    let log_str = String::from_utf8(log_chunk.to_vec()).unwrap();
+/// Parses the logs in a _bulk POST, along with the indices they're stored in, including ALL_INDICES if any log has no index
+/// Fields starting with __ are dropped, so a log can't set its own __index or __id
+/// A body that isn't UTF-8 is refused as a whole, as its lines can't be found
+fn parse_logs(log_chunk: Chunk, path_index: Option<String>) -> Result<(Vec<HashMap<String, LogValue>>, Vec<String>), String> {
+    let log_str = String::from_utf8(log_chunk.to_vec()).map_err(|e| format!("The body is not UTF-8: {}", e))?;
+    let mut indices = Vec::new();
+    let mut next_index = None; // the index named by the meta line before a log
 
-    let req_stream =
-        log_str.lines().map(move |line| {
-            let v: Value = from_slice(line.as_bytes()).unwrap();
+    let logs =
+        log_str.lines().map(|line| {
+            let v: Value = match from_slice(line.as_bytes()) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Read invalid JSON from _bulk POST: {}: {}", e, line);
+                    return None;
+                }
+            };
 
             if !v.is_object() {
                 warn!("Read non-object from _bulk POST: {}", line);
@@ -87,24 +97,675 @@ fn parse_logs(log_chunk: Chunk) -> Box<Stream<Item=RequestMessage, Error=hyper::
 
             // we want to skip the meta info
             // could use a counter for this as it's: meta, data, meta, etc...
-            if json_map.contains_key("index") {
+            if let Some(meta) = json_map.get("index") {
+                next_index = meta.get("_index").and_then(|i| i.as_str()).map(String::from);
+
+                if let Some(ref index) = next_index {
+                    indices.push(index.clone());
+                }
+
                 return None;
             }
 
+            let fields = json_map.iter()
+                .filter(|&(k, _)| !k.starts_with("__"))
+                .map(|(k, v)| (k.to_owned(), v.clone()))
+                .collect::<Map<String, Value>>();
+
             // convert the JSON Map to a LogValue HashMap
-            let log_value_map = value2logvalue(&json_map);
+            let mut log = value2logvalue(&fields);
+
+            // record the logical index, so it can be listed
+            match next_index.take().or_else(|| path_index.clone()) {
+                Some(index) => { log.insert(String::from(INDEX_FIELD), LogValue::String(index)); },
+                None => indices.push(String::from(ALL_INDICES))
+            }
 
-            // create the RPC request message
-            Some(RequestMessage::Insert(log_value_map))
+            Some(log)
         });
 
-    Box::new(
-        stream::iter_ok(
-            req_stream.filter(move |m| m.is_some()) // filter out the Nones
-                         .map(move |o| o.unwrap()) // convert from Some(r) -> r
-                         .collect::<Vec<_>>()
-        )
-    )
+    let logs = logs.filter(move |m| m.is_some()) // filter out the Nones
+        .map(move |o| o.unwrap()) // convert from Some(r) -> r
+        .collect::<Vec<_>>();
+
+    Ok((logs, indices))
+}
+
+/// Groups the logs by the nodes that hold them, sending each live node a single InsertAll
+/// Hints are stored for the nodes that are down, or that fail the InsertAll
+/// Resolves to whether each log was stored by at least one replica, or a hint was stored for it
+fn route_bulk(clients: &HashMap<u32, RPCClient>, logs: Vec<HashMap<String, LogValue>>) -> Box<Future<Item=Vec<bool>, Error=hyper::Error>> {
+    let mut stored = vec![false; logs.len()];
+    let mut batches = HashMap::<u32, Vec<usize>>::new();
+
+    for (i, log) in logs.iter().enumerate() {
+        let key = match log.get("__id") {
+            Some(&LogValue::String(ref id)) => placement_key(id),
+            _ => 0
+        };
+
+        for bucket in replica_buckets(key, clients.len() as u32, REPLICATION_FACTOR) {
+            let client = match clients.get(&bucket) {
+                Some(c) => c,
+                None => continue
+            };
+
+            if client.state() == NodeState::Down {
+                stored[i] |= client.add_hint(log);
+            } else {
+                batches.entry(bucket).or_insert_with(Vec::new).push(i);
+            }
+        }
+    }
+
+    let logs = Rc::new(logs);
+
+    // split each node's batch so every InsertAll fits in a frame
+    let batches = batches.into_iter().flat_map(|(bucket, indices)| {
+        frame_chunks(indices, clients[&bucket].max_frame_size(), |i| log_size(&logs[*i]))
+            .into_iter()
+            .map(move |chunk| (bucket, chunk))
+    }).collect::<Vec<_>>();
+
+    let batch_futures = batches.into_iter().map(|(bucket, indices)| {
+        let client = &clients[&bucket];
+        let batch = indices.iter().map(|i| logs[*i].clone()).collect::<Vec<_>>();
+        let hints = client.hints();
+        let logs = logs.clone();
+
+        client.make_request(RequestMessage::InsertAll(batch)).then(move |res| {
+            let results = match res {
+                Ok(ResponseMessage::InsertResults(results)) => {
+                    results.into_iter().map(|r| {
+                        if let Err(ref e) = r {
+                            warn!("Node {} rejected log: {}", bucket, e);
+                        }
+
+                        r.is_ok()
+                    }).collect::<Vec<_>>()
+                },
+                Ok(ResponseMessage::Error { code, message, retryable }) => {
+                    warn!("Node {} failed batch ({:?}): {}", bucket, code, message);
+
+                    // a retryable error can be replayed later, otherwise the batch failed
+                    if retryable {
+                        indices.iter().map(|i| store_hint(&hints, &logs[*i])).collect::<Vec<_>>()
+                    } else {
+                        vec![false; indices.len()]
+                    }
+                },
+                Ok(r) => {
+                    warn!("Unexpected response to InsertAll from node {}: {:?}", bucket, r);
+                    vec![false; indices.len()]
+                },
+                Err(e) => {
+                    warn!("Error inserting batch on node {}, storing hints: {}", bucket, e);
+                    indices.iter().map(|i| store_hint(&hints, &logs[*i])).collect::<Vec<_>>()
+                }
+            };
+
+            Ok::<_, hyper::Error>(indices.into_iter().zip(results.into_iter()).collect::<Vec<_>>())
+        })
+    }).collect::<Vec<_>>();
+
+    Box::new(future::join_all(batch_futures).map(move |batch_results| {
+        for results in batch_results {
+            for (i, ok) in results {
+                stored[i] |= ok;
+            }
+        }
+
+        stored
+    }))
+}
+
+/// Builds an Elasticsearch style response to a _bulk request, with the result of each log
+fn bulk_json(ids: Vec<String>, stored: Vec<bool>, took: u64) -> Value {
+    let errors = stored.iter().any(|s| !s);
+
+    let items = ids.into_iter().zip(stored.into_iter()).map(|(id, s)| {
+        if s {
+            json!({ "index": { "_type": "_doc", "_id": id, "result": "created", "status": 201 } })
+        } else {
+            json!({ "index": { "_type": "_doc", "_id": id, "status": 503, "error": {
+                "type": "unavailable_shards_exception",
+                "reason": "No replica stored the log"
+            } } })
+        }
+    }).collect::<Vec<_>>();
+
+    json!({ "took": took, "errors": errors, "items": items })
+}
+
+/// Returns the value of a parameter in a query string
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&').find(|p| p.starts_with(name) && p[name.len()..].starts_with('=')).map(|p| &p[name.len() + 1..])
+}
+
+/// Checks for a parameter given with or without a value, such as ?v
+fn has_param(query: Option<&str>, name: &str) -> bool {
+    query.map(|q| q.split('&').any(|p| p == name || (p.starts_with(name) && p[name.len()..].starts_with('=')))).unwrap_or(false)
+}
+
+/// Parses a query string of the form q=key:value, see Query::parse
+fn parse_query(query: Option<&str>) -> Option<Query> {
+    Query::parse(&percent_decode(query_param(query, "q")?)?)
+}
+
+/// Decodes a URL encoded parameter, with + as a space
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+
+                bytes.push(u8::from_str_radix(from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b)
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+fn nodes_json(clients: &HashMap<u32, RPCClient>) -> Value {
+    let mut ids = clients.keys().cloned().collect::<Vec<_>>();
+
+    ids.sort();
+
+    let nodes = ids.into_iter().map(|id| {
+        let client = clients.get(&id).unwrap();
+        let health = client.health();
+        let last_seen_ms = health.since_last_seen().map(|d| d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64);
+
+        json!({
+            "id": id,
+            "address": client.address(),
+            "state": health.state(),
+            "missed_heartbeats": health.missed(),
+            "pending_hints": client.pending_hints(),
+            "last_seen_ms": last_seen_ms
+        })
+    }).collect::<Vec<_>>();
+
+    json!({ "nodes": nodes })
+}
+
+/// The health of the cluster, in the form of Elasticsearch's _cluster/health
+fn health_json(clients: &HashMap<u32, RPCClient>, rebalancing: bool) -> Value {
+    let state = |b: u32| clients.get(&b).map(|c| c.state()).unwrap_or(NodeState::Down);
+    let count = |s: NodeState| (0..clients.len() as u32).filter(|b| state(*b) == s).count();
+
+    json!({
+        "cluster_name": "logstore",
+        "status": cluster_status(clients.len() as u32, REPLICATION_FACTOR, &state),
+        "number_of_nodes": clients.len(),
+        "nodes_up": count(NodeState::Up),
+        "nodes_suspect": count(NodeState::Suspect),
+        "nodes_down": count(NodeState::Down),
+        "pending_hints": clients.values().map(|c| c.pending_hints() as u64).sum::<u64>(),
+        "rebalancing": rebalancing
+    })
+}
+
+/// Asks every node that isn't down for the state of its storage, in order of the nodes' ids
+/// A node that is down, or fails to answer, has None
+fn node_stats(clients: &HashMap<u32, RPCClient>) -> Box<Future<Item=Vec<(u32, Option<StorageStats>)>, Error=hyper::Error>> {
+    let mut ids = clients.keys().cloned().collect::<Vec<_>>();
+
+    ids.sort();
+
+    let stats_futures = ids.into_iter().map(|id| -> Box<Future<Item=(u32, Option<StorageStats>), Error=hyper::Error>> {
+        let client = &clients[&id];
+
+        if client.state() == NodeState::Down {
+            return Box::new(future::ok( (id, None) ));
+        }
+
+        let address = client.address().to_owned();
+
+        Box::new(client.stats().then(move |res| {
+            match res {
+                Ok(stats) => Ok::<_, hyper::Error>( (id, Some(stats)) ),
+                Err(e) => {
+                    warn!("Unable to get stats from {}: {}", address, e);
+                    Ok( (id, None) )
+                }
+            }
+        }))
+    }).collect::<Vec<_>>();
+
+    Box::new(future::join_all(stats_futures))
+}
+
+/// Sends a mapping update to every node, so they all store and index new logs the same way
+/// It's only acknowledged once every node has applied it, a node that was down must be sent it again
+fn put_mapping(clients: &HashMap<u32, RPCClient>, update: MappingUpdate) -> Box<Future<Item=Response<ResponseStream>, Error=hyper::Error>> {
+    let mut ids = clients.keys().cloned().collect::<Vec<_>>();
+
+    ids.sort();
+
+    let put_futures = ids.into_iter().map(|id| -> Box<Future<Item=Result<(), IOError>, Error=hyper::Error>> {
+        let client = &clients[&id];
+        let address = client.address().to_owned();
+
+        if client.state() == NodeState::Down {
+            warn!("Unable to send the mapping to {}: node is down", address);
+            return Box::new(future::ok(Err(IOError::new(ErrorKind::NotConnected, format!("{} is down", address)))));
+        }
+
+        Box::new(client.put_mapping(update.clone()).then(move |res| {
+            if let Err(ref e) = res {
+                warn!("Unable to send the mapping to {}: {}", address, e);
+            }
+
+            Ok::<_, hyper::Error>(res.map(|_| ()))
+        }))
+    }).collect::<Vec<_>>();
+
+    Box::new(future::join_all(put_futures).map(|results| {
+        // a field can't be changed, so a node refusing the update means the request was invalid
+        if let Some(e) = results.iter().filter_map(|r| r.as_ref().err()).find(|e| e.kind() == ErrorKind::InvalidInput) {
+            return json_response(StatusCode::BadRequest, json!({ "error": e.to_string() }));
+        }
+
+        let failed = results.iter().filter(|r| r.is_err()).count();
+
+        json_response(StatusCode::Ok, json!({ "acknowledged": failed == 0, "failed_nodes": failed }))
+    }))
+}
+
+/// Every node, like Elasticsearch's _cat/nodes
+fn cat_nodes(clients: &HashMap<u32, RPCClient>, stats: Vec<(u32, Option<StorageStats>)>, query: Option<&str>) -> Response<ResponseStream> {
+    let columns = ["id", "address", "state", "logs", "disk.bytes", "indices", "mem.postings", "pending_hints"];
+
+    let rows = stats.into_iter().map(|(id, stats)| {
+        let client = &clients[&id];
+
+        let (logs, bytes, indices, postings) = match stats {
+            Some(s) => (s.logs.to_string(), disk_bytes(&s).to_string(), s.indices.len().to_string(), s.mem_postings.to_string()),
+            None => (String::from("-"), String::from("-"), String::from("-"), String::from("-"))
+        };
+
+        vec![id.to_string(), client.address().to_owned(), format!("{:?}", client.state()).to_lowercase(), logs, bytes, indices, postings, client.pending_hints().to_string()]
+    }).collect();
+
+    cat_response(query, &columns, rows)
+}
+
+/// Every logical index, like Elasticsearch's _cat/indices
+/// Each log is held by several nodes, so the count is the copies found divided by the number of copies kept of each log
+fn cat_indices(clients: &HashMap<u32, RPCClient>, stats: Vec<(u32, Option<StorageStats>)>, query: Option<&str>) -> Response<ResponseStream> {
+    let num_nodes = clients.len() as u32;
+    let copies = (if REPLICATION_FACTOR < num_nodes { REPLICATION_FACTOR } else { num_nodes }).max(1) as usize;
+
+    let health = match cluster_status(num_nodes, REPLICATION_FACTOR, |b| clients.get(&b).map(|c| c.state()).unwrap_or(NodeState::Down)) {
+        ClusterStatus::Green => "green",
+        ClusterStatus::Yellow => "yellow",
+        ClusterStatus::Red => "red"
+    };
+
+    let mut docs = HashMap::<String, usize>::new();
+
+    for stats in stats.into_iter().filter_map(|(_, s)| s) {
+        for (index, count) in stats.logical_indices {
+            *docs.entry(index).or_insert(0) += count;
+        }
+    }
+
+    let mut indices = docs.into_iter().collect::<Vec<_>>();
+
+    indices.sort();
+
+    let columns = ["health", "status", "index", "rep", "docs.count"];
+
+    let rows = indices.into_iter().map(|(index, count)| {
+        vec![health.to_owned(), String::from("open"), index, (copies - 1).to_string(), (count / copies).to_string()]
+    }).collect();
+
+    cat_response(query, &columns, rows)
+}
+
+/// The state of every node's files: the logical indices, each field index, and the flushes
+/// Returned as JSON, or as a table of files with format=text
+fn stats_response(clients: &HashMap<u32, RPCClient>, stats: Vec<(u32, Option<StorageStats>)>, query: Option<&str>) -> Response<ResponseStream> {
+    if query_param(query, "format") == Some("text") {
+        let columns = ["node", "file", "terms", "mem.postings", "flushing", "last_flush_ms", "bytes"];
+        let mut rows = Vec::new();
+
+        for (id, stats) in stats.into_iter() {
+            let stats = match stats {
+                Some(s) => s,
+                None => continue
+            };
+
+            rows.push(vec![id.to_string(), String::from("logs.data"), String::from("-"), String::from("-"), String::from("-"), String::from("-"), stats.log_file_size.to_string()]);
+
+            for index in stats.indices {
+                rows.push(vec![
+                    id.to_string(),
+                    index.field + ".index",
+                    index.terms.to_string(),
+                    index.mem_postings.to_string(),
+                    index.flushing.to_string(),
+                    index.last_flush_ms.map(|t| t.to_string()).unwrap_or_else(|| String::from("-")),
+                    index.size.to_string()
+                ]);
+            }
+        }
+
+        return cat_response(Some("v"), &columns, rows);
+    }
+
+    let nodes = stats.into_iter().map(|(id, stats)| {
+        let client = &clients[&id];
+
+        json!({
+            "id": id,
+            "address": client.address(),
+            "state": client.state(),
+            "disk_bytes": stats.as_ref().map(disk_bytes),
+            "stats": stats
+        })
+    }).collect::<Vec<_>>();
+
+    json_response(StatusCode::Ok, json!({ "nodes": nodes }))
+}
+
+/// The bytes used by a node's log and index files
+fn disk_bytes(stats: &StorageStats) -> u64 {
+    stats.log_file_size + stats.indices.iter().map(|i| i.size).sum::<u64>()
+}
+
+/// Streams the results of a query from each node as newline delimited JSON, ending with a summary line
+/// Each page of logs is written out as it arrives, so only the __ids seen are held, to remove the duplicates from replicas
+/// The guard keeps the request in flight until the summary line is written
+fn stream_search(clients: &HashMap<u32, RPCClient>,
+                 buckets: &[u32],
+                 query: Query,
+                 metrics: Arc<Metrics>,
+                 guard: RequestGuard) -> Response<ResponseStream> {
+    let start = get_ts();
+    let timer = Instant::now();
+    let seen = Rc::new(RefCell::new(HashSet::<String>::new()));
+    let failed = Rc::new(Cell::new(0));
+
+    let node_streams = buckets.iter().filter_map(|b| clients.get(b)).map(|rpc_client| {
+        let failed = failed.clone();
+
+        // an error ends that node's stream, but not the response
+        rpc_client.stream_query(query.clone()).then(move |res| {
+            match res {
+                Ok((logs, _)) => Ok::<_, hyper::Error>(logs),
+                Err(e) => {
+                    warn!("Error streaming query from node: {}", e);
+                    failed.set(failed.get() + 1);
+                    Ok(Vec::new())
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    let seen_lines = seen.clone();
+
+    let lines = stream::iter_ok::<_, hyper::Error>(node_streams)
+        .flatten()
+        .map(move |logs| {
+            let mut buf = String::new();
+
+            for log in logs {
+                let id = match log.get("__id") {
+                    Some(&LogValue::String(ref id)) => id.to_owned(),
+                    _ => continue
+                };
+
+                if !seen_lines.borrow_mut().insert(id.clone()) {
+                    continue;
+                }
+
+                buf.push_str(&json!({ "_id": id, "_source": map2json(log) }).to_string());
+                buf.push('\n');
+            }
+
+            Chunk::from(buf)
+        })
+        .filter(|chunk| chunk.len() > 0); // an empty chunk would end the response
+
+    let summary = stream::once::<(), hyper::Error>(Ok(())).map(move |_| {
+        metrics.search_seconds.observe(timer.elapsed());
+
+        let summary = json!({ "summary": {
+            "total": seen.borrow().len(),
+            "failed_nodes": failed.get(),
+            "took": get_ts() - start
+        } });
+
+        Chunk::from(summary.to_string() + "\n")
+    });
+
+    let body: ResponseStream = Box::new(guard.hold(lines.chain(summary)));
+    let mut response = Response::new().with_status(StatusCode::Ok).with_body(body);
+
+    response.headers_mut().set_raw("Content-Type", "application/x-ndjson");
+
+    response
+}
+
+/// Stands in for every index, when a request doesn't name one
+const ALL_INDICES: &str = "*";
+
+/// Logs returned by a search without a size, as in Elasticsearch
+const DEFAULT_SEARCH_SIZE: usize = 10;
+
+/// The most logs a search returns in one response, larger results are streamed with format=ndjson
+const MAX_SEARCH_SIZE: usize = 10_000;
+
+/// The logical index a request's path names, if any
+fn path_index(path: &str) -> Option<String> {
+    let first = path.trim_left_matches('/').split('/').next()?;
+
+    if first.is_empty() || first.starts_with('_') || first == "admin" {
+        None
+    } else {
+        Some(first.to_owned())
+    }
+}
+
+/// The kind of operation a request performs, for authorization
+fn operation(method: &Method, path: &str) -> Operation {
+    match (method, path) {
+        (_, p) if p.starts_with("/admin") => Operation::Admin,
+        (_, "/metrics") => Operation::Admin,
+        (&Method::Put, p) | (&Method::Post, p) if p.ends_with("/_mapping") => Operation::Admin,
+        (&Method::Get, "/") => Operation::Info,
+        (&Method::Post, _) => Operation::Ingest,
+        (&Method::Get, _) => Operation::Read,
+        _ => Operation::Admin
+    }
+}
+
+fn auth_response(err: AuthError) -> Response<ResponseStream> {
+    match err {
+        AuthError::Forbidden => json_response(StatusCode::Forbidden, json!({ "error": "The API key is not allowed to perform this request" })),
+        AuthError::Remote => json_response(StatusCode::Forbidden, json!({ "error": "The first API key must be created from the node itself" })),
+        _ => {
+            let mut response = json_response(StatusCode::Unauthorized, json!({ "error": "A valid API key is required" }));
+
+            response.headers_mut().set_raw("WWW-Authenticate", "Basic realm=\"logstore\"");
+
+            response
+        }
+    }
+}
+
+/// Whether the request came from the node itself
+fn is_loopback(addr: Option<SocketAddr>) -> bool {
+    match addr.map(|a| a.ip()) {
+        Some(IpAddr::V4(ip)) => ip.is_loopback(),
+        Some(IpAddr::V6(ip)) => ip.is_loopback() || ip.to_ipv4().map(|ip| ip.is_loopback()).unwrap_or(false),
+        None => false
+    }
+}
+
+fn is_keys_path(path: &str) -> bool {
+    path == "/admin/keys" || path.starts_with("/admin/keys/")
+}
+
+/// Manages the API keys: GET lists them, POST creates one, DELETE /admin/keys/<id> removes one
+fn keys_response(keys: &RefCell<KeyStore>, method: &Method, path: &str, query: Option<&str>) -> Response<ResponseStream> {
+    let key_json = |k: &ApiKey| json!({ "id": k.id, "role": k.role, "indices": k.indices });
+
+    match *method {
+        Method::Get => json_response(StatusCode::Ok, json!({ "keys": keys.borrow().keys().iter().map(key_json).collect::<Vec<_>>() })),
+        Method::Post => {
+            let role = match query_param(query, "role").and_then(Role::parse) {
+                Some(r) => r,
+                None => return json_response(StatusCode::BadRequest, json!({ "error": "role must be one of ingest, read or admin" }))
+            };
+
+            let indices = query_param(query, "indices")
+                .map(|i| i.split(',').filter(|i| !i.is_empty()).map(String::from).collect::<Vec<_>>())
+                .unwrap_or_else(|| vec![String::from(ALL_INDICES)]);
+
+            match keys.borrow_mut().add(role, indices) {
+                Ok((key, secret)) => {
+                    info!("Created API key {} with role {:?}", key.id, key.role);
+
+                    let mut body = key_json(&key);
+
+                    body["api_key"] = json!(encode_key(&key, &secret));
+
+                    json_response(StatusCode::Created, body)
+                },
+                Err(e) => json_response(StatusCode::InternalServerError, json!({ "error": e.to_string() }))
+            }
+        },
+        Method::Delete => {
+            let id = path.trim_left_matches("/admin/keys").trim_matches('/');
+
+            match keys.borrow_mut().remove(id) {
+                Ok(true) => json_response(StatusCode::Ok, json!({ "deleted": id })),
+                Ok(false) => json_response(StatusCode::NotFound, json!({ "error": "No such key" })),
+                Err(e) => json_response(StatusCode::InternalServerError, json!({ "error": e.to_string() }))
+            }
+        },
+        _ => json_response(StatusCode::MethodNotAllowed, json!({ "error": "Method not allowed" }))
+    }
+}
+
+impl ElasticsearchService {
+    /// Authenticates the request and checks the key can perform it on the index in its path
+    /// Resolves to None when authentication is disabled
+    fn authorize(&self, req: &Request) -> Result<Option<ApiKey>, AuthError> {
+        let keys = self.keys.borrow();
+
+        if !keys.is_enabled() {
+            // otherwise anyone who can reach the endpoint could create the first admin key
+            if is_keys_path(req.path()) && !is_loopback(req.remote_addr()) {
+                warn!("Refused to manage API keys from {:?} before the first key exists", req.remote_addr());
+                return Err(AuthError::Remote);
+            }
+
+            return Ok(None);
+        }
+
+        let header = req.headers().get_raw("Authorization").and_then(|h| h.one()).and_then(|h| from_utf8(h).ok());
+        let key = keys.authenticate(header)?;
+        let op = operation(req.method(), req.path());
+
+        // the indices of a bulk request are checked once its body is read
+        let indices = match (op, path_index(req.path())) {
+            (Operation::Info, _) | (Operation::Admin, _) | (Operation::Ingest, None) => Vec::new(),
+            (_, Some(index)) => vec![index],
+            (_, None) => vec![String::from(ALL_INDICES)]
+        };
+
+        if key.authorize(op, &indices) {
+            Ok(Some(key.clone()))
+        } else {
+            warn!("API key {} refused {:?} of {:?}", key.id, op, indices);
+            Err(AuthError::Forbidden)
+        }
+    }
+}
+
+/// This node's metrics, and what it knows of its peers, in the Prometheus text format
+fn metrics_response(metrics: &Metrics, dm: &DataManager, clients: &HashMap<u32, RPCClient>) -> Response<ResponseStream> {
+    let mut out = Exposition::new();
+
+    dm.write_metrics(&mut out);
+
+    out.histogram("logstore_search_duration_seconds", "Time taken to answer a search across the cluster", &metrics.search_seconds);
+    out.histogram("logstore_rpc_query_duration_seconds", "Time taken to read a page of a query on this node", &metrics.rpc_query_seconds);
+
+    let mut errors = clients.values().map(|c| (c.address().to_owned(), c.health().errors() as usize)).collect::<Vec<_>>();
+
+    errors.sort();
+
+    out.labeled("logstore_rpc_errors_total", "Failed requests and heartbeats to each peer", "counter", "peer", &errors);
+    out.labeled("logstore_open_connections", "Connections open to each server", "gauge", "server", &[
+        (String::from("http"), metrics.http_connections.get()),
+        (String::from("rpc"), metrics.rpc_connections.get())
+    ]);
+
+    text_response(out.finish(), "text/plain; version=0.0.4")
+}
+
+/// Formats rows as text, in columns padded to the widest value, like Elasticsearch's _cat output
+/// The column names are only included with ?v, and format=json returns an object for each row instead
+fn cat_response(query: Option<&str>, columns: &[&str], rows: Vec<Vec<String>>) -> Response<ResponseStream> {
+    if query_param(query, "format") == Some("json") {
+        let objects = rows.into_iter().map(|row| {
+            Value::Object(columns.iter().map(|c| c.to_string()).zip(row.into_iter().map(Value::String)).collect())
+        }).collect::<Vec<_>>();
+
+        return json_response(StatusCode::Ok, Value::Array(objects));
+    }
+
+    let mut lines = Vec::with_capacity(rows.len() + 1);
+
+    if has_param(query, "v") {
+        lines.push(columns.iter().map(|c| c.to_string()).collect::<Vec<_>>());
+    }
+
+    lines.extend(rows);
+
+    let widths = (0..columns.len()).map(|i| lines.iter().map(|l| l[i].len()).max().unwrap_or(0)).collect::<Vec<_>>();
+    let mut text = String::new();
+
+    for line in lines {
+        let cells = line.iter().zip(widths.iter()).map(|(v, w)| format!("{:1$}", v, *w)).collect::<Vec<_>>();
+
+        text.push_str(cells.join(" ").trim_right());
+        text.push('\n');
+    }
+
+    text_response(text, "text/plain; charset=UTF-8")
+}
+
+fn text_response(text: String, content_type: &'static str) -> Response<ResponseStream> {
+    let len = text.len() as u64;
+    let body: ResponseStream = Box::new(Body::from(text));
+    let mut response = Response::new().with_status(StatusCode::Ok).with_header(ContentLength(len)).with_body(body);
+
+    response.headers_mut().set_raw("Content-Type", content_type);
+
+    response
+}
+
+fn json_response(status: StatusCode, value: Value) -> Response<ResponseStream> {
+    let body_str = value.to_string();
+    let len = body_str.len() as u64;
+    let body: ResponseStream = Box::new(Body::from(body_str));
+
+    Response::new()
+        .with_status(status)
+        .with_header(ContentLength(len))
+        .with_body(body)
 }
 
 impl Service for ElasticsearchService {
@@ -117,11 +778,57 @@ impl Service for ElasticsearchService {
     type Future = Box<future::Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Request) -> Self::Future {
-        let clients = self.0.clone();
+        // the request is in flight until its response is ready, a streamed body is counted until it ends
+        let guard = match self.drain.start() {
+            Some(g) => g,
+            None => return Box::new(futures::future::ok(json_response(StatusCode::ServiceUnavailable, json!({ "error": "Shutting down" }))))
+        };
+
+        Box::new(self.handle_request(req).then(move |res| {
+            drop(guard);
+            res
+        }))
+    }
+}
+
+impl ElasticsearchService {
+    fn handle_request(&self, req: Request) -> Box<future::Future<Item = Response<ResponseStream>, Error = hyper::Error>> {
+        let clients = self.clients.clone();
 
         info!("HTTP REQUEST: {} {}", req.method(), req.path());
 
+        let key = match self.authorize(&req) {
+            Ok(k) => k,
+            Err(e) => return Box::new(futures::future::ok(auth_response(e)))
+        };
+
         match (req.method(), req.path()) {
+            (_, path) if is_keys_path(path) => {
+                Box::new(futures::future::ok(keys_response(&self.keys, req.method(), path, req.query())))
+            }
+
+            (&Method::Put, path) | (&Method::Post, path) if path.ends_with("/_mapping") => {
+                Box::new(req.body().concat2().and_then(move |chunk| -> Box<Future<Item=Response<ResponseStream>, Error=hyper::Error>> {
+                    let update = from_slice::<Value>(&chunk)
+                        .map_err(|e| e.to_string())
+                        .and_then(|body| MappingUpdate::from_json(&body));
+
+                    match update {
+                        Ok(update) => put_mapping(&clients, update),
+                        Err(e) => Box::new(future::ok(json_response(StatusCode::BadRequest, json!({ "error": e }))))
+                    }
+                }))
+            }
+
+            (&Method::Get, path) if path.ends_with("/_mapping") => {
+                let mut body = Map::new();
+
+                // every logical index shares the mapping
+                body.insert(path_index(path).unwrap_or_else(|| String::from("_all")), json!({ "mappings": self.data_manager.mapping().to_json() }));
+
+                Box::new(futures::future::ok(json_response(StatusCode::Ok, Value::Object(body))))
+            }
+
             (&Method::Put, _) => {
                 Box::new(futures::future::ok(
                     Response::new()
@@ -131,33 +838,238 @@ impl Service for ElasticsearchService {
                 ))
             }
 
-            (&Method::Post, _) => {
-                let future_log_stream = req
+            (&Method::Post, "/admin/repair") => {
+                let end = get_ts();
+
+                Box::new(repair_all(clients, end - REPAIR_LOOKBACK_MS, end).then(|res| {
+                    match res {
+                        Ok(stats) => Ok::<_, hyper::Error>(json_response(StatusCode::Ok, json!(stats))),
+                        Err(e) => Ok(json_response(StatusCode::InternalServerError, json!({ "error": e.to_string() })))
+                    }
+                }))
+            }
+
+            (&Method::Post, "/admin/rebalance") => {
+                let old_buckets = match query_param(req.query(), "from").and_then(|f| f.parse::<u32>().ok()) {
+                    Some(b) => b,
+                    None => return Box::new(futures::future::ok(json_response(StatusCode::BadRequest, json!({ "error": "from must be the number of nodes before the change" }))))
+                };
+
+                // the nodes configured, unless the cluster is shrinking while the nodes leaving it are still configured
+                let new_buckets = match query_param(req.query(), "to").map(|t| t.parse::<u32>()) {
+                    None => clients.len() as u32,
+                    Some(Ok(b)) => b,
+                    Some(Err(_)) => return Box::new(futures::future::ok(json_response(StatusCode::BadRequest, json!({ "error": "to must be the number of nodes after the change" }))))
+                };
+
+                let res = start_rebalance(&self.handle, clients, self.rebalance.clone(), self.rebalance_path.clone(), old_buckets, new_buckets);
+
+                Box::new(futures::future::ok(match res {
+                    Ok(()) => json_response(StatusCode::Accepted, to_value(&*self.rebalance.borrow()).unwrap()),
+                    Err(e) => json_response(StatusCode::Conflict, json!({ "error": e }))
+                }))
+            }
+
+            (&Method::Get, "/admin/rebalance") => {
+                Box::new(futures::future::ok(json_response(StatusCode::Ok, to_value(&*self.rebalance.borrow()).unwrap())))
+            }
+
+            (&Method::Post, path) => {
+                let start = get_ts();
+                let index = path_index(path);
+                let log_index = index.clone();
+
+                let response = req
                     .body()
                     .concat2()
-                    .map(parse_logs);
+                    .map(move |chunk| parse_logs(chunk, log_index))
+                    .and_then(move |parsed| -> Box<Future<Item=Result<(Vec<String>, Vec<bool>), Response<ResponseStream>>, Error=hyper::Error>> {
+                        let (logs, mut indices) = match parsed {
+                            Ok(p) => p,
+                            Err(e) => return Box::new(future::ok(Err(json_response(StatusCode::BadRequest, json!({ "error": e })))))
+                        };
 
-                let response_stream =
-                    future_log_stream.map(move |req_stream| {
-                        req_stream.map(move |req| {
-                            stream::futures_unordered(
-                                clients.values().map(move |rpc_client| {
-                                    rpc_client.make_request(req.clone()).map_err(|e| hyper::error::Error::Io(e))
-                                })
-                            )
-                            }).flatten()
-                    }).flatten_stream();
+                        // a key scoped to indices must name them, in the path or the meta lines
+                        if let Some(ref key) = key {
+                            indices.extend(index);
 
-                let response =
-                    response_stream.fold(Chunk::default(), move |c, r| future::ok::<Chunk, hyper::Error>(c));
+                            if indices.is_empty() {
+                                indices.push(String::from(ALL_INDICES));
+                            }
 
-                let body: ResponseStream = Box::new(Body::from(BULK_RESPONSE));
+                            if !key.authorize(Operation::Ingest, &indices) {
+                                warn!("API key {} refused ingest into {:?}", key.id, indices);
+                                return Box::new(future::ok(Err(auth_response(AuthError::Forbidden))));
+                            }
+                        }
 
-                Box::new(response.map(|_| {
-                    Response::new()
-                        .with_status(StatusCode::Ok)
-                        .with_header(ContentLength(BULK_RESPONSE.len() as u64))
-                        .with_body(body)
+                        let ids = logs.iter().map(|log| {
+                            match log.get("__id") {
+                                Some(&LogValue::String(ref id)) => id.to_owned(),
+                                _ => String::new()
+                            }
+                        }).collect::<Vec<_>>();
+
+                        Box::new(route_bulk(&clients, logs).map(move |stored| Ok((ids, stored))))
+                    });
+
+                Box::new(response.map(move |res| {
+                    let (ids, stored) = match res {
+                        Ok(r) => r,
+                        Err(response) => return response
+                    };
+
+                    let failed = stored.iter().filter(|s| !**s).count();
+
+                    if failed > 0 {
+                        warn!("Bulk insert stored {} logs, failed {}", stored.len() - failed, failed);
+                    }
+
+                    json_response(StatusCode::Ok, bulk_json(ids, stored, get_ts() - start))
+                }))
+            }
+
+            (&Method::Get, "/admin/nodes") => {
+                Box::new(futures::future::ok(json_response(StatusCode::Ok, nodes_json(&clients))))
+            }
+
+            (&Method::Get, "/admin/stats") => {
+                let query = req.query().map(String::from);
+
+                Box::new(node_stats(&clients).map(move |stats| stats_response(&clients, stats, query.as_ref().map(|q| q.as_str()))))
+            }
+
+            (&Method::Get, "/_cluster/health") => {
+                let rebalancing = self.rebalance.borrow().as_ref().map(|p| p.running).unwrap_or(false);
+
+                Box::new(futures::future::ok(json_response(StatusCode::Ok, health_json(&clients, rebalancing))))
+            }
+
+            (&Method::Get, "/_cat/nodes") => {
+                let query = req.query().map(String::from);
+
+                Box::new(node_stats(&clients).map(move |stats| cat_nodes(&clients, stats, query.as_ref().map(|q| q.as_str()))))
+            }
+
+            (&Method::Get, "/_cat/indices") => {
+                let query = req.query().map(String::from);
+
+                Box::new(node_stats(&clients).map(move |stats| cat_indices(&clients, stats, query.as_ref().map(|q| q.as_str()))))
+            }
+
+            (&Method::Get, "/metrics") => {
+                Box::new(futures::future::ok(metrics_response(&self.metrics, &self.data_manager, &clients)))
+            }
+
+            (&Method::Get, path) if path.ends_with("/_search") => {
+                let start = Instant::now();
+                let query = match parse_query(req.query()) {
+                    Some(q) => q,
+                    None => {
+                        let body: ResponseStream = Box::new(Body::from(BADREQUEST));
+
+                        return Box::new(futures::future::ok(
+                            Response::new()
+                                .with_status(StatusCode::BadRequest)
+                                .with_header(ContentLength(BADREQUEST.len() as u64))
+                                .with_body(body),
+                        ));
+                    }
+                };
+
+                if let Err(e) = query.validate() {
+                    return Box::new(futures::future::ok(json_response(StatusCode::BadRequest, json!({ "error": e }))));
+                }
+
+                // a search of the index in the path only reads its logs, which is all a key scoped to it was authorized for
+                let query = match path_index(path) {
+                    Some(index) => query.in_index(&index),
+                    None => query
+                };
+
+                // only query live nodes, using replicas to cover the nodes that are down
+                let buckets = read_buckets(clients.len() as u32, REPLICATION_FACTOR, |b| {
+                    clients.get(&b).map(|c| c.state()).unwrap_or(NodeState::Down)
+                });
+
+                if query_param(req.query(), "format") == Some("ndjson") {
+                    // the head of the response is ready before its body, so the body holds its own guard
+                    let guard = self.drain.track();
+
+                    return Box::new(futures::future::ok(stream_search(&clients, &buckets, query, self.metrics.clone(), guard)));
+                }
+
+                // only `size` logs are read from each node, rather than every match
+                let size = match query_param(req.query(), "size").map(|s| s.parse::<usize>()) {
+                    None => DEFAULT_SEARCH_SIZE,
+                    Some(Ok(s)) if s <= MAX_SEARCH_SIZE => s,
+                    _ => return Box::new(futures::future::ok(json_response(StatusCode::BadRequest, json!({
+                        "error": format!("size must be a number of logs up to {}, use format=ndjson for more", MAX_SEARCH_SIZE)
+                    }))))
+                };
+
+                let response_futures = buckets.iter().filter_map(|b| clients.get(b)).map(|rpc_client| {
+                    let address = rpc_client.address().to_owned();
+
+                    rpc_client.query_first(query.clone(), size).then(move |res| {
+                        match res {
+                            Ok((logs, more)) => Ok::<_, hyper::Error>( (logs, more, None) ),
+                            Err(e) => {
+                                warn!("Error querying node {}: {}", address, e);
+                                Ok( (Vec::new(), false, Some(json!({ "node": address, "reason": e.to_string() }))) )
+                            }
+                        }
+                    })
+                }).collect::<Vec<_>>();
+
+                let total = response_futures.len();
+
+                // accumulate the results, removing the duplicates returned by replicas
+                let response = stream::futures_unordered(response_futures)
+                    .fold((Vec::new(), HashSet::<String>::new(), false, Vec::new()), move |(mut hits, mut seen, mut truncated, mut failures), (logs, more, failure)| {
+                        for log in logs {
+                            let id = match log.get("__id") {
+                                Some(&LogValue::String(ref id)) => id.to_owned(),
+                                _ => continue
+                            };
+
+                            if seen.contains(&id) {
+                                continue;
+                            }
+
+                            if hits.len() == size {
+                                truncated = true;
+                                break;
+                            }
+
+                            seen.insert(id.clone());
+                            hits.push(json!({ "_id": id, "_source": map2json(log) }));
+                        }
+
+                        failures.extend(failure);
+
+                        future::ok::<_, hyper::Error>( (hits, seen, truncated || more, failures) )
+                    });
+
+                let metrics = self.metrics.clone();
+
+                Box::new(response.map(move |(hits, _, truncated, failures)| {
+                    metrics.search_seconds.observe(start.elapsed());
+
+                    json_response(StatusCode::Ok, json!({
+                        "timed_out": false,
+                        "truncated": truncated,
+                        "_shards": {
+                            "total": total,
+                            "successful": total - failures.len(),
+                            "failed": failures.len(),
+                            "failures": failures
+                        },
+                        "hits": {
+                            "total": hits.len(),
+                            "hits": hits
+                        }
+                    }))
                 }))
             }
 
@@ -216,12 +1128,40 @@ impl Service for ElasticsearchService {
     }
 }
 
-pub fn configure_http_server(handle: &Handle, clients: HashMap<u32, RPCClient>) {
-    let addr = "127.0.0.1:9200".parse().unwrap();
-    let rc = Rc::new(clients);
+/// Returns the future that accepts HTTP connections, dropping it stops accepting them
+/// The requests in flight are tracked by drain
+pub fn configure_http_server(handle: &Handle,
+                             addr: &SocketAddr,
+                             clients: Rc<HashMap<u32, RPCClient>>,
+                             dir_path: &Path,
+                             drain: Drain,
+                             data_manager: Arc<DataManager>,
+                             metrics: Arc<Metrics>) -> Box<Future<Item=(), Error=()>> {
+    let rebalance: RebalanceState = Rc::new(RefCell::new(None));
+    let rebalance_path = dir_path.join("rebalance.json");
+    let service_handle = handle.clone();
+
+    let keys = match KeyStore::load(&dir_path.join("api_keys.json")) {
+        Ok(k) => Rc::new(RefCell::new(k)),
+        Err(e) => panic!("Unable to load API keys: {}", e)
+    };
+
+    if !keys.borrow().is_enabled() {
+        warn!("No API keys configured; the HTTP endpoint is open to anyone until one is created from this host");
+    }
 
     let serve = Http::new()
-        .serve_addr_handle(&addr, &handle, move || Ok(ElasticsearchService(rc.clone())))
+        .serve_addr_handle(addr, &handle, move || Ok(ElasticsearchService {
+            clients: clients.clone(),
+            handle: service_handle.clone(),
+            rebalance: rebalance.clone(),
+            rebalance_path: rebalance_path.clone(),
+            keys: keys.clone(),
+            drain: drain.clone(),
+            data_manager: data_manager.clone(),
+            _connection: Gauge::track(&metrics.http_connections),
+            metrics: metrics.clone()
+        }))
         .unwrap();
 
     println!(
@@ -231,7 +1171,7 @@ pub fn configure_http_server(handle: &Handle, clients: HashMap<u32, RPCClient>)
 
     let http_handle_2 = handle.clone();
 
-    handle.spawn(
+    Box::new(
         serve
             .for_each(move |conn| {
                 http_handle_2.spawn(
@@ -241,5 +1181,176 @@ pub fn configure_http_server(handle: &Handle, clients: HashMap<u32, RPCClient>)
                 Ok(())
             })
             .map_err(|_| ()),
-    );
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ::cluster::{placement_key, replica_buckets, NodeState, MAX_MISSED_HEARTBEATS, REPLICATION_FACTOR};
+    use ::data_manager::DataManager;
+    use ::http_server::{parse_logs, route_bulk, ALL_INDICES};
+    use ::json::{json2map, INDEX_FIELD};
+    use ::log_value::LogValue;
+    use ::metrics::Metrics;
+    use ::rpc_codec::DEFAULT_MAX_FRAME_SIZE;
+    use ::rpc_server::{run_rpc_server, RPCClient};
+
+    use futures::sync::oneshot;
+    use hyper::Chunk;
+    use tokio_core::reactor::Core;
+
+    use std::collections::HashMap;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    const TEST_DIR: &str = "/tmp/http_routing_test";
+
+    /// A node running in its own thread, with its own data directory
+    struct TestNode {
+        dm: Arc<DataManager>,
+        stop: oneshot::Sender<()>,
+        thread: JoinHandle<()>
+    }
+
+    fn start_node(port: u16) -> TestNode {
+        let dir = Path::new(TEST_DIR).join(port.to_string());
+
+        remove_dir_all(&dir).ok();
+        create_dir_all(&dir).unwrap();
+
+        let dm = Arc::new(DataManager::new(&dir).unwrap());
+        let server_dm = dm.clone();
+        let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let thread = thread::spawn(move || {
+            run_rpc_server(server_dm, addr, None, DEFAULT_MAX_FRAME_SIZE, Arc::new(Metrics::default()), stopped, Duration::from_secs(1)).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(300)); // let it start listening
+
+        TestNode { dm, stop, thread }
+    }
+
+    fn stop_node(node: TestNode) {
+        node.stop.send(()).ok();
+        node.thread.join().unwrap();
+    }
+
+    fn holds(node: &TestNode, id: &str) -> bool {
+        !node.dm.get("__id", &LogValue::String(id.to_owned())).unwrap().is_empty()
+    }
+
+    #[test]
+    fn bulk_indices() {
+        let body = [
+            r#"{"index":{"_index":"logs-a"}}"#,
+            r#"{"host":"web"}"#,
+            r#"{"index":{}}"#,
+            r#"{"host":"db","__index":"secret","__id":"chosen"}"#
+        ].join("\n");
+
+        let (logs, indices) = parse_logs(Chunk::from(body.into_bytes()), None).unwrap();
+
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].get(INDEX_FIELD), Some(&LogValue::String(String::from("logs-a"))));
+
+        // the second log can't pick its own index or id, and is stored without an index
+        assert_eq!(logs[1].get(INDEX_FIELD), None);
+        assert_ne!(logs[1].get("__id"), Some(&LogValue::String(String::from("chosen"))));
+        assert_eq!(indices, vec![String::from("logs-a"), String::from(ALL_INDICES)]);
+
+        // with an index in the path, every log has one
+        let body = [r#"{"index":{}}"#, r#"{"host":"web"}"#].join("\n");
+
+        let (logs, indices) = parse_logs(Chunk::from(body.into_bytes()), Some(String::from("logs-b"))).unwrap();
+
+        assert_eq!(logs[0].get(INDEX_FIELD), Some(&LogValue::String(String::from("logs-b"))));
+        assert!(indices.is_empty());
+    }
+
+    #[test]
+    fn heartbeats_and_routing() {
+        let ports = [24400, 24401, 24402];
+        let mut nodes = ports.iter().map(|p| start_node(*p)).collect::<Vec<_>>();
+        let mut core = Core::new().unwrap();
+        let hint_dir = Path::new(TEST_DIR).join("hints");
+
+        remove_dir_all(&hint_dir).ok();
+        create_dir_all(&hint_dir).unwrap();
+
+        let mut clients = HashMap::new();
+
+        for (id, port) in ports.iter().enumerate() {
+            let mut client = RPCClient::new(format!("127.0.0.1:{}", port), None, DEFAULT_MAX_FRAME_SIZE, &mut core);
+
+            client.enable_hints(&hint_dir, id as u32).unwrap();
+            clients.insert(id as u32, client);
+        }
+
+        core.run(clients[&2].heartbeat()).unwrap();
+
+        assert_eq!(clients[&2].state(), NodeState::Up);
+
+        // a node that stops answering is suspect after missing a heartbeat, and down after missing MAX_MISSED_HEARTBEATS
+        stop_node(nodes.pop().unwrap());
+
+        core.run(clients[&2].heartbeat()).unwrap();
+
+        assert_eq!(clients[&2].state(), NodeState::Suspect);
+
+        for _ in 1..MAX_MISSED_HEARTBEATS {
+            core.run(clients[&2].heartbeat()).unwrap();
+        }
+
+        assert_eq!(clients[&2].state(), NodeState::Down);
+
+        // writes go to the live replicas, and a hint is stored for the node that is down
+        let logs = (0..30).map(|i| json2map(&json!({ "n": i }).to_string()).unwrap()).collect::<Vec<_>>();
+
+        let ids = logs.iter().map(|log| {
+            match log.get("__id") {
+                Some(&LogValue::String(ref id)) => id.to_owned(),
+                _ => panic!("Log without an __id")
+            }
+        }).collect::<Vec<_>>();
+
+        assert!(core.run(route_bulk(&clients, logs)).unwrap().iter().all(|stored| *stored));
+
+        let mut hinted = Vec::new();
+
+        for id in &ids {
+            let owners = replica_buckets(placement_key(id), ports.len() as u32, REPLICATION_FACTOR);
+
+            for owner in owners.iter().filter(|o| **o != 2) {
+                assert!(holds(&nodes[*owner as usize], id), "{} is not on node {}", id, owner);
+            }
+
+            if owners.contains(&2) {
+                hinted.push(id);
+            }
+        }
+
+        assert!(!hinted.is_empty());
+        assert_eq!(clients[&2].pending_hints(), hinted.len() as u32);
+
+        // once the node is back, the next heartbeat reconnects, and the hints are replayed to it
+        nodes.push(start_node(ports[2]));
+
+        core.run(clients[&2].heartbeat()).unwrap();
+
+        assert_eq!(clients[&2].state(), NodeState::Up);
+
+        core.run(clients[&2].replay_hints()).unwrap();
+
+        assert_eq!(clients[&2].pending_hints(), 0);
+        assert!(hinted.iter().all(|id| holds(&nodes[2], id)));
+
+        for node in nodes {
+            stop_node(node);
+        }
+    }
 }
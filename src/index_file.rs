@@ -9,15 +9,19 @@ use rmps::decode::{from_slice, from_read};
 //use self::byteorder::{LE, ReadBytesExt, WriteBytesExt};
 use self::multimap::MultiMap;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::fs::{remove_file, rename};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::io::{Write, Seek, SeekFrom};
+use std::sync::Arc;
 use itertools::Itertools;
 use itertools::EitherOrBoth::{Left, Right, Both};
 
+use ::json::get_ts;
 use ::log_value::LogValue;
-use ::record_file::{RecordFile, buf2string};
+use ::record_file::RecordFile;
 use ::record_error::RecordError;
 
 //const FILE_HEADER: &[u8; 12] = b"LOGINDEX\x01\x00\x00\x00";
@@ -35,13 +39,85 @@ const FILE_HEADER: &[u8; 12] = b"LOGINDEX\x01XXX";
 /// |----------------------------------------|
 
 pub struct IndexFile {
-    rec_file: RecordFile,               // the record file holding the term -> Vec<offsets in file>
+    rec_file: Arc<RecordFile>,          // the record file holding the term -> Vec<offsets in file>, shared with a flush
     mem_index: MultiMap<LogValue, u64>, // not-yet-persisted index entries
-    term_map: HashMap<LogValue, u64>,   // term to location in index file
+    mem_postings: usize,                // number of locations in mem_index
+    flushing: Option<Arc<MultiMap<LogValue, u64>>>, // entries being written by a flush, searched until it finishes
+    term_map: BTreeMap<LogValue, u64>,  // term to location in index file, sorted
+    last_flush: Option<u64>,            // when the index was last flushed, in ms since the epoch
     dir_path: PathBuf,
     index_name: String
 }
 
+/// The in-memory entries of an index being merged with its file into a new file
+/// The new file is written without holding the index, so entries can still be added and searched
+pub struct IndexFlush {
+    rec_file: Arc<RecordFile>,            // the current file
+    records: Vec<u64>,                    // location of every term's record in the current file
+    entries: Arc<MultiMap<LogValue, u64>>,
+    postings: usize,                      // number of locations in entries
+    tmp_path: PathBuf
+}
+
+impl IndexFlush {
+    /// Writes every term in the current file, with the flushed locations merged in, to a temporary file
+    /// Returns the new file and where each term's record is in it
+    pub fn write(&self) -> Result<(RecordFile, BTreeMap<LogValue, u64>), RecordError> {
+        // a flush that failed part way may have left its file behind
+        if self.tmp_path.exists() {
+            remove_file(&self.tmp_path)?;
+        }
+
+        let mut tmp_rec_file = RecordFile::new(&self.tmp_path, FILE_HEADER).map_err(|e| {
+            error!("Could not create temporary index file: {}", e.to_string());
+            RecordError::from(e)
+        })?;
+
+        let mut term_map = BTreeMap::new();
+
+        for &rec_loc in &self.records {
+            // get our term and our locations from the file
+            let (term, mut locs): (LogValue, Vec<u64>) = from_slice(&self.rec_file.read_at(rec_loc)?)?;
+
+            // insert each location in the flushed entries into the sorted list, if it's not already there
+            if let Some(mem_locs) = self.entries.get_vec(&term) {
+                for mem_loc in mem_locs {
+                    if let Err(loc) = locs.binary_search(mem_loc) {
+                        locs.insert(loc, *mem_loc);
+                    }
+                }
+            }
+
+            let loc = tmp_rec_file.append(&to_vec(&(&term, locs))?)?; // add the (term, locs) to our RecordFile
+            term_map.insert(term, loc); // add the record location to our term map
+        }
+
+        // now write the terms that were only in memory
+        for (term, locs) in self.entries.iter_all() {
+            if term_map.contains_key(term) {
+                continue;
+            }
+
+            // concurrent inserts can add locations out of order, but they're merged as a sorted list
+            let mut locs = locs.clone();
+
+            locs.sort_unstable();
+
+            debug!("Inserting term record into new file & term_map: {}", term);
+
+            let loc = tmp_rec_file.append(&to_vec(&(term, locs))?)?;
+            term_map.insert(term.clone(), loc);
+        }
+
+        // the new file replaces the current one, so it must be complete on disk in case the node crashes before closing it
+        tmp_rec_file.fd.seek(SeekFrom::Start(tmp_rec_file.end_of_file))?;
+        tmp_rec_file.fd.write_all(&to_vec(&term_map)?)?;
+        tmp_rec_file.sync()?;
+
+        Ok( (tmp_rec_file, term_map) )
+    }
+}
+
 impl IndexFile  {
     pub fn new(dir_path: &Path, index_name: &str) -> Result<IndexFile, RecordError> {
         let file_path = dir_path.join(index_name.to_owned() + ".index");
@@ -50,7 +126,7 @@ impl IndexFile  {
         // the end of the record file, is where the serialized term map begins
         let eof = rec_file.fd.seek(SeekFrom::End(0))?;
         let begin = rec_file.fd.seek(SeekFrom::Start(rec_file.end_of_file))?;
-        let mut term_map = HashMap::new();
+        let mut term_map = BTreeMap::new();
 
         if eof-begin > 0 {
             term_map = from_read(&rec_file.fd)?;
@@ -60,9 +136,12 @@ impl IndexFile  {
         // TODO: Run a check on this file
 
         Ok(IndexFile {
-            rec_file,
+            rec_file: Arc::new(rec_file),
             mem_index: MultiMap::new(),
+            mem_postings: 0,
+            flushing: None,
             term_map,
+            last_flush: None,
             dir_path: PathBuf::from(dir_path),
             index_name: String::from(index_name)
         })
@@ -70,17 +149,53 @@ impl IndexFile  {
 
     pub fn add(&mut self, value: LogValue, offset: u64) {
         // simply add to the in-memory index
-        // it's flushed to disk on close
+        // it's flushed to disk by a flush, or on close
         self.mem_index.insert(value, offset);
+        self.mem_postings += 1;
+    }
+
+    /// The number of locations held in memory that have not started being flushed
+    pub fn mem_postings(&self) -> usize {
+        self.mem_postings
+    }
+
+    pub fn is_flushing(&self) -> bool {
+        self.flushing.is_some()
+    }
+
+    pub fn last_flush(&self) -> Option<u64> {
+        self.last_flush
+    }
+
+    /// The size of the index file on disk
+    pub fn file_size(&self) -> u64 {
+        self.rec_file.fd.metadata().map(|m| m.len()).unwrap_or(0)
+    }
+
+    /// The number of distinct terms, both on disk and in memory
+    pub fn term_count(&self) -> usize {
+        let in_memory = self.mem_index.iter_all().filter(|&(t, _)| !self.term_map.contains_key(t)).count();
+
+        let flushing = match self.flushing {
+            Some(ref f) => f.iter_all().filter(|&(t, _)| !self.term_map.contains_key(t) && !self.mem_index.contains_key(t)).count(),
+            None => 0
+        };
+
+        self.term_map.len() + in_memory + flushing
     }
 
     #[allow(resolve_trait_on_defaulted_unit)]
-    pub fn get(&mut self, value: &LogValue) -> Result<Vec<u64>, RecordError> {
+    pub fn get(&self, value: &LogValue) -> Result<Vec<u64>, RecordError> {
         let mut in_memory = match self.mem_index.get_vec(value) {
             Some(v) => v.clone(),
             None => Vec::<u64>::new()
         };
 
+        // entries being flushed aren't in the file until the flush finishes
+        if let Some(v) = self.flushing.as_ref().and_then(|f| f.get_vec(value)) {
+            in_memory.extend_from_slice(v);
+        }
+
         // sort the vector
         in_memory.sort_unstable();
 
@@ -106,87 +221,124 @@ impl IndexFile  {
         }).collect::<Vec<_>>())
     }
 
-    /// Flushes the in-memory index to disk
-    pub fn flush(&mut self) -> Result<(), RecordError> {
-        if self.mem_index.len() == 0 {
-            return Ok( () );
+    /// Returns all of the terms in the index, both on disk and in memory
+    pub fn terms(&self) -> Vec<LogValue> {
+        let mut ret = self.term_map.keys().cloned().collect::<Vec<_>>();
+
+        for (term, _) in self.mem_index.iter_all() {
+            if !self.term_map.contains_key(term) {
+                ret.push(term.clone());
+            }
         }
 
-        // create our new temporary RecordFile
-        let tmp_file_path = self.dir_path.join(self.index_name.to_owned() + ".tmp_index");
+        if let Some(ref flushing) = self.flushing {
+            for (term, _) in flushing.iter_all() {
+                if !self.term_map.contains_key(term) && !self.mem_index.contains_key(term) {
+                    ret.push(term.clone());
+                }
+            }
+        }
 
-        let tmp_rec_file_res = RecordFile::new(&tmp_file_path, FILE_HEADER);
+        ret
+    }
 
-        if let Err(e) = tmp_rec_file_res {
-            error!("Could not create temporary index file: {}", e.to_string());
-            return Err(RecordError::from(e));
-        }
+    /// Returns up to `count` of the string terms on disk starting with the prefix, in order, continuing after `after` if it's given
+    /// The terms on disk are sorted, so only those from the prefix on are read
+    pub fn disk_terms(&self, prefix: &str, after: Option<&str>, count: usize) -> Vec<String> {
+        let start = match after {
+            Some(a) => Excluded(LogValue::String(a.to_owned())),
+            None => Included(LogValue::String(prefix.to_owned()))
+        };
 
-        let mut tmp_rec_file = tmp_rec_file_res?;
+        let mut ret = Vec::new();
 
-        // we'd need some sort of lock around this
-        // LOCK: START
-        self.term_map.clear(); // kill this as we re-populate it below
+        for term in self.term_map.range((start, Unbounded)).map(|(t, _)| t).take(count) {
+            match *term {
+                LogValue::String(ref s) if s.starts_with(prefix) => ret.push(s.to_owned()),
+                _ => break
+            }
+        }
 
-        for rec in (&mut self.rec_file).into_iter() {
-            debug!("Attempting to deserialized: {}", buf2string(&rec));
+        ret
+    }
 
-            // get our term and our locations from the file
-            let (term, mut locs): (LogValue, Vec<u64>) = from_slice(&rec)?;
+    /// Returns up to `count` of the string terms in memory starting with the prefix, in no particular order
+    pub fn mem_terms(&self, prefix: &str, count: usize) -> BTreeSet<String> {
+        let in_memory = self.mem_index.iter_all().chain(self.flushing.iter().flat_map(|f| f.iter_all()));
+
+        in_memory
+            .filter_map(|(t, _)| match *t {
+                LogValue::String(ref s) if s.starts_with(prefix) => Some(s.to_owned()),
+                _ => None
+            })
+            .take(count)
+            .collect()
+    }
 
-            debug!("Read term from disk: {}", term);
+    /// Flushes the in-memory index to disk
+    pub fn flush(&mut self) -> Result<(), RecordError> {
+        match self.start_flush() {
+            Some(flush) => {
+                let written = flush.write();
 
-            if self.mem_index.contains_key(&term) {
-                // go through each location in the in-memory index, and insert it into
-                // the location we're going to write to disk if it's not already found
-                for mem_loc in self.mem_index.get_vec(&term).unwrap() {
-                    debug!("\tFound term in mem_index");
+                self.finish_flush(flush, written).map(|_| ())
+            },
+            None => Ok( () )
+        }
+    }
 
-                    // didn't find this location in the list of locations
-                    if let Err(loc) = locs.binary_search(mem_loc) {
-                        debug!("\tDidn't find location, so inserting");
-                        locs.insert(loc, *mem_loc);
-                    }
-                }
+    /// Moves the in-memory entries into a flush, which is written with `IndexFlush::write`
+    /// Returns None if there's nothing to flush, or a flush is already running
+    pub fn start_flush(&mut self) -> Option<IndexFlush> {
+        if self.flushing.is_some() || self.mem_postings == 0 {
+            return None;
+        }
 
-                debug!("\tRemoving term from mem_index: {}", term);
-                self.mem_index.remove(&term); // remove it from the map
-            }
+        let entries = Arc::new(mem::replace(&mut self.mem_index, MultiMap::new()));
 
-            let rec = (&term, locs);
-            let mut buf = to_vec(&rec)?;
+        self.flushing = Some(entries.clone());
 
-            debug!("\tInserting term record into new file & term_map: {}", term);
+        Some(IndexFlush {
+            rec_file: self.rec_file.clone(),
+            records: self.term_map.values().cloned().collect(),
+            entries,
+            postings: mem::replace(&mut self.mem_postings, 0),
+            tmp_path: self.dir_path.join(self.index_name.to_owned() + ".tmp_index")
+        })
+    }
 
-            let loc = tmp_rec_file.append(&buf)?; // add the (term, locs) to our RecordFile
-            self.term_map.insert(term.clone(), loc); // add the record location to our term map
-        }
+    /// Switches to the file written by the flush, returning the number of locations flushed
+    /// If writing failed, the entries are kept in memory for the next flush
+    pub fn finish_flush(&mut self, flush: IndexFlush, written: Result<(RecordFile, BTreeMap<LogValue, u64>), RecordError>) -> Result<usize, RecordError> {
+        self.flushing = None;
 
-        // now we need to go through whatever is left in the in-memory map
-        for (term, locs) in self.mem_index.iter_all() {
-            let rec = (term, locs);
-            let mut buf = to_vec(&rec)?;
+        let file_path = self.rec_file.file_path.clone();
 
-            debug!("Inserting term record into new file & term_map: {}", term);
-            debug!("Writing rec to file: {}", buf2string(&buf));
+        let (mut rec_file, term_map) = match written.and_then(|w| { rename(&flush.tmp_path, &file_path)?; Ok(w) }) {
+            Ok(w) => w,
+            Err(e) => {
+                error!("Unable to flush index {}: {}", self.index_name, e.to_string());
 
-            let loc = tmp_rec_file.append(&buf)?; // add the (term, locs) to our RecordFile
-            self.term_map.insert(term.clone(), loc); // add the record location to our term map
-            // don't both deleting from mem_index as we'll clear the whole thing below
-        }
+                for (term, locs) in flush.entries.iter_all() {
+                    for loc in locs {
+                        self.mem_index.insert(term.clone(), *loc);
+                    }
+                }
 
-        self.mem_index.clear(); // everything should be written to disk at this point
+                self.mem_postings += flush.postings;
 
-        // Switch the two files
-        remove_file(&self.rec_file.file_path).unwrap();
-        rename(tmp_file_path, &self.rec_file.file_path).unwrap();
+                return Err(e);
+            }
+        };
 
-        tmp_rec_file.file_path = self.rec_file.file_path.clone(); // update the file name
-        self.rec_file = tmp_rec_file; // update the rec_file
+        rec_file.file_path = file_path; // update the file name
 
-        // LOCK: END
+        // the old file was replaced by the rename, and is closed once nothing is reading it
+        self.rec_file = Arc::new(rec_file);
+        self.term_map = term_map;
+        self.last_flush = Some(get_ts());
 
-        return Ok( () )
+        Ok(flush.postings)
     }
 
     pub fn close(&mut self) {
@@ -197,19 +349,27 @@ impl IndexFile  {
 
         let buff = to_vec(&self.term_map).unwrap();
 
-        if let Err(e) = self.rec_file.fd.seek(SeekFrom::Start(self.rec_file.end_of_file)) {
+        let rec_file = match Arc::get_mut(&mut self.rec_file) {
+            Some(f) => f,
+            None => {
+                error!("Unable to close index {} while it's being flushed", self.index_name);
+                return;
+            }
+        };
+
+        if let Err(e) = rec_file.fd.seek(SeekFrom::Start(rec_file.end_of_file)) {
             error!("Unable to seek to the end of the RecordFile: {}", e.to_string());
             return;
         }
 
-        if let Err(e) = self.rec_file.fd.write(&buff) {
+        if let Err(e) = rec_file.fd.write(&buff) {
             error!("Error writing serialized term map to file: {}", e.to_string());
         }
 
         info!("Closed index: {}", self.index_name);
 
         // close the underlying RecordFile
-        self.rec_file.close();
+        rec_file.close();
     }
 }
 
@@ -222,12 +382,20 @@ impl Drop for IndexFile {
 
         let buff = to_vec(&self.term_map).unwrap();
 
-        if let Err(e) = self.rec_file.fd.seek(SeekFrom::Start(self.rec_file.end_of_file)) {
+        let rec_file = match Arc::get_mut(&mut self.rec_file) {
+            Some(f) => f,
+            None => {
+                error!("Unable to close index {} while it's being flushed", self.index_name);
+                return;
+            }
+        };
+
+        if let Err(e) = rec_file.fd.seek(SeekFrom::Start(rec_file.end_of_file)) {
             error!("Unable to seek to the end of the RecordFile: {}", e.to_string());
             return;
         }
 
-        if let Err(e) = self.rec_file.fd.write(&buff) {
+        if let Err(e) = rec_file.fd.write(&buff) {
             error!("Error writing serialized term map to file: {}", e.to_string());
         }
 
@@ -270,21 +438,25 @@ impl Drop for IndexFile {
 mod tests {
     use ::index_file::IndexFile;
     use ::log_value::LogValue;
+    use ::utils::TestDir;
 
-    use std::path::Path;
+    use std::mem;
     use serde_json::Number;
     use simple_logger;
 
     #[test]
     fn new_file_no_slash() {
         simple_logger::init().unwrap();  // this will panic on error
-        IndexFile::new(Path::new("/tmp"), "id").unwrap();
+        let dir = TestDir::new("index_new_test");
+
+        IndexFile::new(&dir, "id").unwrap();
     }
 
     #[test]
     fn add_flush() {
         simple_logger::init().unwrap();  // this will panic on error
-        let mut index_file = IndexFile::new(Path::new("/tmp"), "id").unwrap();
+        let dir = TestDir::new("index_add_test");
+        let mut index_file = IndexFile::new(&dir, "id").unwrap();
 
         index_file.add(LogValue::Number(Number::from(7)), 24);
         index_file.add(LogValue::String(String::from("test")), 16);
@@ -294,7 +466,8 @@ mod tests {
     #[test]
     fn double_flush() {
         simple_logger::init().unwrap();  // this will panic on error
-        let mut index_file = IndexFile::new(Path::new("/tmp"), "id").unwrap();
+        let dir = TestDir::new("index_double_flush_test");
+        let mut index_file = IndexFile::new(&dir, "id").unwrap();
 
         index_file.add(LogValue::Number(Number::from(7)), 24);
 
@@ -307,10 +480,74 @@ mod tests {
         debug!("Second flush");
     }
 
+    #[test]
+    fn terms() {
+        simple_logger::init().unwrap();  // this will panic on error
+        let dir = TestDir::new("index_terms_test");
+        let mut index_file = IndexFile::new(&dir, "terms_test").unwrap();
+
+        index_file.add(LogValue::String(String::from("test")), 16);
+        index_file.add(LogValue::String(String::from("test")), 24);
+
+        assert!(index_file.terms().contains(&LogValue::String(String::from("test"))));
+    }
+
+    #[test]
+    fn matching_terms() {
+        let dir = TestDir::new("index_matching_terms_test");
+        let mut index_file = IndexFile::new(&dir, "matching_terms_test").unwrap();
+        let term = |t: &str| LogValue::String(String::from(t));
+
+        // terms of other types sort around the strings on disk
+        index_file.add(LogValue::Number(Number::from(7)), 8);
+        index_file.add(term("api-1"), 16);
+        index_file.add(term("web-1"), 24);
+        index_file.add(LogValue::Array(vec![term("web-2")]), 32);
+        index_file.flush().unwrap();
+
+        index_file.add(term("web-3"), 40);
+        index_file.add(term("worker-1"), 48);
+
+        assert_eq!(index_file.disk_terms("web-", None, 10), vec!["web-1"]);
+        assert_eq!(index_file.disk_terms("", None, 10), vec!["api-1", "web-1"]);
+        assert_eq!(index_file.disk_terms("", Some("api-1"), 10), vec!["web-1"]);
+        assert_eq!(index_file.disk_terms("", None, 1), vec!["api-1"]);
+
+        assert_eq!(index_file.mem_terms("web-", 10).into_iter().collect::<Vec<_>>(), vec!["web-3"]);
+        assert_eq!(index_file.mem_terms("", 10).len(), 2);
+        assert_eq!(index_file.mem_terms("", 1).len(), 1);
+    }
+
+    #[test]
+    fn reopen_after_flush() {
+        let dir = TestDir::new("index_reopen_test");
+        let term = |t: &str| LogValue::String(String::from(t));
+        let mut index_file = IndexFile::new(&dir, "host").unwrap();
+
+        index_file.add(term("web-1"), 16);
+        index_file.flush().unwrap();
+
+        index_file.add(term("web-1"), 24);
+        index_file.add(term("web-2"), 32);
+        index_file.flush().unwrap();
+
+        index_file.add(term("web-3"), 40);
+
+        // a crash, so the index is never closed and the entries in memory are lost
+        mem::forget(index_file);
+
+        let index_file = IndexFile::new(&dir, "host").unwrap();
+
+        assert_eq!(index_file.get(&term("web-1")).unwrap(), vec![16, 24]);
+        assert_eq!(index_file.get(&term("web-2")).unwrap(), vec![32]);
+        assert!(index_file.get(&term("web-3")).unwrap().is_empty());
+    }
+
     #[test]
     fn get() {
         simple_logger::init().unwrap();  // this will panic on error
-        let mut index_file = IndexFile::new(Path::new("/tmp"), "test").unwrap();
+        let dir = TestDir::new("index_get_test");
+        let mut index_file = IndexFile::new(&dir, "test").unwrap();
 
         index_file.add(LogValue::String(String::from("test")), 16);
 
@@ -18,12 +18,15 @@ use std::hash::Hasher;
 
 use ::log_value::LogValue;
 
+/// The field holding the logical index a log was sent to, as named by a _bulk request
+pub const INDEX_FIELD: &str = "__index";
+
 // this is kinda clunky :-\
 fn make_json_error(msg: &str) -> JsonError {
     return JsonError::syntax(ErrorCode::Message(String::from(msg).into_boxed_str()), 0, 0);
 }
 
-fn get_ts() -> u64 {
+pub fn get_ts() -> u64 {
     let ts = time::get_time();
 
     return (ts.sec as u64 * 1000) + (ts.nsec as u64 / 1000000);
@@ -54,10 +57,24 @@ pub fn json2map(log: &str) -> Result<HashMap<String, LogValue>, Box<Error>> {
     return value2map(&json_map, false);
 }
 
+/// Converts a JSON object into a log, rejecting the fields and values that cannot be stored
+pub fn object2map(json_map: &Map<String, Value>) -> Result<HashMap<String, LogValue>, Box<Error>> {
+    return value2map(json_map, false);
+}
+
 pub fn value2logvalue(value_map: &Map<String, Value>) -> HashMap<String, LogValue> {
     return value2map(value_map, true).unwrap();
 }
 
+/// Whether the value is, or an array holding at any depth, a JSON object
+pub fn has_object(value: &Value) -> bool {
+    match *value {
+        Value::Object(_) => true,
+        Value::Array(ref v) => v.iter().any(has_object),
+        _ => false
+    }
+}
+
 fn value2map(json_map: &Map<String, Value>, skip_invalid: bool) -> Result<HashMap<String, LogValue>, Box<Error>> {
     let mut log_map = HashMap::<String, LogValue>::new();
 
@@ -88,24 +105,19 @@ fn value2map(json_map: &Map<String, Value>, skip_invalid: bool) -> Result<HashMa
             }
         }
 
-        // convert to a LogValue or return an error if nested objects found
-        let log_value = if let Value::Array(ref v) = *value {
-            if v.iter().any(|i| i.is_object()) {
+        // nor objects in arrays, at any depth
+        if let Value::Array(ref v) = *value {
+            if v.iter().any(has_object) {
                 if skip_invalid {
                     continue;
                 } else {
                     return Err(Box::new(make_json_error("JSON Objects in arrays not allowed")));
                 }
             }
+        }
 
-            let mut log_value_array = v.iter().map(|x| LogValue::from(x)).collect::<Vec<_>>();
-
-            log_value_array.sort_unstable(); // sort the array
-
-            LogValue::Array(log_value_array)
-        } else {
-            LogValue::from(value)
-        };
+        // arrays keep their order, each element is indexed on its own
+        let log_value = LogValue::from(value);
 
         // hash both
         hash.write(key.as_bytes());
@@ -0,0 +1,78 @@
+//! logstore: a log storage engine with an Elasticsearch compatible HTTP endpoint
+//!
+//! The storage engine (`DataManager` over `LogFile` and `IndexFile`) can be embedded on its own,
+//! `Client` and `BlockingClient` talk to a cluster over RPC, and the server binary is built
+//! from `rpc_server` and `http_server`.
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+extern crate base64;
+extern crate byteorder;
+extern crate bytes;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate hyper;
+extern crate itertools;
+extern crate lz4;
+extern crate positioned_io;
+extern crate rand;
+extern crate rayon;
+extern crate regex;
+extern crate rmp_serde as rmps;
+extern crate rustls;
+extern crate serde;
+extern crate sha2;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_proto;
+extern crate tokio_rustls;
+extern crate tokio_service;
+extern crate toml;
+extern crate twox_hash;
+extern crate unicode_normalization;
+extern crate webpki;
+
+// the storage engine
+pub mod log_value;
+pub mod record_error;
+pub mod record_file;
+pub mod log_file;
+pub mod index_file;
+pub mod data_manager;
+pub mod flusher;
+pub mod retention;
+pub mod json;
+pub mod analysis;
+pub mod mapping;
+pub mod pattern;
+pub mod query;
+
+// talking to other nodes
+pub mod rpc_codec;
+pub mod rpc_server;
+pub mod client;
+pub mod tls;
+pub mod cluster;
+pub mod repair;
+
+// the HTTP endpoint
+pub mod http_server;
+pub mod auth;
+
+pub mod config;
+pub mod metrics;
+pub mod shutdown;
+
+mod utils;
+mod hint_file;
+mod rebalance;
+
+pub use client::{BlockingClient, Client, ClientOptions};
+pub use data_manager::DataManager;
+pub use log_value::LogValue;
+pub use record_error::RecordError;
@@ -50,6 +50,26 @@ impl LogFile {
         Ok(count)
     }
 
+    /// Sets whether each write is synced to disk before it returns
+    pub fn set_sync(&mut self, sync: bool) {
+        self.rec_file.sync = sync;
+    }
+
+    /// Whether each write is synced to disk before it returns
+    pub fn is_synced(&self) -> bool {
+        self.rec_file.sync
+    }
+
+    /// The number of logs in the file
+    pub fn record_count(&self) -> u32 {
+        self.rec_file.record_count
+    }
+
+    /// The size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.rec_file.end_of_file
+    }
+
     /// Adds a log to the file, returning the location in the file
     pub fn add(&mut self, log: &HashMap<String, LogValue>) -> Result<u64, RecordError> {
         let buff = to_vec(log)?;
@@ -60,6 +80,27 @@ impl LogFile {
         return Ok(loc);
     }
 
+    /// Adds a batch of logs to the file with a single write, returning the location of each log
+    /// A log that cannot be serialized is not written, and its error is returned in its place
+    pub fn add_all(&mut self, logs: &[&HashMap<String, LogValue>]) -> Result<Vec<Result<u64, RecordError>>, RecordError> {
+        let buffs = logs.iter().map(|log| to_vec(log)).collect::<Vec<_>>();
+
+        let locs = {
+            let records = buffs.iter().filter_map(|b| b.as_ref().ok()).map(|b| b.as_slice()).collect::<Vec<_>>();
+
+            self.rec_file.append_all(&records)?
+        };
+
+        let mut locs = locs.into_iter();
+
+        Ok(buffs.into_iter().map(|b| {
+            match b {
+                Ok(_) => Ok(locs.next().unwrap()),
+                Err(e) => Err(RecordError::from(e))
+            }
+        }).collect())
+    }
+
     pub fn get(&self, location: u64) -> Result<HashMap<String, LogValue>, RecordError> {
         match from_slice::<HashMap<String, LogValue>>(self.rec_file.read_at(location)?.as_slice()) {
             Err(e) => Err(RecordError::from(e)),
@@ -67,7 +108,33 @@ impl LogFile {
         }
     }
 
-    /// Temporary until TcpServer can be shutdown
+    /// Reads up to `limit` logs, or `max_bytes` of logs, starting at `location`, returning the logs and the location of the next log
+    /// A location of 0 starts at the beginning of the file; a next location of None indicates the end of the file
+    pub fn scan(&self, location: u64, limit: usize, max_bytes: usize) -> Result<(Vec<HashMap<String, LogValue>>, Option<u64>), RecordError> {
+        let mut loc = if location == 0 { self.rec_file.first_record() } else { location };
+        let mut ret = Vec::new();
+        let mut bytes = 0;
+
+        while ret.len() < limit && loc < self.rec_file.end_of_file {
+            let rec = self.rec_file.read_at(loc)?;
+
+            // always return at least one log, so the scan makes progress
+            if !ret.is_empty() && bytes + rec.len() > max_bytes {
+                break;
+            }
+
+            loc += 4 + rec.len() as u64;
+            bytes += rec.len();
+
+            ret.push(from_slice::<HashMap<String, LogValue>>(&rec)?);
+        }
+
+        let next = if loc < self.rec_file.end_of_file { Some(loc) } else { None };
+
+        Ok( (ret, next) )
+    }
+
+    /// Writes the header, called once nothing else is writing
     pub fn close(&mut self) {
         self.rec_file.close();
     }
@@ -138,6 +205,26 @@ mod tests {
         assert_eq!(num_logs, log_file.check().unwrap());
     }
 
+    #[test]
+    fn scan() {
+        simple_logger::init().unwrap();  // this will panic on error
+        let mut log_file = LogFile::new(Path::new("/tmp")).unwrap();
+        let msg = json2map(&json!({ "a": "scan" }).to_string()).unwrap();
+
+        let loc = log_file.add(&msg).unwrap();
+        log_file.add(&msg).unwrap();
+
+        let (logs, next) = log_file.scan(loc, 1, usize::max_value()).unwrap();
+
+        assert_eq!(logs, vec![msg.clone()]);
+        assert!(next.is_some());
+
+        let (logs, next) = log_file.scan(next.unwrap(), 10, usize::max_value()).unwrap();
+
+        assert_eq!(logs, vec![msg]);
+        assert_eq!(next, None);
+    }
+
     #[test]
     fn add_valid_msg() {
         simple_logger::init().unwrap();  // this will panic on error
@@ -44,6 +44,16 @@ impl LogValue {
         }
     }
 
+    fn type_rank(&self) -> u8 {
+        match *self {
+            LogValue::Null => 0,
+            LogValue::Bool(_) => 1,
+            LogValue::Number(_) => 2,
+            LogValue::String(_) => 3,
+            LogValue::Array(_) => 4
+        }
+    }
+
     pub fn into_value(self) -> JsonValue {
         match self {
             LogValue::Null => JsonValue::Null,
@@ -146,11 +156,8 @@ impl Ord for LogValue {
             (&LogValue::Number(ref n1), &LogValue::Number(ref n2)) => n1.as_f64().unwrap().partial_cmp(&n2.as_f64().unwrap()).unwrap(),
             (&LogValue::String(ref s1), &LogValue::String(ref s2)) => s1.cmp(&s2),
             (&LogValue::Array(ref a1), &LogValue::Array(ref a2)) => a1.cmp(&a2),
-            (&LogValue::Null, _) => Ordering::Less,
-            (&LogValue::Bool(_), _) => Ordering::Less,
-            (&LogValue::Number(_), _) => Ordering::Less,
-            (&LogValue::String(_), _) => Ordering::Less,
-            (&LogValue::Array(_), _) => Ordering::Less,
+            // values of different types are ordered by type, so all the strings of an index's terms are together
+            (a, b) => a.type_rank().cmp(&b.type_rank())
         }
     }
 }
@@ -1,74 +1,186 @@
 #[macro_use]
-extern crate log;
-#[macro_use]
-extern crate serde_derive;
+extern crate clap;
 #[macro_use]
-extern crate serde_json;
+extern crate log;
 
-extern crate base64;
-extern crate byteorder;
-extern crate bytes;
 extern crate chan_signal;
 extern crate futures;
-extern crate futures_cpupool;
-extern crate hyper;
-extern crate itertools;
-extern crate positioned_io;
-extern crate rayon;
-extern crate rmp_serde as rmps;
-extern crate serde;
+extern crate logstore;
 extern crate simple_logger;
 extern crate tokio_core;
-extern crate tokio_io;
-extern crate tokio_proto;
-extern crate tokio_service;
-extern crate twox_hash;
-
-// my files/modules
-mod utils;
-mod log_file;
-mod index_file;
-mod log_value;
-mod record_file;
-mod json;
-mod data_manager;
-mod rpc_codec;
-mod rpc_server;
-mod record_error;
-mod http_server;
 
 use std::collections::HashMap;
+use std::process;
+use std::rc::Rc;
 use std::thread;
 use std::time;
-use std::sync::{Arc, Mutex};
-use std::path::Path;
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use log::Level;
 
 use chan_signal::Signal;
+use clap::{App, Arg, ArgMatches};
 use tokio_core::reactor::Core;
-use futures::future;
-use futures::executor;
+use futures::Future;
+use futures::sync::oneshot;
+
+use logstore::DataManager;
+use logstore::config::{Config, Durability};
+use logstore::flusher::Flusher;
+use logstore::retention::Retention;
+use logstore::metrics::Metrics;
+use logstore::http_server::configure_http_server;
+use logstore::repair::start_repair;
+use logstore::rpc_server::{run_rpc_server, start_heartbeats, RPCClient};
+use logstore::shutdown::Drain;
+use logstore::tls::RpcTls;
+
+/// Reads the config file, if given, then applies the command line flags over it
+fn load_config(matches: &ArgMatches) -> Result<Config, Vec<String>> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(Path::new(path)).map_err(|e| vec![e])?,
+        None => Config::default()
+    };
+
+    if let Some(dir) = matches.value_of("data-dir") { config.data_dir = PathBuf::from(dir); }
+    if let Some(addr) = matches.value_of("rpc-addr") { config.rpc_addr = addr.to_owned(); }
+    if let Some(addr) = matches.value_of("http-addr") { config.http_addr = addr.to_owned(); }
+    if let Some(level) = matches.value_of("log-level") { config.log_level = level.to_owned(); }
+    if let Some(nodes) = matches.values_of("node") { config.nodes = nodes.map(String::from).collect(); }
+
+    let mut errors = Vec::new();
+
+    if let Some(secs) = matches.value_of("flush-interval") {
+        match secs.parse() {
+            Ok(s) => config.flush_interval_secs = s,
+            Err(_) => errors.push(format!("flush-interval must be a number of seconds, not {}", secs))
+        }
+    }
+
+    if let Some(budget) = matches.value_of("index-memory-budget") {
+        match budget.parse() {
+            Ok(b) => config.index_memory_budget = b,
+            Err(_) => errors.push(format!("index-memory-budget must be a number of index entries, not {}", budget))
+        }
+    }
 
-use rpc_server::run_rpc_server;
-use http_server::configure_http_server;
-use rpc_server::RPCClient;
-use data_manager::DataManager;
+    if let Some(durability) = matches.value_of("durability") {
+        match Durability::from_str(durability) {
+            Ok(d) => config.durability = d,
+            Err(e) => errors.push(e)
+        }
+    }
+
+    if let Some(secs) = matches.value_of("shutdown-timeout") {
+        match secs.parse() {
+            Ok(s) => config.shutdown_timeout_secs = s,
+            Err(_) => errors.push(format!("shutdown-timeout must be a number of seconds, not {}", secs))
+        }
+    }
+
+    if let Some(days) = matches.value_of("retention-days") {
+        match days.parse() {
+            Ok(d) => config.retention_days = Some(d),
+            Err(_) => errors.push(format!("retention-days must be a number of days, not {}", days))
+        }
+    }
+
+    if let Some(size) = matches.value_of("max-frame-size") {
+        match size.parse() {
+            Ok(s) => config.max_frame_size = s,
+            Err(_) => errors.push(format!("max-frame-size must be a number of bytes, not {}", size))
+        }
+    }
+
+    if let Err(e) = config.validate() {
+        errors.extend(e);
+    }
+
+    if errors.is_empty() { Ok(config) } else { Err(errors) }
+}
 
 fn main() {
-    simple_logger::init_with_level(Level::Debug).unwrap(); // this will panic on error
+    let matches = App::new("logstore")
+        .version(crate_version!())
+        .about("Stores logs, and searches them with an Elasticsearch compatible API")
+        .arg(Arg::with_name("config").short("c").long("config").takes_value(true).help("TOML config file"))
+        .arg(Arg::with_name("data-dir").long("data-dir").takes_value(true).help("Directory holding the log and index files"))
+        .arg(Arg::with_name("rpc-addr").long("rpc-addr").takes_value(true).help("Address the RPC server listens on"))
+        .arg(Arg::with_name("http-addr").long("http-addr").takes_value(true).help("Address the HTTP server listens on"))
+        .arg(Arg::with_name("log-level").long("log-level").takes_value(true).help("error, warn, info, debug or trace"))
+        .arg(Arg::with_name("flush-interval").long("flush-interval").takes_value(true).help("Seconds between index flushes, 0 to only flush for the memory budget"))
+        .arg(Arg::with_name("index-memory-budget").long("index-memory-budget").takes_value(true).help("Index entries held in memory before they're flushed"))
+        .arg(Arg::with_name("durability").long("durability").takes_value(true).help("buffered, or fsync to sync every write"))
+        .arg(Arg::with_name("shutdown-timeout").long("shutdown-timeout").takes_value(true).help("Seconds requests in flight are given to finish when shutting down"))
+        .arg(Arg::with_name("retention-days").long("retention-days").takes_value(true).help("Days logs are kept"))
+        .arg(Arg::with_name("max-frame-size").long("max-frame-size").takes_value(true).help("Largest RPC frame in bytes, the same on every node"))
+        .arg(Arg::with_name("node").long("node").takes_value(true).multiple(true).number_of_values(1)
+            .help("RPC address of a node in the cluster, once per node in the order of their ids"))
+        .get_matches();
+
+    let config = match load_config(&matches) {
+        Ok(c) => c,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("Invalid configuration: {}", e);
+            }
+
+            process::exit(1);
+        }
+    };
+
+    // validated above
+    simple_logger::init_with_level(Level::from_str(&config.log_level).unwrap()).unwrap(); // this will panic on error
+
+    debug!("Configuration: {:?}", config);
+
+    let tls = match config.tls {
+        Some(ref t) => match RpcTls::load(&t.ca, &t.cert, &t.key, &t.server_name) {
+            Ok(tls) => Some(Arc::new(tls)),
+            Err(e) => {
+                eprintln!("Unable to load TLS configuration: {}", e);
+                process::exit(1);
+            }
+        },
+        None => None
+    };
 
     // signal channel to handle Ctrl-C
     let signal = chan_signal::notify(&[Signal::INT, Signal::TERM]);
 
     // create our DataManager
-    let dm = Arc::new(Mutex::new(DataManager::new(Path::new("/tmp")).unwrap()));
+    let data_manager = DataManager::new(&config.data_dir).unwrap();
+
+    data_manager.set_sync(config.durability == Durability::Fsync);
+
+    let dm = Arc::new(data_manager);
+    let metrics = Arc::new(Metrics::default());
+    let drain_timeout = time::Duration::from_secs(config.shutdown_timeout_secs);
+
+    let flush_interval = match config.flush_interval_secs {
+        0 => None,
+        secs => Some(time::Duration::from_secs(secs))
+    };
+
+    let flusher = Flusher::start(dm.clone(), flush_interval, config.index_memory_budget).unwrap();
+    let retention = config.retention_days.map(|days| Retention::start(dm.clone(), days).unwrap());
 
     let dm_c = dm.clone();
+    let rpc_addr = config.rpc_addr.parse().unwrap();
+    let tls_c = tls.clone();
+    let metrics_c = metrics.clone();
+    let max_frame_size = config.max_frame_size;
+    let (rpc_stop, rpc_stopped) = oneshot::channel::<()>();
 
     // spaw off our RPC server
-    let handler = thread::Builder::new()
+    let rpc_thread = thread::Builder::new()
         .name("rpc server".to_string())
-        .spawn(move || run_rpc_server(dm_c))
+        .spawn(move || {
+            if let Err(e) = run_rpc_server(dm_c, rpc_addr, tls_c, max_frame_size, metrics_c, rpc_stopped, drain_timeout) {
+                error!("RPC server failed: {}", e);
+            }
+        })
         .unwrap();
 
     // hackie
@@ -76,36 +188,71 @@ fn main() {
 
     debug!("Creating client map");
 
+    let http_config = config.clone();
+    let http_dm = dm.clone();
+    let (http_stop, http_stopped) = oneshot::channel::<()>();
+
+    let http_thread = thread::spawn(move || {
+        let config = http_config;
 
-    thread::spawn(|| {
         // create the core for the clients and HTTP Server
         let mut core = Core::new().unwrap();
 
         // construct the server info
         let mut server_info: HashMap<u32, RPCClient> = HashMap::new();
 
-        // insert bogus servers
-        server_info.insert(
-            0,
-            RPCClient::new("127.0.0.1:12345".to_string(), &mut core),
-        );
-    //    server_info.insert(
-    //        1,
-    //        RPCClient::new("127.0.0.1:2345".to_string(), &mut core),
-    //    );
+        for (id, address) in config.nodes.iter().enumerate() {
+            let mut client = RPCClient::new(address.clone(), tls.clone(), config.max_frame_size, &mut core);
+
+            client.enable_hints(&config.data_dir, id as u32).unwrap();
+
+            server_info.insert(id as u32, client);
+        }
 
         let http_handle = core.handle();
+        let server_info = Rc::new(server_info);
 
-        configure_http_server(&http_handle, server_info);
+        start_heartbeats(&http_handle, server_info.clone());
+        start_repair(&http_handle, server_info.clone());
 
-        core.run(future::empty::<(), ()>()).unwrap();
+        let drain = Drain::new();
+        let http_server = configure_http_server(&http_handle, &config.http_addr.parse().unwrap(), server_info.clone(), &config.data_dir, drain.clone(), http_dm, metrics);
+
+        // stop accepting connections, then give the requests in flight time to finish
+        core.run(http_server.select2(http_stopped)).ok();
+
+        info!("Shutting down HTTP server");
+
+        drain.stop();
+
+        if core.run(drain.wait(&http_handle, drain_timeout)) == Ok(true) {
+            info!("HTTP server finished all requests");
+        }
+
+        for client in server_info.values() {
+            if let Some(hints) = client.hints() {
+                hints.borrow_mut().close();
+            }
+        }
     });
 
-    let ret = signal.recv().unwrap();
+    let signal = signal.recv().unwrap();
+
+    info!("Received {:?}, shutting down", signal);
+
+    // the HTTP server sends requests to the RPC servers, so it stops first
+    http_stop.send(()).ok();
+    http_thread.join().unwrap();
+
+    rpc_stop.send(()).ok();
+    rpc_thread.join().unwrap();
+
+    if let Some(retention) = retention {
+        retention.stop();
+    }
 
-//    handler.join().unwrap();
-//    debug!("After join");
+    flusher.stop();
 
-    dm.lock().unwrap().close();
+    dm.close();
     info!("Data Manager closed");
 }
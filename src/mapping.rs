@@ -0,0 +1,448 @@
+use serde_json;
+use serde_json::{Map, Value};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{rename, File};
+use std::io::{Error as IOError, ErrorKind};
+use std::path::Path;
+
+use ::analysis::{Analyzer, Normalizer};
+use ::json::INDEX_FIELD;
+use ::log_value::LogValue;
+
+/// The internal fields that are indexed whatever the mapping: repair and the client find logs by __id,
+/// repair finds them by __ts, and the logical indices are listed from __index
+const ALWAYS_INDEXED: &[&str] = &["__id", "__ts", INDEX_FIELD];
+
+/// What happens to a field that is not in the mapping
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Dynamic {
+    Index,  // added to the field's index, `"dynamic": true`
+    Store,  // kept in the stored log but not indexed, `"dynamic": false`
+    Reject  // the log is rejected, `"dynamic": "strict"`
+}
+
+/// The type of a field's values, a log with a value of another type is rejected
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    Keyword, // a string, indexed as a whole
+    Text,    // a string, split into words by an analyzer
+    Long,
+    Double,
+    Boolean
+}
+
+impl FieldType {
+    /// Parses an Elasticsearch type, the integer and floating point types map onto Long and Double
+    pub fn parse(name: &str) -> Option<FieldType> {
+        match name {
+            "keyword" => Some(FieldType::Keyword),
+            "text" => Some(FieldType::Text),
+            "long" | "integer" | "short" | "byte" => Some(FieldType::Long),
+            "double" | "float" | "half_float" => Some(FieldType::Double),
+            "boolean" => Some(FieldType::Boolean),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FieldType::Keyword => "keyword",
+            FieldType::Text => "text",
+            FieldType::Long => "long",
+            FieldType::Double => "double",
+            FieldType::Boolean => "boolean"
+        }
+    }
+
+    /// Checks a value is of the type, null and each element of an array are checked on their own
+    fn accepts(&self, value: &LogValue) -> bool {
+        match (*self, value) {
+            (_, &LogValue::Null) => true,
+            (_, &LogValue::Array(ref values)) => values.iter().all(|v| self.accepts(v)),
+            (FieldType::Keyword, &LogValue::String(_)) | (FieldType::Text, &LogValue::String(_)) => true,
+            (FieldType::Long, &LogValue::Number(ref n)) => n.is_i64() || n.is_u64(),
+            (FieldType::Double, &LogValue::Number(_)) => true,
+            (FieldType::Boolean, &LogValue::Bool(_)) => true,
+            _ => false
+        }
+    }
+}
+
+/// How a field's values are stored and indexed
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct FieldMapping {
+    pub field_type: FieldType,
+    pub index: bool, // false to only keep the value in the stored log, so the field can't be searched
+    #[serde(default)]
+    pub analyzer: Option<Analyzer>, // only for text fields
+    #[serde(default)]
+    pub normalizer: Vec<Normalizer> // only for keyword fields, applied in order
+}
+
+impl FieldMapping {
+    fn from_json(field: &str, settings: &Value) -> Result<FieldMapping, String> {
+        if ALWAYS_INDEXED.contains(&field) {
+            return Err(format!("{} is always indexed and cannot be mapped", field));
+        }
+
+        let settings = settings.as_object().ok_or_else(|| format!("the mapping of {} must be a JSON object", field))?;
+        let mut field_type = None;
+        let mut index = true;
+        let mut analyzer = None;
+        let mut normalizer = Vec::new();
+
+        for (key, value) in settings {
+            match key.as_str() {
+                "type" => field_type = Some(value.as_str()
+                    .and_then(FieldType::parse)
+                    .ok_or_else(|| format!("{} has an unsupported type {}", field, value))?),
+                "index" => index = value.as_bool().ok_or_else(|| format!("index of {} must be true or false", field))?,
+                "analyzer" => analyzer = Some(value.as_str()
+                    .and_then(Analyzer::parse)
+                    .ok_or_else(|| format!("{} has an unsupported analyzer {}, it must be standard, whitespace or stop", field, value))?),
+                "normalizer" => normalizer = parse_normalizer(field, value)?,
+                _ => return Err(format!("{} has an unsupported mapping parameter {}", field, key))
+            }
+        }
+
+        if !normalizer.is_empty() && field_type.is_some() && field_type != Some(FieldType::Keyword) {
+            return Err(format!("{} has a normalizer, but only keyword fields are normalized", field));
+        }
+
+        match (field_type, analyzer) {
+            (Some(FieldType::Text), analyzer) => Ok(FieldMapping { field_type: FieldType::Text, index, analyzer: analyzer.or(Some(Analyzer::Standard)), normalizer }),
+            (Some(_), Some(_)) => Err(format!("{} has an analyzer, but only text fields are analyzed", field)),
+            (Some(field_type), None) => Ok(FieldMapping { field_type, index, analyzer: None, normalizer }),
+            (None, _) => Err(format!("{} has no type", field))
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut ret = json!({ "type": self.field_type.name(), "index": self.index });
+
+        if let Some(analyzer) = self.analyzer {
+            ret["analyzer"] = json!(analyzer.name());
+        }
+
+        if !self.normalizer.is_empty() {
+            ret["normalizer"] = json!(self.normalizer.iter().map(|n| n.name()).collect::<Vec<_>>());
+        }
+
+        ret
+    }
+}
+
+/// A change to the mapping, from the body of a `PUT _mapping` request
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MappingUpdate {
+    pub dynamic: Option<Dynamic>,
+    pub properties: BTreeMap<String, FieldMapping>
+}
+
+impl MappingUpdate {
+    /// Parses an Elasticsearch mapping: `{ "dynamic": "strict", "properties": { "host": { "type": "keyword" } } }`
+    pub fn from_json(body: &Value) -> Result<MappingUpdate, String> {
+        let body = body.as_object().ok_or("the mapping must be a JSON object")?;
+        let mut update = MappingUpdate { dynamic: None, properties: BTreeMap::new() };
+
+        for (key, value) in body {
+            match key.as_str() {
+                "dynamic" => update.dynamic = Some(parse_dynamic(value)?),
+                "properties" => {
+                    let properties = value.as_object().ok_or("properties must be a JSON object")?;
+
+                    for (field, settings) in properties {
+                        update.properties.insert(field.to_owned(), FieldMapping::from_json(field, settings)?);
+                    }
+                },
+                _ => return Err(format!("unsupported mapping parameter {}", key))
+            }
+        }
+
+        Ok(update)
+    }
+}
+
+/// A normalizer's name, or an array of names applied in order: `"lowercase"` or `["nfkc", "lowercase", "trim"]`
+fn parse_normalizer(field: &str, value: &Value) -> Result<Vec<Normalizer>, String> {
+    let names = match *value {
+        Value::String(_) => vec![value],
+        Value::Array(ref names) => names.iter().collect(),
+        _ => return Err(format!("normalizer of {} must be a name or an array of names", field))
+    };
+
+    names.into_iter().map(|name| {
+        name.as_str()
+            .and_then(Normalizer::parse)
+            .ok_or_else(|| format!("{} has an unsupported normalizer {}, it must be lowercase, nfkc or trim", field, name))
+    }).collect()
+}
+
+fn parse_dynamic(value: &Value) -> Result<Dynamic, String> {
+    match (value.as_bool(), value.as_str()) {
+        (Some(true), _) | (_, Some("true")) => Ok(Dynamic::Index),
+        (Some(false), _) | (_, Some("false")) => Ok(Dynamic::Store),
+        (_, Some("strict")) => Ok(Dynamic::Reject),
+        _ => Err(format!("dynamic must be true, false or strict, not {}", value))
+    }
+}
+
+/// How the fields of logs are stored and indexed, stored as JSON
+/// Every logical index shares the mapping, just as they share the field indices
+/// The mapping only applies to logs inserted after it's changed
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Mapping {
+    pub dynamic: Dynamic,
+    pub properties: BTreeMap<String, FieldMapping>
+}
+
+impl Default for Mapping {
+    fn default() -> Mapping {
+        Mapping { dynamic: Dynamic::Index, properties: BTreeMap::new() }
+    }
+}
+
+impl Mapping {
+    /// Loads the mapping from the file, the default mapping is used if it does not exist
+    pub fn load(path: &Path) -> Result<Mapping, IOError> {
+        match File::open(path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| IOError::new(ErrorKind::InvalidData, e)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Mapping::default()),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Writes the mapping to a temporary file, then moves it into place
+    pub fn save(&self, path: &Path) -> Result<(), IOError> {
+        let tmp_path = path.with_extension("tmp");
+
+        {
+            let file = File::create(&tmp_path)?;
+
+            serde_json::to_writer_pretty(file, self).map_err(|e| IOError::new(ErrorKind::Other, e))?;
+        }
+
+        rename(tmp_path, path)
+    }
+
+    /// Adds the fields of the update, leaving the mapping unchanged if any field is already mapped differently,
+    /// as the logs already indexed would no longer match it
+    pub fn apply(&mut self, update: &MappingUpdate) -> Result<(), String> {
+        for (field, mapping) in update.properties.iter() {
+            match self.properties.get(field) {
+                Some(existing) if existing != mapping => return Err(format!("the mapping of {} cannot be changed", field)),
+                _ => ()
+            }
+        }
+
+        if let Some(dynamic) = update.dynamic {
+            self.dynamic = dynamic;
+        }
+
+        self.properties.extend(update.properties.iter().map(|(f, m)| (f.to_owned(), m.clone())));
+
+        Ok( () )
+    }
+
+    /// Checks the log can be stored, returning why it can't
+    /// Internal fields, starting with __, are never rejected
+    pub fn check(&self, log: &HashMap<String, LogValue>) -> Result<(), String> {
+        for (field, value) in log.iter() {
+            match self.properties.get(field) {
+                Some(mapping) if !mapping.field_type.accepts(value) => {
+                    return Err(format!("{} must be a {}, not {}", field, mapping.field_type.name(), value.clone().into_value()));
+                },
+                None if self.dynamic == Dynamic::Reject && !field.starts_with("__") => {
+                    return Err(format!("{} is not in the mapping, and dynamic is strict", field));
+                },
+                _ => ()
+            }
+        }
+
+        Ok( () )
+    }
+
+    /// Whether the values of the field are added to its index
+    pub fn is_indexed(&self, field: &str) -> bool {
+        if ALWAYS_INDEXED.contains(&field) {
+            return true;
+        }
+
+        match self.properties.get(field) {
+            Some(mapping) => mapping.index,
+            None => self.dynamic == Dynamic::Index
+        }
+    }
+
+    /// The analyzer of a text field that's indexed
+    pub fn analyzer(&self, field: &str) -> Option<Analyzer> {
+        match self.properties.get(field) {
+            Some(mapping) if mapping.index => mapping.analyzer,
+            _ => None
+        }
+    }
+
+    /// The normalizers of a keyword field that's indexed, applied to its values and to the values searched for
+    pub fn normalizers(&self, field: &str) -> &[Normalizer] {
+        match self.properties.get(field) {
+            Some(mapping) if mapping.index => mapping.normalizer.as_slice(),
+            _ => &[]
+        }
+    }
+
+    /// The mapping in the format of Elasticsearch's `GET _mapping`
+    pub fn to_json(&self) -> Value {
+        let dynamic = match self.dynamic {
+            Dynamic::Index => json!(true),
+            Dynamic::Store => json!(false),
+            Dynamic::Reject => json!("strict")
+        };
+
+        let properties = self.properties.iter().map(|(f, m)| (f.to_owned(), m.to_json())).collect::<Map<String, Value>>();
+
+        json!({ "dynamic": dynamic, "properties": properties })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::analysis::{Analyzer, Normalizer};
+    use ::mapping::{Dynamic, FieldType, Mapping, MappingUpdate};
+    use ::json::json2map;
+
+    use std::fs::remove_file;
+    use std::path::Path;
+
+    #[test]
+    fn parse_apply() {
+        let update = MappingUpdate::from_json(&json!({
+            "dynamic": "strict",
+            "properties": {
+                "status": { "type": "integer" },
+                "message": { "type": "keyword", "index": false }
+            }
+        })).unwrap();
+
+        assert_eq!(update.dynamic, Some(Dynamic::Reject));
+        assert_eq!(update.properties["status"].field_type, FieldType::Long);
+        assert!(!update.properties["message"].index);
+
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "host": { "type": "geo_point" } } })).is_err());
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "host": { "type": "keyword", "boost": 2 } } })).is_err());
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "__ts": { "type": "long" } } })).is_err());
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "__id": { "type": "keyword", "index": false } } })).is_err());
+        assert!(MappingUpdate::from_json(&json!({ "dynamic": "runtime" })).is_err());
+
+        let mut mapping = Mapping::default();
+
+        mapping.apply(&update).unwrap();
+
+        // fields can be added, and the same mapping sent again, but not changed
+        assert!(mapping.apply(&update).is_ok());
+        assert!(mapping.apply(&MappingUpdate::from_json(&json!({ "properties": { "host": { "type": "keyword" } } })).unwrap()).is_ok());
+        assert!(mapping.apply(&MappingUpdate::from_json(&json!({ "properties": { "status": { "type": "keyword" } } })).unwrap()).is_err());
+
+        assert_eq!(mapping.dynamic, Dynamic::Reject);
+        assert_eq!(mapping.properties.len(), 3);
+        assert_eq!(mapping.to_json()["dynamic"], json!("strict"));
+        assert_eq!(mapping.to_json()["properties"]["status"], json!({ "type": "long", "index": true }));
+    }
+
+    #[test]
+    fn check_indexed() {
+        let mut mapping = Mapping::default();
+
+        mapping.apply(&MappingUpdate::from_json(&json!({
+            "dynamic": false,
+            "properties": {
+                "status": { "type": "long" },
+                "message": { "type": "keyword", "index": false }
+            }
+        })).unwrap()).unwrap();
+
+        assert!(mapping.is_indexed("status"));
+        assert!(!mapping.is_indexed("message"));
+        assert!(!mapping.is_indexed("unknown"));
+        assert!(mapping.is_indexed("__ts"));
+        assert!(mapping.is_indexed("__id"));
+
+        assert!(mapping.check(&json2map(&json!({ "status": 200, "other": "x" }).to_string()).unwrap()).is_ok());
+        assert!(mapping.check(&json2map(&json!({ "status": "200" }).to_string()).unwrap()).is_err());
+        assert!(mapping.check(&json2map(&json!({ "status": 2.5 }).to_string()).unwrap()).is_err());
+
+        mapping.dynamic = Dynamic::Reject;
+
+        // the __id and __ts added to every log aren't rejected
+        assert!(mapping.check(&json2map(&json!({ "status": 200 }).to_string()).unwrap()).is_ok());
+        assert!(mapping.check(&json2map(&json!({ "other": "x" }).to_string()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn text_fields() {
+        let update = MappingUpdate::from_json(&json!({
+            "properties": {
+                "message": { "type": "text" },
+                "path": { "type": "text", "analyzer": "whitespace" }
+            }
+        })).unwrap();
+
+        let mut mapping = Mapping::default();
+
+        mapping.apply(&update).unwrap();
+
+        assert_eq!(mapping.analyzer("message"), Some(Analyzer::Standard));
+        assert_eq!(mapping.analyzer("path"), Some(Analyzer::Whitespace));
+        assert_eq!(mapping.analyzer("host"), None);
+        assert_eq!(mapping.to_json()["properties"]["message"]["analyzer"], json!("standard"));
+
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "host": { "type": "keyword", "analyzer": "stop" } } })).is_err());
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "message": { "type": "text", "analyzer": "english" } } })).is_err());
+    }
+
+    #[test]
+    fn normalizers() {
+        let update = MappingUpdate::from_json(&json!({
+            "properties": {
+                "level": { "type": "keyword", "normalizer": "lowercase" },
+                "host": { "type": "keyword", "normalizer": ["nfkc", "lowercase", "trim"] },
+                "user": { "type": "keyword", "normalizer": "trim", "index": false }
+            }
+        })).unwrap();
+
+        let mut mapping = Mapping::default();
+
+        mapping.apply(&update).unwrap();
+
+        assert_eq!(mapping.normalizers("level"), &[Normalizer::Lowercase]);
+        assert_eq!(mapping.normalizers("host"), &[Normalizer::Nfkc, Normalizer::Lowercase, Normalizer::Trim]);
+        assert!(mapping.normalizers("user").is_empty());
+        assert!(mapping.normalizers("other").is_empty());
+        assert_eq!(mapping.to_json()["properties"]["level"]["normalizer"], json!(["lowercase"]));
+
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "message": { "type": "text", "normalizer": "lowercase" } } })).is_err());
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "level": { "type": "keyword", "normalizer": "asciifolding" } } })).is_err());
+        assert!(MappingUpdate::from_json(&json!({ "properties": { "level": { "type": "keyword", "normalizer": 1 } } })).is_err());
+
+        // a normalizer can't be added to a field already mapped
+        assert!(mapping.apply(&MappingUpdate::from_json(&json!({ "properties": { "user": { "type": "keyword", "index": false } } })).unwrap()).is_err());
+    }
+
+    #[test]
+    fn save_load() {
+        let path = Path::new("/tmp/mapping_test.json");
+
+        remove_file(path).ok();
+
+        assert_eq!(Mapping::load(path).unwrap(), Mapping::default());
+
+        let mut mapping = Mapping::default();
+
+        mapping.apply(&MappingUpdate::from_json(&json!({ "properties": { "host": { "type": "keyword" } } })).unwrap()).unwrap();
+        mapping.save(path).unwrap();
+
+        assert_eq!(Mapping::load(path).unwrap(), mapping);
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the buckets of a latency histogram
+pub const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A count that only goes up
+#[derive(Default)]
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    pub fn add(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A count that goes up and down
+#[derive(Default)]
+pub struct Gauge(AtomicUsize);
+
+impl Gauge {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Counts one more until the returned guard is dropped
+    pub fn track(gauge: &Arc<Gauge>) -> GaugeGuard {
+        gauge.0.fetch_add(1, Ordering::Relaxed);
+
+        GaugeGuard { gauge: gauge.clone() }
+    }
+}
+
+pub struct GaugeGuard {
+    gauge: Arc<Gauge>
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.gauge.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts durations into buckets, the last bucket holds everything over the largest bound
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<AtomicUsize>,
+    sum_micros: AtomicUsize
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: (0..bounds.len() + 1).map(|_| AtomicUsize::new(0)).collect(),
+            sum_micros: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+        let bucket = self.bounds.iter().position(|b| secs <= *b).unwrap_or(self.bounds.len());
+
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_secs() as usize * 1_000_000 + duration.subsec_nanos() as usize / 1000, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> usize {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new(LATENCY_BUCKETS)
+    }
+}
+
+/// The metrics of the servers, shared between their threads
+/// The storage engine's metrics are kept by the DataManager, and the health of each peer by its RPCClient
+#[derive(Default)]
+pub struct Metrics {
+    pub rpc_connections: Arc<Gauge>,
+    pub http_connections: Arc<Gauge>,
+    pub search_seconds: Histogram,    // _search requests to the HTTP endpoint, across the cluster
+    pub rpc_query_seconds: Histogram  // pages of a query answered by this node
+}
+
+/// Writes metrics in the Prometheus text exposition format
+#[derive(Default)]
+pub struct Exposition {
+    out: String
+}
+
+impl Exposition {
+    pub fn new() -> Exposition {
+        Exposition::default()
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: usize) {
+        self.header(name, help, "counter");
+        writeln!(self.out, "{} {}", name, value).unwrap();
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: usize) {
+        self.header(name, help, "gauge");
+        writeln!(self.out, "{} {}", name, value).unwrap();
+    }
+
+    /// A counter or gauge with a sample for each value of a label
+    pub fn labeled(&mut self, name: &str, help: &str, kind: &str, label: &str, samples: &[(String, usize)]) {
+        self.header(name, help, kind);
+
+        for &(ref label_value, value) in samples {
+            writeln!(self.out, "{}{{{}=\"{}\"}} {}", name, label, escape(label_value), value).unwrap();
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+
+        // buckets are cumulative
+        let mut total = 0;
+
+        for (bound, count) in histogram.bounds.iter().zip(histogram.counts.iter()) {
+            total += count.load(Ordering::Relaxed);
+            writeln!(self.out, "{}_bucket{{le=\"{}\"}} {}", name, bound, total).unwrap();
+        }
+
+        total += histogram.counts[histogram.bounds.len()].load(Ordering::Relaxed);
+
+        writeln!(self.out, "{}_bucket{{le=\"+Inf\"}} {}", name, total).unwrap();
+        writeln!(self.out, "{}_sum {}", name, histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6).unwrap();
+        writeln!(self.out, "{}_count {}", name, total).unwrap();
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+    }
+}
+
+/// Escapes a label value, as it's written in double quotes
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use ::metrics::{Exposition, Gauge, Histogram};
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::new(&[0.1, 1.0]);
+
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(5));
+
+        assert_eq!(histogram.count(), 3);
+
+        let mut out = Exposition::new();
+
+        out.histogram("test_seconds", "A test", &histogram);
+
+        let text = out.finish();
+
+        assert!(text.contains("# TYPE test_seconds histogram\n"));
+        assert!(text.contains("test_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(text.contains("test_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("test_seconds_sum 5.55\n"));
+    }
+
+    #[test]
+    fn gauge_guard() {
+        let gauge = Arc::new(Gauge::default());
+        let guard = Gauge::track(&gauge);
+
+        assert_eq!(gauge.get(), 1);
+
+        drop(guard);
+
+        assert_eq!(gauge.get(), 0);
+
+        let mut out = Exposition::new();
+
+        out.labeled("test_errors_total", "A test", "counter", "peer", &[(String::from("a\"b"), 2)]);
+
+        assert!(out.finish().contains("test_errors_total{peer=\"a\\\"b\"} 2\n"));
+    }
+}
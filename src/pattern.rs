@@ -0,0 +1,157 @@
+use regex;
+use regex::{Regex, RegexBuilder};
+
+/// The most terms a pattern can match in an index, matching more is refused rather than reading every posting
+pub const MAX_EXPANSIONS: usize = 1024;
+
+/// The most terms a pattern is tested against in an index, a pattern that doesn't narrow them down
+/// with a literal prefix is refused rather than testing every term
+pub const MAX_TERMS_SCANNED: usize = 100_000;
+
+/// Terms read from an index at a time while testing a pattern, the index is only locked while reading them
+pub const TERM_SCAN_BATCH: usize = 1000;
+
+/// The most memory, in bytes, a compiled pattern can use, which bounds its size and repetitions
+const MAX_PATTERN_SIZE: usize = 1 << 20;
+
+/// A wildcard or regular expression matched against the whole of each term in an index
+/// Matching takes time linear in the length of the term, whatever the pattern
+pub struct TermPattern {
+    prefix: String, // every matching term starts with it, so only those terms need testing
+    regex: Regex
+}
+
+impl TermPattern {
+    /// `*` matches any characters and `?` any one character, `\` escapes the character after it
+    pub fn wildcard(pattern: &str) -> Result<TermPattern, String> {
+        let mut prefix = String::new();
+        let mut expr = String::from("^(?s:");
+        let mut literal = true; // still in the part before the first wildcard
+        let mut chars = pattern.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => {
+                    literal = false;
+                    expr.push_str(".*");
+                },
+                '?' => {
+                    literal = false;
+                    expr.push('.');
+                },
+                c => {
+                    let c = if c == '\\' { chars.next().unwrap_or('\\') } else { c };
+
+                    if literal {
+                        prefix.push(c);
+                    }
+
+                    expr.push_str(&regex::escape(&c.to_string()));
+                }
+            }
+        }
+
+        expr.push_str(")$");
+
+        Ok(TermPattern { prefix, regex: build(&expr)? })
+    }
+
+    /// A regular expression, which must match the whole term as in Elasticsearch
+    pub fn regex(pattern: &str) -> Result<TermPattern, String> {
+        let regex = build(&format!("^(?:{})$", pattern))?;
+
+        Ok(TermPattern { prefix: literal_prefix(pattern), regex })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn is_match(&self, term: &str) -> bool {
+        self.regex.is_match(term)
+    }
+}
+
+/// The literal characters every match of a regular expression starts with
+/// Stops at the first character with a special meaning, and gives up on an alternation, which could start anywhere
+fn literal_prefix(pattern: &str) -> String {
+    if pattern.contains('|') {
+        return String::new();
+    }
+
+    let mut prefix = String::new();
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(e) if e.is_ascii_punctuation() => prefix.push(e),
+                _ => break // a class such as \d, or an escape sequence
+            },
+            // a repetition that allows none of the character before it
+            '*' | '?' | '{' => {
+                prefix.pop();
+                break;
+            },
+            '.' | '^' | '$' | '+' | '(' | ')' | '[' | ']' | '}' => break,
+            c => prefix.push(c)
+        }
+    }
+
+    prefix
+}
+
+fn build(regex: &str) -> Result<Regex, String> {
+    RegexBuilder::new(regex)
+        .size_limit(MAX_PATTERN_SIZE)
+        .dfa_size_limit(MAX_PATTERN_SIZE)
+        .build()
+        .map_err(|e| format!("Invalid pattern: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use ::pattern::TermPattern;
+
+    #[test]
+    fn wildcard() {
+        let pattern = TermPattern::wildcard("web-*").unwrap();
+
+        assert_eq!(pattern.prefix(), "web-");
+        assert!(pattern.is_match("web-1"));
+        assert!(pattern.is_match("web-"));
+        assert!(!pattern.is_match("api-web-1"));
+
+        let pattern = TermPattern::wildcard("/api/v?/users").unwrap();
+
+        assert_eq!(pattern.prefix(), "/api/v");
+        assert!(pattern.is_match("/api/v2/users"));
+        assert!(!pattern.is_match("/api/v10/users"));
+
+        // escaped and regex characters are literal
+        let pattern = TermPattern::wildcard("a\\*.b*").unwrap();
+
+        assert_eq!(pattern.prefix(), "a*.b");
+        assert!(pattern.is_match("a*.bc"));
+        assert!(!pattern.is_match("ax.bc"));
+    }
+
+    #[test]
+    fn regex() {
+        let pattern = TermPattern::regex("web-[0-9]+").unwrap();
+
+        assert_eq!(pattern.prefix(), "web-");
+        assert!(pattern.is_match("web-12"));
+        assert!(!pattern.is_match("web-12a"));
+        assert!(!pattern.is_match("my-web-12"));
+
+        // only the characters every match starts with are a prefix
+        assert_eq!(TermPattern::regex("web\\.ex?ample").unwrap().prefix(), "web.e");
+        assert_eq!(TermPattern::regex("web+").unwrap().prefix(), "web");
+        assert_eq!(TermPattern::regex("web|api").unwrap().prefix(), "");
+        assert_eq!(TermPattern::regex("\\d+").unwrap().prefix(), "");
+
+        assert!(TermPattern::regex("web-[0-9").is_err());
+        assert!(TermPattern::regex("(a{1000}){1000}").is_err());
+    }
+}
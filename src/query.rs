@@ -0,0 +1,109 @@
+use serde_json::{from_str, Value};
+
+use ::json::{has_object, INDEX_FIELD};
+use ::log_value::LogValue;
+use ::pattern::TermPattern;
+
+/// What a search matches, each node evaluates it against its own mapping
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+pub enum Query {
+    Term(String, LogValue),   // the field has the value, or every element of an array value, or for a text field, every word
+    Phrase(String, String),   // a text field has the words next to each other and in order, any other field has the value
+    Wildcard(String, String), // a term of the field matches the pattern, see TermPattern::wildcard
+    Regex(String, String),    // a term of the field matches the regular expression
+    And(Vec<Query>)           // every one of the queries matches
+}
+
+impl Query {
+    /// Parses the `q` parameter of a search, `field:value`
+    /// A value in slashes is a regular expression, and a value in double quotes is a phrase
+    /// Otherwise a value with * or ? is a wildcard pattern, or it's parsed as JSON, falling back to a string
+    pub fn parse(q: &str) -> Option<Query> {
+        let mut kv = q.splitn(2, ':');
+
+        let field = kv.next()?.to_owned();
+        let value = kv.next()?;
+
+        if value.len() >= 2 && value.starts_with('/') && value.ends_with('/') {
+            return Some(Query::Regex(field, value[1..value.len() - 1].to_owned()));
+        }
+
+        let query = match from_str::<Value>(value) {
+            Ok(Value::String(phrase)) => Query::Phrase(field, phrase),
+            Ok(ref v) if !has_object(v) => Query::Term(field, LogValue::from(v)),
+            _ if value.contains('*') || value.contains('?') => Query::Wildcard(field, value.to_owned()),
+            _ => Query::Term(field, LogValue::String(value.to_owned()))
+        };
+
+        Some(query)
+    }
+
+    /// Checks the query can be run, so an invalid pattern is reported once rather than by every node
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Query::Wildcard(_, ref pattern) => TermPattern::wildcard(pattern).map(|_| ()),
+            Query::Regex(_, ref pattern) => TermPattern::regex(pattern).map(|_| ()),
+            Query::And(ref queries) => queries.iter().map(|q| q.validate()).collect(),
+            _ => Ok( () )
+        }
+    }
+
+    /// Restricts the query to the logs of a logical index, where a trailing * matches any suffix as in an API key's indices
+    pub fn in_index(self, index: &str) -> Query {
+        let restriction = if index.ends_with('*') {
+            Query::Wildcard(String::from(INDEX_FIELD), index.to_owned())
+        } else {
+            Query::Term(String::from(INDEX_FIELD), LogValue::String(index.to_owned()))
+        };
+
+        Query::And(vec![restriction, self])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::query::Query;
+    use ::json::INDEX_FIELD;
+    use ::log_value::LogValue;
+
+    use serde_json::Number;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Query::parse("status:200"), Some(Query::Term(String::from("status"), LogValue::Number(Number::from(200)))));
+        assert_eq!(Query::parse("message:timeout"), Some(Query::Term(String::from("message"), LogValue::String(String::from("timeout")))));
+        assert_eq!(Query::parse("message:\"timed out\""), Some(Query::Phrase(String::from("message"), String::from("timed out"))));
+        assert_eq!(Query::parse("time:12:30"), Some(Query::Term(String::from("time"), LogValue::String(String::from("12:30")))));
+        assert_eq!(Query::parse("message"), None);
+    }
+
+    #[test]
+    fn parse_patterns() {
+        assert_eq!(Query::parse("host:web-*"), Some(Query::Wildcard(String::from("host"), String::from("web-*"))));
+        assert_eq!(Query::parse("path:/api/v?/users"), Some(Query::Wildcard(String::from("path"), String::from("/api/v?/users"))));
+        assert_eq!(Query::parse("host:/web-[0-9]+/"), Some(Query::Regex(String::from("host"), String::from("web-[0-9]+"))));
+
+        // quoted values are never patterns
+        assert_eq!(Query::parse("path:\"/api/\""), Some(Query::Phrase(String::from("path"), String::from("/api/"))));
+
+        assert!(Query::parse("host:/web-[0-9/").unwrap().validate().is_err());
+        assert!(Query::parse("host:web-*").unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn in_index() {
+        let query = Query::parse("host:web-1").unwrap();
+
+        assert_eq!(query.clone().in_index("logs-app"), Query::And(vec![
+            Query::Term(String::from(INDEX_FIELD), LogValue::String(String::from("logs-app"))),
+            query.clone()
+        ]));
+
+        assert_eq!(query.clone().in_index("logs-*"), Query::And(vec![
+            Query::Wildcard(String::from(INDEX_FIELD), String::from("logs-*")),
+            query.clone()
+        ]));
+
+        assert!(Query::parse("host:/web-[/").unwrap().in_index("logs-*").validate().is_err());
+    }
+}
@@ -0,0 +1,389 @@
+use futures::{future, Future};
+use futures::future::Loop;
+use serde_json;
+use tokio_core::reactor::{Handle, Timeout};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{rename, File};
+use std::io::{Error as IOError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use ::cluster::{placement_key, primary_bucket, replica_buckets, REPLICATION_FACTOR};
+use ::log_value::LogValue;
+use ::rpc_codec::{RequestMessage, ResponseMessage, frame_chunks, log_size};
+use ::rpc_server::RPCClient;
+
+/// Number of logs read from a node in each batch
+pub const REBALANCE_BATCH_SIZE: u32 = 500;
+
+/// Delay between batches read from a node, throttling the rebalance
+pub const REBALANCE_BATCH_DELAY_MS: u64 = 100;
+
+/// The progress of rebalancing the logs held by a single node
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct NodeProgress {
+    pub cursor: u64,  // location in the node's log file to continue from
+    pub scanned: u64, // number of logs read from the node
+    pub moved: u64,   // number of logs copied to their new owners
+    pub done: bool
+}
+
+/// The progress of a rebalance, persisted so it can be resumed after an interruption
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RebalanceProgress {
+    pub old_buckets: u32,
+    pub new_buckets: u32,
+    pub nodes: BTreeMap<u32, NodeProgress>,
+    #[serde(skip)]
+    pub running: bool
+}
+
+pub type RebalanceState = Rc<RefCell<Option<RebalanceProgress>>>;
+
+impl RebalanceProgress {
+    pub fn new(old_buckets: u32, new_buckets: u32) -> RebalanceProgress {
+        let nodes = (0..old_buckets).map(|b| (b, NodeProgress::default())).collect();
+
+        RebalanceProgress { old_buckets, new_buckets, nodes, running: false }
+    }
+
+    /// Loads the progress of a previous rebalance, if there is one
+    pub fn load(path: &Path) -> Option<RebalanceProgress> {
+        let file = File::open(path).ok()?;
+
+        match serde_json::from_reader(file) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                warn!("Unable to read rebalance progress from {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Writes the progress to a temporary file, then moves it into place
+    pub fn save(&self, path: &Path) -> Result<(), IOError> {
+        let tmp_path = path.with_extension("tmp");
+
+        {
+            let file = File::create(&tmp_path)?;
+
+            serde_json::to_writer(file, self).map_err(|e| IOError::new(ErrorKind::Other, e))?;
+        }
+
+        rename(tmp_path, path)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.nodes.values().all(|n| n.done)
+    }
+}
+
+/// Returns the buckets that hold a log after rebalancing, but did not hold it before
+pub fn new_owners(id: &str, old_buckets: u32, new_buckets: u32) -> Vec<u32> {
+    let key = placement_key(id);
+    let old = replica_buckets(key, old_buckets, REPLICATION_FACTOR);
+
+    replica_buckets(key, new_buckets, REPLICATION_FACTOR).into_iter().filter(|b| !old.contains(b)).collect()
+}
+
+/// Starts moving logs from their owners in a cluster of `old_buckets` nodes to their owners in a cluster of `new_buckets`
+/// Every node of the larger cluster must have a client, so a cluster shrinks while the nodes leaving it are still configured
+/// A previous rebalance between the same cluster sizes is resumed from where it left off
+pub fn start_rebalance(handle: &Handle,
+                       clients: Rc<HashMap<u32, RPCClient>>,
+                       state: RebalanceState,
+                       progress_path: PathBuf,
+                       old_buckets: u32,
+                       new_buckets: u32) -> Result<(), String> {
+    if state.borrow().as_ref().map(|p| p.running).unwrap_or(false) {
+        return Err(String::from("A rebalance is already running"));
+    }
+
+    if old_buckets == 0 || new_buckets == 0 {
+        return Err(format!("Cannot rebalance from {} to {} nodes", old_buckets, new_buckets));
+    }
+
+    if let Some(missing) = (0..old_buckets.max(new_buckets)).find(|b| !clients.contains_key(b)) {
+        return Err(format!("Cannot rebalance from {} to {} nodes without node {}", old_buckets, new_buckets, missing));
+    }
+
+    let mut progress = match RebalanceProgress::load(&progress_path) {
+        Some(ref p) if p.old_buckets == old_buckets && p.new_buckets == new_buckets && !p.is_done() => {
+            info!("Resuming rebalance from {} to {} nodes", old_buckets, new_buckets);
+            p.clone()
+        },
+        _ => RebalanceProgress::new(old_buckets, new_buckets)
+    };
+
+    progress.running = true;
+
+    let buckets = progress.nodes.iter().filter(|&(_, n)| !n.done).map(|(b, _)| *b).collect::<Vec<_>>();
+
+    *state.borrow_mut() = Some(progress);
+
+    info!("Starting rebalance from {} to {} nodes", old_buckets, new_buckets);
+
+    let node_futures = buckets.into_iter().map(|bucket| {
+        rebalance_node(handle.clone(), clients.clone(), state.clone(), progress_path.clone(), bucket)
+    }).collect::<Vec<_>>();
+
+    handle.spawn(future::join_all(node_futures).then(move |res| {
+        let mut progress = state.borrow_mut();
+        let progress = progress.as_mut().unwrap();
+
+        progress.running = false;
+
+        match res {
+            Ok(_) => info!("Rebalance from {} to {} nodes finished", progress.old_buckets, progress.new_buckets),
+            Err(e) => error!("Rebalance stopped, it can be resumed: {}", e)
+        }
+
+        if let Err(e) = progress.save(&progress_path) {
+            error!("Unable to save rebalance progress: {}", e);
+        }
+
+        Ok::<(), ()>(())
+    }));
+
+    Ok( () )
+}
+
+/// Reads all of the logs from a node in batches, copying each log it's the primary for to its new owners
+fn rebalance_node(handle: Handle,
+                  clients: Rc<HashMap<u32, RPCClient>>,
+                  state: RebalanceState,
+                  progress_path: PathBuf,
+                  bucket: u32) -> Box<Future<Item=(), Error=IOError>> {
+    let (start, old_buckets, new_buckets) = {
+        let progress = state.borrow();
+        let progress = progress.as_ref().unwrap();
+
+        (progress.nodes[&bucket].cursor, progress.old_buckets, progress.new_buckets)
+    };
+
+    Box::new(future::loop_fn(start, move |cursor| {
+        let clients = clients.clone();
+        let state = state.clone();
+        let progress_path = progress_path.clone();
+        let handle = handle.clone();
+
+        let scan_future = clients[&bucket].make_request(RequestMessage::Scan(cursor, REBALANCE_BATCH_SIZE));
+
+        scan_future.and_then(move |resp| -> Box<Future<Item=Loop<(), u64>, Error=IOError>> {
+            let (logs, next) = match resp {
+                ResponseMessage::Scan(logs, next) => (logs, next),
+                r => return Box::new(future::err(IOError::new(ErrorKind::InvalidData, format!("Unexpected scan response: {:?}", r))))
+            };
+
+            let scanned = logs.len() as u64;
+            let mut batches = HashMap::<u32, Vec<HashMap<String, LogValue>>>::new();
+
+            for log in logs {
+                let owners = match log.get("__id") {
+                    // only the old primary sends the log, so it's only moved once
+                    Some(&LogValue::String(ref id)) if primary_bucket(id, old_buckets) == bucket => new_owners(id, old_buckets, new_buckets),
+                    _ => continue
+                };
+
+                for owner in owners {
+                    batches.entry(owner).or_insert_with(Vec::new).push(log.clone());
+                }
+            }
+
+            // split each owner's batch so every InsertAll fits in a frame
+            let batches = batches.into_iter().flat_map(|(owner, batch)| {
+                frame_chunks(batch, clients[&owner].max_frame_size(), log_size).into_iter().map(move |chunk| (owner, chunk))
+            }).collect::<Vec<_>>();
+
+            let insert_futures = batches.into_iter().map(|(owner, batch)| {
+                clients[&owner].make_request(RequestMessage::InsertAll(batch)).and_then(|resp| {
+                    match resp {
+                        ResponseMessage::InsertResults(results) => Ok(results.into_iter().filter(|r| r.is_ok()).count() as u64),
+                        r => Err(IOError::new(ErrorKind::InvalidData, format!("Unexpected insert response: {:?}", r)))
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            Box::new(future::join_all(insert_futures).and_then(move |moved| -> Box<Future<Item=Loop<(), u64>, Error=IOError>> {
+                {
+                    let mut progress = state.borrow_mut();
+                    let progress = progress.as_mut().unwrap();
+
+                    {
+                        let node = progress.nodes.get_mut(&bucket).unwrap();
+
+                        node.cursor = next.unwrap_or(cursor);
+                        node.scanned += scanned;
+                        node.moved += moved.into_iter().sum::<u64>();
+                        node.done = next.is_none();
+
+                        debug!("Rebalance of node {}: {:?}", bucket, node);
+                    }
+
+                    if let Err(e) = progress.save(&progress_path) {
+                        error!("Unable to save rebalance progress: {}", e);
+                    }
+                }
+
+                match next {
+                    None => Box::new(future::ok(Loop::Break(()))),
+                    Some(n) => {
+                        let delay = future::result(Timeout::new(Duration::from_millis(REBALANCE_BATCH_DELAY_MS), &handle)).flatten();
+
+                        Box::new(delay.map(move |_| Loop::Continue(n)))
+                    }
+                }
+            }))
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use ::cluster::{placement_key, replica_buckets, REPLICATION_FACTOR};
+    use ::data_manager::DataManager;
+    use ::json::json2map;
+    use ::log_value::LogValue;
+    use ::metrics::Metrics;
+    use ::rebalance::{new_owners, start_rebalance, RebalanceProgress, RebalanceState};
+    use ::rpc_codec::DEFAULT_MAX_FRAME_SIZE;
+    use ::rpc_server::{run_rpc_server, RPCClient};
+
+    use futures::sync::oneshot;
+    use tokio_core::reactor::Core;
+
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn owners_change() {
+        // growing a cluster only adds owners that weren't there before
+        for i in 0..100 {
+            let id = format!("id{}", i);
+
+            for owner in new_owners(&id, 3, 4) {
+                assert!(owner < 4);
+            }
+        }
+
+        assert!(new_owners("id", 3, 3).is_empty());
+
+        // shrinking a cluster only adds owners that remain
+        for i in 0..100 {
+            let id = format!("id{}", i);
+
+            for owner in new_owners(&id, 4, 3) {
+                assert!(owner < 3);
+            }
+        }
+    }
+
+    #[test]
+    fn save_load() {
+        let path = Path::new("/tmp/rebalance_test.json");
+        let mut progress = RebalanceProgress::new(2, 3);
+
+        progress.nodes.get_mut(&1).unwrap().cursor = 1234;
+        progress.save(path).unwrap();
+
+        assert_eq!(RebalanceProgress::load(path), Some(progress));
+    }
+
+    /// Inserts logs into their owners in a cluster of `old` nodes, rebalances it to `new` nodes,
+    /// then checks every log is held by each of its owners in the new cluster
+    fn rebalance_cluster(name: &str, first_port: u16, old: u32, new: u32) {
+        let size = old.max(new);
+        let mut core = Core::new().unwrap();
+        let mut dms = Vec::new();
+        let mut servers = Vec::new();
+
+        for b in 0..size {
+            let dir = PathBuf::from(format!("/tmp/{}/{}", name, b));
+
+            remove_dir_all(&dir).ok();
+            create_dir_all(&dir).unwrap();
+
+            let dm = Arc::new(DataManager::new(&dir).unwrap());
+            let server_dm = dm.clone();
+            let addr = format!("127.0.0.1:{}", first_port + b as u16).parse().unwrap();
+            let (stop, stopped) = oneshot::channel::<()>();
+
+            let server = thread::spawn(move || {
+                run_rpc_server(server_dm, addr, None, DEFAULT_MAX_FRAME_SIZE, Arc::new(Metrics::default()), stopped, Duration::from_secs(1)).unwrap();
+            });
+
+            dms.push(dm);
+            servers.push((stop, server));
+        }
+
+        thread::sleep(Duration::from_millis(500)); // let the servers start listening
+
+        let clients = (0..size).map(|b| {
+            (b, RPCClient::new(format!("127.0.0.1:{}", first_port + b as u16), None, DEFAULT_MAX_FRAME_SIZE, &mut core))
+        }).collect::<HashMap<_, _>>();
+
+        let mut ids = Vec::new();
+
+        for i in 0..100 {
+            let log = json2map(&json!({ "n": i }).to_string()).unwrap();
+
+            let id = match log.get("__id") {
+                Some(&LogValue::String(ref id)) => id.to_owned(),
+                _ => panic!("Log without an __id")
+            };
+
+            for b in replica_buckets(placement_key(&id), old, REPLICATION_FACTOR) {
+                dms[b as usize].insert(&log).unwrap();
+            }
+
+            ids.push(id);
+        }
+
+        let handle = core.handle();
+        let state: RebalanceState = Rc::new(RefCell::new(None));
+        let progress_path = PathBuf::from(format!("/tmp/{}/rebalance.json", name));
+
+        start_rebalance(&handle, Rc::new(clients), state.clone(), progress_path, old, new).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        while state.borrow().as_ref().unwrap().running {
+            assert!(Instant::now() < deadline, "The rebalance didn't finish");
+            core.turn(Some(Duration::from_millis(50)));
+        }
+
+        assert!(state.borrow().as_ref().unwrap().is_done());
+
+        for id in &ids {
+            for b in replica_buckets(placement_key(id), new, REPLICATION_FACTOR) {
+                let logs = dms[b as usize].get("__id", &LogValue::String(id.to_owned())).unwrap();
+
+                assert_eq!(logs.len(), 1, "{} is not on node {}", id, b);
+            }
+        }
+
+        for (stop, server) in servers {
+            stop.send(()).ok();
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn grow() {
+        rebalance_cluster("rebalance_grow_test", 24100, 2, 3);
+    }
+
+    #[test]
+    fn shrink() {
+        rebalance_cluster("rebalance_shrink_test", 24200, 3, 2);
+    }
+}
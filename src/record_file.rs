@@ -30,6 +30,7 @@ pub struct RecordFile {
     pub record_count: u32,  // number of records in the file
     pub header_len: usize,  // length of the header
     pub end_of_file: u64,   // end of the file (size) as controlled by RecordFile
+    pub sync: bool,         // sync each append to disk before returning
 }
 
 pub fn buf2string(buf: &[u8]) -> String {
@@ -113,6 +114,7 @@ impl RecordFile {
             record_count,
             header_len: header.len(),
             end_of_file,
+            sync: false,
         })
     }
 
@@ -136,9 +138,52 @@ impl RecordFile {
         self.record_count += 1;
         self.end_of_file += (4 + rec_size) as u64;
 
+        // the header is synced with the record, so the file can be opened again after a crash
+        if self.sync {
+            self.write_header()?;
+            self.fd.sync_data()?;
+        }
+
         Ok(rec_loc)
     }
 
+    /// Appends a batch of records to the end of the file with a single write
+    /// When sync is set, the batch and the header are synced once before returning; otherwise, like a single append, it's left to the OS,
+    /// so a crash can lose the batch or the end of it
+    /// Returns the location where each record was written
+    pub fn append_all(&mut self, records: &[&[u8]]) -> Result<Vec<u64>, IOError> {
+        let start = self.fd.seek(SeekFrom::Start(self.end_of_file))?;
+        let mut buff = Vec::with_capacity(records.iter().map(|r| r.len() + 4).sum());
+        let mut locs = Vec::with_capacity(records.len());
+
+        for record in records {
+            locs.push(start + buff.len() as u64);
+
+            buff.write_u32::<LE>(record.len() as u32)?;
+            buff.extend_from_slice(record);
+        }
+
+        debug!("WROTE {} RECORDS AT {}: {} bytes", records.len(), start, buff.len());
+
+        self.fd.write_all(&buff)?;
+        self.fd.flush()?;
+
+        self.record_count += records.len() as u32;
+        self.end_of_file += buff.len() as u64;
+
+        if self.sync {
+            self.write_header()?;
+            self.fd.sync_data()?;
+        }
+
+        Ok(locs)
+    }
+
+    /// The location of the first record in the file
+    pub fn first_record(&self) -> u64 {
+        (self.header_len + 4 + 8) as u64
+    }
+
     /// Read a record from a given offset
     pub fn read_at(&self, file_offset: u64) -> Result<Vec<u8>, IOError> {
         //        self.fd.seek(SeekFrom::Start(file_offset))?;
@@ -149,6 +194,12 @@ impl RecordFile {
         //        self.fd.read_exact(&mut rec_buff)?;
 
         let rec_size = self.fd.read_u32_at::<LE>(file_offset)?;
+
+        // a location from before the file was rewritten may not be the start of a record
+        if file_offset + 4 + rec_size as u64 > self.end_of_file {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("No record at {} in {}", file_offset, self.file_path.display())));
+        }
+
         let mut rec_buff = vec![0; rec_size as usize];
 
         self.fd.read_exact_at(file_offset + 4, &mut rec_buff)?;
@@ -162,7 +213,21 @@ impl RecordFile {
         Ok(rec_buff)
     }
 
-    /// Temporary until TcpServer can be shutdown
+    /// Writes the record count and end of file to the header, so the file can be opened again if it's never closed
+    pub fn write_header(&mut self) -> Result<(), IOError> {
+        self.fd.seek(SeekFrom::Start(self.header_len as u64))?;
+        self.fd.write_u32::<LE>(self.record_count)?;
+        self.fd.write_u64::<LE>(self.end_of_file)?;
+        self.fd.flush()
+    }
+
+    /// Writes the header, and syncs the file to disk
+    pub fn sync(&mut self) -> Result<(), IOError> {
+        self.write_header()?;
+        self.fd.sync_all()
+    }
+
+    /// Writes the record count and end of file to the header
     pub fn close(&mut self) {
         self.fd.seek(SeekFrom::Start(self.header_len as u64)).unwrap();
         self.fd.write_u32::<LE>(self.record_count).unwrap(); // cannot return an error, so best attempt
@@ -310,18 +375,18 @@ impl<'a> Iterator for MutRecordFileIterator<'a> {
 #[cfg(test)]
 mod tests {
     use record_file::RecordFile;
+    use utils::TestDir;
 
     use simple_logger;
-    use std::path::PathBuf;
-    use std::fs::remove_file;
+    use std::mem::forget;
     use std::io::{Error as IOError, ErrorKind, Read, Seek, SeekFrom, Write};
 
     #[test]
     fn new() {
         simple_logger::init().unwrap(); // this will panic on error
-        remove_file("/tmp/test.data");
+        let dir = TestDir::new("record_new_test");
         let mut rec_file =
-            RecordFile::new(&PathBuf::from("/tmp/test.data"), "ABCD".as_bytes()).unwrap();
+            RecordFile::new(&dir.join("test.data"), "ABCD".as_bytes()).unwrap();
 
         rec_file.fd.seek(SeekFrom::Start(rec_file.end_of_file));
         rec_file.fd.write("TEST".as_bytes());
@@ -330,9 +395,9 @@ mod tests {
     #[test]
     fn append() {
         simple_logger::init().unwrap(); // this will panic on error
-        remove_file("/tmp/test.data");
+        let dir = TestDir::new("record_append_test");
         let mut rec_file =
-            RecordFile::new(&PathBuf::from("/tmp/test.data"), "ABCD".as_bytes()).unwrap();
+            RecordFile::new(&dir.join("test.data"), "ABCD".as_bytes()).unwrap();
 
         // put this here to see if it messes with stuff
         rec_file.fd.seek(SeekFrom::Start(rec_file.end_of_file));
@@ -350,9 +415,9 @@ mod tests {
     #[test]
     fn read_at() {
         simple_logger::init().unwrap(); // this will panic on error
-        remove_file("/tmp/test.data");
+        let dir = TestDir::new("record_read_at_test");
         let mut rec_file =
-            RecordFile::new(&PathBuf::from("/tmp/test.data"), "ABCD".as_bytes()).unwrap();
+            RecordFile::new(&dir.join("test.data"), "ABCD".as_bytes()).unwrap();
         let rec = "THE_RECORD".as_bytes();
 
         rec_file.append(rec).unwrap();
@@ -363,12 +428,52 @@ mod tests {
         assert_eq!(rec, rec_read.as_slice());
     }
 
+    #[test]
+    fn append_all() {
+        simple_logger::init().unwrap(); // this will panic on error
+        let dir = TestDir::new("record_append_all_test");
+        let mut rec_file =
+            RecordFile::new(&dir.join("test_append_all.data"), "ABCD".as_bytes()).unwrap();
+
+        let recs: Vec<&[u8]> = vec!["FIRST".as_bytes(), "SECOND".as_bytes()];
+        let locs = rec_file.append_all(&recs).unwrap();
+
+        assert_eq!(rec_file.record_count, 2);
+        assert_eq!(rec_file.read_at(locs[0]).unwrap().as_slice(), recs[0]);
+        assert_eq!(rec_file.read_at(locs[1]).unwrap().as_slice(), recs[1]);
+    }
+
+    #[test]
+    fn reopen_synced() {
+        simple_logger::init().unwrap(); // this will panic on error
+        let dir = TestDir::new("record_reopen_synced_test");
+        let mut rec_file =
+            RecordFile::new(&dir.join("test_reopen_synced.data"), "ABCD".as_bytes()).unwrap();
+
+        rec_file.sync = true;
+
+        let rec = "THE_RECORD".as_bytes();
+        let recs: Vec<&[u8]> = vec!["FIRST".as_bytes(), "SECOND".as_bytes()];
+
+        rec_file.append(rec).unwrap();
+        rec_file.append_all(&recs).unwrap();
+
+        // skip close() and Drop, as a crash would
+        forget(rec_file);
+
+        let mut rec_file =
+            RecordFile::new(&dir.join("test_reopen_synced.data"), "ABCD".as_bytes()).unwrap();
+
+        assert_eq!(rec_file.record_count, 3);
+        assert_eq!((&mut rec_file).into_iter().collect::<Vec<_>>(), vec![rec.to_vec(), recs[0].to_vec(), recs[1].to_vec()]);
+    }
+
     #[test]
     fn iterate() {
         simple_logger::init().unwrap(); // this will panic on error
-        remove_file("/tmp/test.data");
+        let dir = TestDir::new("record_iterate_test");
         let mut rec_file =
-            RecordFile::new(&PathBuf::from("/tmp/test.data"), "ABCD".as_bytes()).unwrap();
+            RecordFile::new(&dir.join("test.data"), "ABCD".as_bytes()).unwrap();
 
         // put this here to see if it messes with stuff
         rec_file.fd.seek(SeekFrom::Start(rec_file.end_of_file));
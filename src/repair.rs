@@ -0,0 +1,334 @@
+use futures::{future, stream, Future, Stream};
+use tokio_core::reactor::{Handle, Interval};
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{Error as IOError, ErrorKind};
+use std::rc::Rc;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ::cluster::{placement_key, primary_bucket, NodeState, REPLICATION_FACTOR};
+use ::json::get_ts;
+use ::log_value::LogValue;
+use ::rpc_codec::{frame_chunks, log_size, RequestMessage, ResponseMessage};
+use ::rpc_server::RPCClient;
+
+/// Size of each time window that is summarized and compared between replicas
+pub const REPAIR_WINDOW_MS: u64 = 60 * 1000;
+
+/// How far back each periodic repair looks
+pub const REPAIR_LOOKBACK_MS: u64 = 60 * 60 * 1000;
+
+/// How often replicas are repaired
+pub const REPAIR_INTERVAL_SECS: u64 = 10 * 60;
+
+/// Most __ids looked up at once when copying the logs missing from a replica
+const COPY_CONCURRENCY: usize = 16;
+
+/// Most logs sent to a replica in each round of InsertAll requests
+const COPY_BATCH_SIZE: usize = 1000;
+
+/// The logs to compare between replicas: those whose primary is `bucket`, with a __ts in [start, end)
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RepairRange {
+    pub bucket: u32,
+    pub num_buckets: u32,
+    pub start: u64,
+    pub end: u64
+}
+
+/// A hash summary of all the __ids in a single time window
+/// The hash is the XOR of the hashes of each __id, so it's independent of insertion order
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct WindowSummary {
+    pub start: u64,
+    pub hash: u64,
+    pub count: u32
+}
+
+/// The results of repairing a pair of replicas
+#[derive(Clone, Copy, Serialize, Debug, Default, PartialEq)]
+pub struct RepairStats {
+    pub windows_compared: u64,
+    pub windows_repaired: u64,
+    pub logs_sent: u64
+}
+
+/// Filters the (__id, __ts) pairs down to those in the range
+pub fn ids_for_range(ids: Vec<(String, u64)>, range: &RepairRange) -> Vec<(String, u64)> {
+    ids.into_iter()
+        .filter(|&(ref id, ts)| ts >= range.start && ts < range.end && primary_bucket(id, range.num_buckets) == range.bucket)
+        .collect()
+}
+
+/// Summarizes the (__id, __ts) pairs into REPAIR_WINDOW_MS windows
+/// Each __id is counted once, as a copy stored twice would cancel itself out of the hash but not the count
+pub fn summarize(ids: &[(String, u64)]) -> Vec<WindowSummary> {
+    let mut windows = BTreeMap::<u64, WindowSummary>::new();
+    let mut seen = HashSet::new();
+
+    for &(ref id, ts) in ids {
+        if !seen.insert(id) {
+            continue;
+        }
+
+        let start = ts - (ts % REPAIR_WINDOW_MS);
+        let summary = windows.entry(start).or_insert(WindowSummary { start, hash: 0, count: 0 });
+
+        summary.hash ^= placement_key(id);
+        summary.count += 1;
+    }
+
+    windows.into_iter().map(|(_, v)| v).collect()
+}
+
+/// Returns the start of every window that differs between the two summaries
+pub fn diff_summaries(a: &[WindowSummary], b: &[WindowSummary]) -> Vec<u64> {
+    let a_map = a.iter().map(|s| (s.start, s)).collect::<BTreeMap<_, _>>();
+    let b_map = b.iter().map(|s| (s.start, s)).collect::<BTreeMap<_, _>>();
+
+    let mut ret = Vec::new();
+
+    for (start, summary) in a_map.iter() {
+        if b_map.get(start) != Some(summary) {
+            ret.push(*start);
+        }
+    }
+
+    for start in b_map.keys() {
+        if !a_map.contains_key(start) {
+            ret.push(*start);
+        }
+    }
+
+    ret.sort();
+    ret
+}
+
+fn unexpected(resp: ResponseMessage) -> IOError {
+    IOError::new(ErrorKind::InvalidData, format!("Unexpected repair response: {:?}", resp))
+}
+
+/// The client of a node, the ids configured for the nodes may not be 0 to n - 1
+fn client(clients: &HashMap<u32, RPCClient>, id: u32) -> Result<&RPCClient, IOError> {
+    clients.get(&id).ok_or_else(|| IOError::new(ErrorKind::NotFound, format!("No node has the id {}", id)))
+}
+
+fn get_summary(clients: &HashMap<u32, RPCClient>, id: u32, range: RepairRange) -> Box<Future<Item=Vec<WindowSummary>, Error=IOError>> {
+    let client = match client(clients, id) {
+        Ok(c) => c,
+        Err(e) => return Box::new(future::err(e))
+    };
+
+    Box::new(client.make_request(RequestMessage::Summary(range)).and_then(|resp| {
+        match resp {
+            ResponseMessage::Summary(s) => Ok(s),
+            r => Err(unexpected(r))
+        }
+    }))
+}
+
+fn get_ids(clients: &HashMap<u32, RPCClient>, id: u32, range: RepairRange) -> Box<Future<Item=HashSet<String>, Error=IOError>> {
+    let client = match client(clients, id) {
+        Ok(c) => c,
+        Err(e) => return Box::new(future::err(e))
+    };
+
+    Box::new(client.make_request(RequestMessage::Ids(range)).and_then(|resp| {
+        match resp {
+            ResponseMessage::Ids(ids) => Ok(ids.into_iter().collect()),
+            r => Err(unexpected(r))
+        }
+    }))
+}
+
+/// Copies the logs with the given __ids from one node to another, resolving to the number the other node stored
+/// At most COPY_CONCURRENCY __ids are looked up at once, and the logs are sent COPY_BATCH_SIZE at a time
+fn copy_logs(clients: Rc<HashMap<u32, RPCClient>>, from: u32, to: u32, ids: Vec<String>) -> Box<Future<Item=u64, Error=IOError>> {
+    if ids.is_empty() {
+        return Box::new(future::ok(0));
+    }
+
+    debug!("Copying {} logs from {} to {}", ids.len(), from, to);
+
+    let get_clients = clients.clone();
+
+    let copied = stream::iter_ok(ids)
+        .map(move |id| -> Box<Future<Item=Vec<HashMap<String, LogValue>>, Error=IOError>> {
+            match client(&get_clients, from) {
+                Ok(c) => c.get_all(String::from("__id"), LogValue::String(id)),
+                Err(e) => Box::new(future::err(e))
+            }
+        })
+        .buffer_unordered(COPY_CONCURRENCY)
+        .map(stream::iter_ok::<_, IOError>)
+        .flatten()
+        .chunks(COPY_BATCH_SIZE)
+        .and_then(move |batch| insert_logs(&clients, to, batch))
+        .fold(0, |total, stored| future::ok::<u64, IOError>(total + stored));
+
+    Box::new(copied)
+}
+
+/// Sends the logs to a node with InsertAll, a frame at a time, resolving to the number it stored
+fn insert_logs(clients: &HashMap<u32, RPCClient>, to: u32, logs: Vec<HashMap<String, LogValue>>) -> Box<Future<Item=u64, Error=IOError>> {
+    let to_client = match client(clients, to) {
+        Ok(c) => c,
+        Err(e) => return Box::new(future::err(e))
+    };
+
+    let insert_futures = frame_chunks(logs, to_client.max_frame_size(), log_size).into_iter().map(|batch| {
+        to_client.make_request(RequestMessage::InsertAll(batch)).and_then(move |resp| {
+            match resp {
+                ResponseMessage::InsertResults(results) => Ok(results.into_iter().filter(|r| r.is_ok()).count() as u64),
+                ResponseMessage::Error { message, .. } => {
+                    warn!("Node {} refused repaired logs: {}", to, message);
+                    Ok(0)
+                },
+                r => Err(unexpected(r))
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    Box::new(future::join_all(insert_futures).map(|stored| stored.into_iter().sum()))
+}
+
+/// Repairs the logs in a single window, copying the missing logs to each side
+fn repair_window(clients: Rc<HashMap<u32, RPCClient>>, a: u32, b: u32, range: RepairRange) -> Box<Future<Item=u64, Error=IOError>> {
+    let ids_future = get_ids(&clients, a, range).join(get_ids(&clients, b, range));
+
+    Box::new(ids_future.and_then(move |(a_ids, b_ids)| {
+        let missing_on_b = a_ids.difference(&b_ids).cloned().collect::<Vec<_>>();
+        let missing_on_a = b_ids.difference(&a_ids).cloned().collect::<Vec<_>>();
+
+        copy_logs(clients.clone(), a, b, missing_on_b)
+            .join(copy_logs(clients, b, a, missing_on_a))
+            .map(|(to_b, to_a)| to_b + to_a)
+    }))
+}
+
+/// Compares the logs held by nodes a and b in the range, copying the missing logs to each side
+pub fn repair_pair(clients: Rc<HashMap<u32, RPCClient>>, a: u32, b: u32, range: RepairRange) -> Box<Future<Item=RepairStats, Error=IOError>> {
+    let summary_future = get_summary(&clients, a, range).join(get_summary(&clients, b, range));
+
+    Box::new(summary_future.and_then(move |(a_summary, b_summary)| {
+        let windows = diff_summaries(&a_summary, &b_summary);
+        let windows_compared = a_summary.iter().chain(b_summary.iter()).map(|s| s.start).collect::<BTreeSet<_>>().len() as u64;
+
+        if !windows.is_empty() {
+            info!("Replicas {} and {} differ in {} windows for bucket {}", a, b, windows.len(), range.bucket);
+        }
+
+        let repair_futures = windows.iter().map(|start| {
+            let window = RepairRange { start: *start, end: start + REPAIR_WINDOW_MS, ..range };
+
+            repair_window(clients.clone(), a, b, window)
+        }).collect::<Vec<_>>();
+
+        let windows_repaired = windows.len() as u64;
+
+        future::join_all(repair_futures).map(move |sent| {
+            RepairStats {
+                windows_compared,
+                windows_repaired,
+                logs_sent: sent.into_iter().sum()
+            }
+        })
+    }))
+}
+
+/// Repairs every live replica against its primary for the range [start, end)
+/// A bucket with no node of that id is skipped, as is a replica that isn't up
+pub fn repair_all(clients: Rc<HashMap<u32, RPCClient>>, start: u64, end: u64) -> Box<Future<Item=RepairStats, Error=IOError>> {
+    let num_buckets = clients.len() as u32;
+    let count = if REPLICATION_FACTOR < num_buckets { REPLICATION_FACTOR } else { num_buckets };
+    let mut repair_futures = Vec::new();
+
+    for bucket in 0..num_buckets {
+        let range = RepairRange { bucket, num_buckets, start, end };
+
+        for i in 1..count {
+            let replica = (bucket + i) % num_buckets;
+
+            let states = match (clients.get(&bucket), clients.get(&replica)) {
+                (Some(p), Some(r)) => (p.state(), r.state()),
+                _ => {
+                    warn!("Skipping repair of bucket {} between {} and {}: no node has one of the ids", bucket, bucket, replica);
+                    continue;
+                }
+            };
+
+            if states != (NodeState::Up, NodeState::Up) {
+                debug!("Skipping repair of bucket {} between {} and {}", bucket, bucket, replica);
+                continue;
+            }
+
+            repair_futures.push(repair_pair(clients.clone(), bucket, replica, range));
+        }
+    }
+
+    Box::new(future::join_all(repair_futures).map(|stats| {
+        stats.into_iter().fold(RepairStats::default(), |acc, s| {
+            RepairStats {
+                windows_compared: acc.windows_compared + s.windows_compared,
+                windows_repaired: acc.windows_repaired + s.windows_repaired,
+                logs_sent: acc.logs_sent + s.logs_sent
+            }
+        })
+    }))
+}
+
+/// Periodically repairs the last REPAIR_LOOKBACK_MS of logs between replicas
+pub fn start_repair(handle: &Handle, clients: Rc<HashMap<u32, RPCClient>>) {
+    let interval = Interval::new(Duration::from_secs(REPAIR_INTERVAL_SECS), handle).unwrap();
+    let repair_handle = handle.clone();
+
+    handle.spawn(
+        interval
+            .for_each(move |_| {
+                let end = get_ts();
+
+                repair_handle.spawn(
+                    repair_all(clients.clone(), end - REPAIR_LOOKBACK_MS, end)
+                        .map(|stats| info!("Repair finished: {:?}", stats))
+                        .map_err(|e| error!("Repair failed: {}", e)),
+                );
+
+                Ok(())
+            })
+            .map_err(|e| error!("Repair timer failed: {}", e)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use ::repair::{summarize, diff_summaries, REPAIR_WINDOW_MS};
+
+    #[test]
+    fn summary_order_independent() {
+        let a = vec![(String::from("a"), 10), (String::from("b"), 20)];
+        let b = vec![(String::from("b"), 20), (String::from("a"), 10)];
+
+        assert_eq!(summarize(&a), summarize(&b));
+        assert_eq!(summarize(&a).len(), 1);
+    }
+
+    #[test]
+    fn diff_finds_missing() {
+        let a = vec![(String::from("a"), 10), (String::from("b"), REPAIR_WINDOW_MS + 20)];
+        let b = vec![(String::from("a"), 10)];
+
+        assert_eq!(diff_summaries(&summarize(&a), &summarize(&b)), vec![REPAIR_WINDOW_MS]);
+        assert_eq!(diff_summaries(&summarize(&b), &summarize(&a)), vec![REPAIR_WINDOW_MS]);
+        assert!(diff_summaries(&summarize(&a), &summarize(&a)).is_empty());
+    }
+
+    #[test]
+    fn summary_ignores_duplicates() {
+        let a = vec![(String::from("a"), 10), (String::from("b"), 20)];
+        let b = vec![(String::from("a"), 10), (String::from("b"), 20), (String::from("b"), 20)];
+
+        assert_eq!(summarize(&a), summarize(&b));
+        assert_eq!(summarize(&b)[0].count, 2);
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::Error as IOError;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use ::data_manager::DataManager;
+use ::json::get_ts;
+
+/// How often the logs past the retention period are looked for
+const RETENTION_CHECK_SECS: u64 = 60 * 60;
+
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Removes the logs older than the retention period on a background thread,
+/// when it starts and every RETENTION_CHECK_SECS after that
+pub struct Retention {
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<()>
+}
+
+impl Retention {
+    /// Starts the thread, keeping the logs with a __ts within the last `days`
+    pub fn start(dm: Arc<DataManager>, days: u64) -> Result<Retention, IOError> {
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread_stopping = stopping.clone();
+
+        let thread = thread::Builder::new()
+            .name("retention".to_string())
+            .spawn(move || {
+                while !thread_stopping.load(Ordering::SeqCst) {
+                    let cutoff = get_ts().saturating_sub(days * MS_PER_DAY);
+
+                    match dm.expire(cutoff) {
+                        Ok(0) => debug!("No logs older than {} days", days),
+                        Ok(count) => info!("Removed {} logs older than {} days", count, days),
+                        Err(e) => error!("Unable to remove logs older than {} days: {}", days, e.to_string())
+                    }
+
+                    thread::park_timeout(Duration::from_secs(RETENTION_CHECK_SECS));
+                }
+            })?;
+
+        Ok(Retention { stopping, thread })
+    }
+
+    /// Stops the thread, waiting for logs being removed to finish
+    pub fn stop(self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.thread.thread().unpark();
+
+        if self.thread.join().is_err() {
+            error!("Retention thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::data_manager::DataManager;
+    use ::json::{get_ts, json2map};
+    use ::log_value::LogValue;
+    use ::retention::{Retention, MS_PER_DAY};
+
+    use serde_json::Number;
+
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn removes_old_logs() {
+        let dir = Path::new("/tmp/retention_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = Arc::new(DataManager::new(dir).unwrap());
+
+        for days in 0..4 {
+            let mut log = json2map(&json!({ "host": "localhost", "days": days }).to_string()).unwrap();
+
+            log.insert(String::from("__ts"), LogValue::Number(Number::from(get_ts() - days * MS_PER_DAY - 1000)));
+            dm.insert(&log).unwrap();
+        }
+
+        let retention = Retention::start(dm.clone(), 2).unwrap();
+
+        for _ in 0..40 {
+            if dm.stats().unwrap().logs == 2 {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        retention.stop();
+
+        // the logs from today and yesterday are kept
+        assert_eq!(dm.stats().unwrap().logs, 2);
+        assert_eq!(dm.get("host", &LogValue::String(String::from("localhost"))).unwrap().len(), 2);
+
+        drop(dm);
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -7,6 +7,7 @@ use bytes::BytesMut;
 use bytes::IntoBuf;
 use bytes::Buf;
 use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use lz4::block::{compress, decompress};
 use rmps::decode::from_slice;
 use rmps::encode::to_vec;
 
@@ -15,21 +16,196 @@ use std::marker::PhantomData;
 use std::collections::HashMap;
 
 use ::log_value::LogValue;
+use ::data_manager::StorageStats;
+use ::mapping::{Mapping, MappingUpdate};
+use ::query::Query;
+use ::repair::{RepairRange, WindowSummary};
+
+/// Magic bytes at the start of every handshake
+pub const HANDSHAKE_MAGIC: &[u8; 4] = b"LSRP";
+
+/// Length of a handshake on the wire
+pub const HANDSHAKE_LEN: usize = 12;
+
+/// The range of protocol versions this node can speak
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+pub const MAX_PROTOCOL_VERSION: u16 = 1;
+
+/// Capability bit: frames may be LZ4 compressed
+pub const CAP_COMPRESSION: u32 = 0x1;
+
+/// The capabilities this node supports, a bit set
+pub const CAPABILITIES: u32 = CAP_COMPRESSION;
+
+/// Bit set in a frame's length prefix when its payload is compressed
+const COMPRESSED_FLAG: u32 = 0x8000_0000;
+
+/// Payloads smaller than this are not worth compressing
+pub const MIN_COMPRESS_SIZE: usize = 512;
+
+/// Exchanged by both sides when a connection is opened, before any messages
+/// The over the wire format is the magic bytes, the min and max versions as Little Endian u16s,
+/// followed by the capabilities as a Little Endian u32
+/// The server replies with the agreed version as both min and max, and the capabilities both sides support
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Handshake {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub capabilities: u32
+}
+
+impl Handshake {
+    /// The handshake describing this node
+    pub fn ours() -> Handshake {
+        Handshake {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: MAX_PROTOCOL_VERSION,
+            capabilities: CAPABILITIES
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0; HANDSHAKE_LEN];
+
+        buf[0..4].copy_from_slice(HANDSHAKE_MAGIC);
+
+        {
+            let mut cursor = Cursor::new(&mut buf[4..]);
+
+            // cannot fail, the buffer is the correct size
+            cursor.write_u16::<LE>(self.min_version).unwrap();
+            cursor.write_u16::<LE>(self.max_version).unwrap();
+            cursor.write_u32::<LE>(self.capabilities).unwrap();
+        }
+
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> IOResult<Handshake> {
+        if buf.len() != HANDSHAKE_LEN || &buf[0..4] != HANDSHAKE_MAGIC {
+            return Err(IOError::new(ErrorKind::InvalidData, "Peer is not a logstore node; invalid handshake"));
+        }
+
+        let mut cursor = Cursor::new(&buf[4..]);
+
+        Ok(Handshake {
+            min_version: cursor.read_u16::<LE>()?,
+            max_version: cursor.read_u16::<LE>()?,
+            capabilities: cursor.read_u32::<LE>()?
+        })
+    }
+
+    /// Picks the highest version both sides support, and the capabilities both sides have
+    pub fn negotiate(&self, peer: &Handshake) -> IOResult<Handshake> {
+        let version = if self.max_version < peer.max_version { self.max_version } else { peer.max_version };
+        let min_version = if self.min_version > peer.min_version { self.min_version } else { peer.min_version };
+
+        if version < min_version {
+            return Err(IOError::new(ErrorKind::InvalidData, format!(
+                "Incompatible protocol version: peer supports {}-{}, this node supports {}-{}",
+                peer.min_version, peer.max_version, self.min_version, self.max_version
+            )));
+        }
+
+        Ok(Handshake {
+            min_version: version,
+            max_version: version,
+            capabilities: self.capabilities & peer.capabilities
+        })
+    }
+
+    pub fn has(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+}
+
+/// The default largest payload accepted or sent in a single frame, 16 MiB
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Space reserved in each frame for the message wrapping a list of logs
+pub const FRAME_OVERHEAD: usize = 1024;
+
+/// The smallest max frame size a node can be configured with, 64 KiB
+pub const MIN_MAX_FRAME_SIZE: usize = 64 * 1024;
 
 pub struct LengthPrefixedMessage<Recv, Send> {
+    max_frame_size: usize,
+    compression: bool, // set when both sides agreed to CAP_COMPRESSION
     _recv: PhantomData<Recv>,
     _send: PhantomData<Send>
 }
 
 impl <Recv, Send> LengthPrefixedMessage<Recv, Send> {
     pub fn new() -> LengthPrefixedMessage<Recv, Send> {
-        LengthPrefixedMessage{_recv: PhantomData, _send: PhantomData}
+        LengthPrefixedMessage::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> LengthPrefixedMessage<Recv, Send> {
+        LengthPrefixedMessage{max_frame_size, compression: false, _recv: PhantomData, _send: PhantomData}
+    }
+
+    /// Enables compressing the frames sent, and accepting compressed frames
+    pub fn with_compression(mut self, compression: bool) -> LengthPrefixedMessage<Recv, Send> {
+        self.compression = compression;
+        self
+    }
+
+    /// Decompresses a compressed payload, refusing to inflate it beyond the maximum frame size
+    fn decompress(&self, payload: &[u8]) -> IOResult<Vec<u8>> {
+        if payload.len() < 4 {
+            return Err(IOError::new(ErrorKind::InvalidData, "Compressed frame is missing its size"));
+        }
+
+        let msg_size = Cursor::new(&payload[0..4]).read_u32::<LE>()?;
+
+        if msg_size as usize > self.max_frame_size {
+            return Err(IOError::new(ErrorKind::InvalidData, format!(
+                "Compressed frame of {} bytes exceeds the maximum frame size of {} bytes", msg_size, self.max_frame_size
+            )));
+        }
+
+        decompress(&payload[4..], Some(msg_size as i32))
     }
 }
 
+/// Splits items into chunks whose total size, as measured by `size`, fits in a frame
+/// An item that is too large on its own is placed in a chunk by itself
+pub fn frame_chunks<T, F>(items: Vec<T>, max_frame_size: usize, size: F) -> Vec<Vec<T>> where F: Fn(&T) -> usize {
+    let budget = max_frame_size.saturating_sub(FRAME_OVERHEAD);
+    let mut ret = Vec::new();
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0;
+
+    for item in items {
+        let item_size = size(&item);
+
+        if !chunk.is_empty() && chunk_size + item_size > budget {
+            ret.push(chunk);
+            chunk = Vec::new();
+            chunk_size = 0;
+        }
+
+        chunk_size += item_size;
+        chunk.push(item);
+    }
+
+    if !chunk.is_empty() {
+        ret.push(chunk);
+    }
+
+    ret
+}
+
+/// The size of a log once serialized
+pub fn log_size(log: &HashMap<String, LogValue>) -> usize {
+    to_vec(log).map(|v| v.len()).unwrap_or(0)
+}
+
 // `LengthPrefixedMessage` is a codec for sending and receiving MessagePack serializable types. The
 // over the wire format is a Little Endian u32 indicating the number of bytes in the payload
 // (not including the 4 u32 bytes themselves) followed by the MessagePack payload.
+// When compression was negotiated the high bit of the size may be set, in which case the payload
+// is a Little Endian u32 of the MessagePack size, followed by the LZ4 compressed MessagePack.
 impl<Recv, Send> Decoder for LengthPrefixedMessage<Recv, Send> where for<'de> Recv: Deserialize<'de> {
     type Item = Recv;
     type Error = IOError;
@@ -47,15 +223,29 @@ impl<Recv, Send> Decoder for LengthPrefixedMessage<Recv, Send> where for<'de> Re
         let mut cursor = Cursor::new(size_buf);
 
         // read in the size, indicate we need more bytes if it fails
-        let msg_size = match cursor.read_u32::<LE>() {
-            Ok(msg_size) => msg_size,
+        let header = match cursor.read_u32::<LE>() {
+            Ok(header) => header,
             Err(_) => return Ok(None),
         };
 
-        debug!("DECODE: SIZE: {} + 4\tBUF LEN: {}", msg_size, buf.len());
+        let compressed = header & COMPRESSED_FLAG != 0;
+        let msg_size = header & !COMPRESSED_FLAG;
+
+        if compressed && !self.compression {
+            return Err(IOError::new(ErrorKind::InvalidData, "Received a compressed frame without negotiating compression"));
+        }
+
+        debug!("DECODE: SIZE: {} + 4\tBUF LEN: {}\tCOMPRESSED: {}", msg_size, buf.len(), compressed);
+
+        // refuse to buffer a frame larger than we allow
+        if msg_size as usize > self.max_frame_size {
+            return Err(IOError::new(ErrorKind::InvalidData, format!(
+                "Frame of {} bytes exceeds the maximum frame size of {} bytes", msg_size, self.max_frame_size
+            )));
+        }
 
         // Make sure our buffer has all the bytes indicated by msg_size + 4 bytes for the size
-        if buf.len() < (msg_size + 4) as usize {
+        if buf.len() < msg_size as usize + 4 {
             debug!("INDICATING WE NEED MORE BYTES");
             return Ok(None);
         }
@@ -66,6 +256,14 @@ impl<Recv, Send> Decoder for LengthPrefixedMessage<Recv, Send> where for<'de> Re
 
         debug!("GOT BUFFER OF SIZE: {}", msg_buf.len());
 
+        if compressed {
+            let msg_bytes = self.decompress(&msg_buf[..])?;
+
+            let ret: Recv = from_slice(&msg_bytes[..]).map_err(|err| IOError::new(ErrorKind::InvalidData, err))?;
+
+            return Ok(Some(ret));
+        }
+
         let ret: Recv = from_slice(&msg_buf[..]).map_err(|err| IOError::new(ErrorKind::InvalidData, err))?;
 
         Ok(Some(ret))
@@ -77,33 +275,93 @@ impl<Recv, Send> Encoder for LengthPrefixedMessage<Recv, Send> where Send: Seria
     type Error = IOError;
 
     fn encode(&mut self, msg: Send, buf: &mut BytesMut) -> IOResult<()> {
-        let msg_bytes = to_vec(&msg).unwrap();
-        let msg_size = msg_bytes.len() as u32;
+        let msg_bytes = to_vec(&msg).map_err(|err| IOError::new(ErrorKind::InvalidData, err))?;
+
+        // the peer would refuse this frame, so don't send it
+        if msg_bytes.len() > self.max_frame_size {
+            return Err(IOError::new(ErrorKind::InvalidData, format!(
+                "Message of {} bytes exceeds the maximum frame size of {} bytes", msg_bytes.len(), self.max_frame_size
+            )));
+        }
+
+        // only send the compressed payload when it's actually smaller
+        let compressed = if self.compression && msg_bytes.len() >= MIN_COMPRESS_SIZE {
+            let mut payload = vec![];
+
+            payload.write_u32::<LE>(msg_bytes.len() as u32)?;
+            payload.extend_from_slice(&compress(&msg_bytes, None, false)?);
+
+            if payload.len() < msg_bytes.len() { Some(payload) } else { None }
+        } else {
+            None
+        };
+
+        let (payload, flag) = match compressed {
+            Some(ref p) => (&p[..], COMPRESSED_FLAG),
+            None => (&msg_bytes[..], 0)
+        };
+
+        let msg_size = payload.len() as u32;
         let mut msg_size_buf = vec![];
 
-        debug!("ENCODE SIZE: {}", msg_size);
+        debug!("ENCODE SIZE: {}\tUNCOMPRESSED: {}", msg_size, msg_bytes.len());
 
-        msg_size_buf.write_u32::<LE>(msg_size)?;
+        msg_size_buf.write_u32::<LE>(msg_size | flag)?;
 
         buf.extend_from_slice(&msg_size_buf);
-        buf.extend_from_slice(&msg_bytes);
+        buf.extend_from_slice(payload);
 
         Ok( () )
     }
 }
 
+/// Sent with the last page of a query's results
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct QuerySummary {
+    pub total: u32 // number of logs that matched
+}
+
+/// The class of error in an Error response, so clients can decide how to handle it
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ErrorCode {
+    Validation, // the request itself is bad, and will fail again
+    Storage,    // the node was unable to read or write its files
+    Overload,   // the node is too busy to handle the request right now
+    Internal    // anything else
+}
+
 // create the two message enums
+// a variant is sent as its index, so new variants are only ever added at the end, never inserted or reordered,
+// or nodes speaking the same protocol version decode each other's messages as the wrong variants
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub enum RequestMessage {
     Insert(HashMap<String, LogValue>),
-    //    InsertAll(Vec<HashMap<String, LogValue>>),
-    Get(String, LogValue)
+    InsertAll(Vec<HashMap<String, LogValue>>),
+    Get(String, LogValue),
+    Ping, // heartbeat
+    Summary(RepairRange), // hash summary of the __ids in the range
+    Ids(RepairRange), // all of the __ids in the range
+    Scan(u64, u32), // read logs in file order from a location, at most a count
+    GetFrom(String, LogValue, u32), // Get, skipping the first n logs
+    Stats, // the state of the node's log file and indices
+    PutMapping(MappingUpdate), // change the mapping applied to logs inserted on the node
+    Search(Query, u32) // like GetFrom, for any query
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub enum ResponseMessage {
-    Ok, // response to Insert and InsertAll
-    Logs(Vec<HashMap<String, LogValue>>) // response to Get
+    Ok, // response to Insert
+    InsertResults(Vec<Result<(), String>>), // response to InsertAll, the result of inserting each log
+    Logs(Vec<HashMap<String, LogValue>>), // response to Get
+    Pong, // response to Ping
+    Summary(Vec<WindowSummary>), // response to Summary
+    Ids(Vec<String>), // response to Ids
+    Scan(Vec<HashMap<String, LogValue>>, Option<u64>), // response to Scan, with the location to continue from
+    Error { code: ErrorCode, message: String, retryable: bool }, // response to any request that failed
+    LogsPage(Vec<HashMap<String, LogValue>>, u32), // response to Get or GetFrom when more logs remain, with the offset of the next page
+    LogsDone(Vec<HashMap<String, LogValue>>, QuerySummary), // the last page of a response to GetFrom or Search
+    Stats(StorageStats), // response to Stats
+    Mapping(Mapping) // response to PutMapping, the node's new mapping
 }
 
 
@@ -114,17 +372,98 @@ pub type ClientCodec = LengthPrefixedMessage<ResponseMessage, RequestMessage>;
 
 #[cfg(test)]
 mod tests {
-    use ::rpc_codec::{RequestMessage, ResponseMessage};
-    use ::rpc_codec::{ServerCodec, ClientCodec};
+    use ::rpc_codec::{RequestMessage, ResponseMessage, ErrorCode, QuerySummary};
+    use ::rpc_codec::{ServerCodec, ClientCodec, Handshake, frame_chunks, FRAME_OVERHEAD, MIN_COMPRESS_SIZE};
     use ::log_value::LogValue;
+    use ::data_manager::StorageStats;
+    use ::mapping::{Mapping, MappingUpdate};
+    use ::query::Query;
+    use ::repair::RepairRange;
 
     use byteorder::{LE, ReadBytesExt, WriteBytesExt};
     use bytes::BytesMut;
     use std::io::Cursor;
     use rmps::encode::to_vec;
     use std::io::Write;
+    use std::collections::{BTreeMap, HashMap};
+    use serde::Serialize;
     use tokio_io::codec::{Decoder, Encoder};
 
+    /// The index a message is sent as, the first element of the array rmp-serde writes for a variant
+    fn variant_index<T: Serialize>(msg: &T) -> u8 {
+        let buff = to_vec(msg).unwrap();
+
+        assert_eq!(buff[0], 0x92); // fixarray of the index and the fields
+
+        buff[1]
+    }
+
+    #[test]
+    fn variant_indices() {
+        let range = RepairRange { bucket: 0, num_buckets: 1, start: 0, end: 1 };
+        let value = LogValue::String(String::from("value"));
+
+        let requests = vec![
+            RequestMessage::Insert(HashMap::new()),
+            RequestMessage::InsertAll(Vec::new()),
+            RequestMessage::Get(String::from("key"), value.clone()),
+            RequestMessage::Ping,
+            RequestMessage::Summary(range),
+            RequestMessage::Ids(range),
+            RequestMessage::Scan(0, 1),
+            RequestMessage::GetFrom(String::from("key"), value.clone(), 1),
+            RequestMessage::Stats,
+            RequestMessage::PutMapping(MappingUpdate { dynamic: None, properties: BTreeMap::new() }),
+            RequestMessage::Search(Query::Term(String::from("key"), value), 1)
+        ];
+
+        let stats = StorageStats { logs: 0, log_file_size: 0, mem_postings: 0, indices: Vec::new(), logical_indices: Vec::new() };
+
+        let responses = vec![
+            ResponseMessage::Ok,
+            ResponseMessage::InsertResults(Vec::new()),
+            ResponseMessage::Logs(Vec::new()),
+            ResponseMessage::Pong,
+            ResponseMessage::Summary(Vec::new()),
+            ResponseMessage::Ids(Vec::new()),
+            ResponseMessage::Scan(Vec::new(), None),
+            ResponseMessage::Error { code: ErrorCode::Internal, message: String::new(), retryable: false },
+            ResponseMessage::LogsPage(Vec::new(), 1),
+            ResponseMessage::LogsDone(Vec::new(), QuerySummary { total: 0 }),
+            ResponseMessage::Stats(stats),
+            ResponseMessage::Mapping(Mapping::default())
+        ];
+
+        // every variant keeps the index it was first sent as
+        for (i, request) in requests.iter().enumerate() {
+            assert_eq!(variant_index(request) as usize, i, "{:?}", request);
+        }
+
+        for (i, response) in responses.iter().enumerate() {
+            assert_eq!(variant_index(response) as usize, i, "{:?}", response);
+        }
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let ours = Handshake::ours();
+
+        assert_eq!(Handshake::from_bytes(&ours.to_bytes()).unwrap(), ours);
+        assert!(Handshake::from_bytes(b"HTTP/1.1 200").is_err());
+    }
+
+    #[test]
+    fn handshake_negotiate() {
+        let ours = Handshake { min_version: 1, max_version: 3, capabilities: 0b011 };
+        let peer = Handshake { min_version: 2, max_version: 4, capabilities: 0b110 };
+
+        assert_eq!(ours.negotiate(&peer).unwrap(), Handshake { min_version: 3, max_version: 3, capabilities: 0b010 });
+
+        let old = Handshake { min_version: 4, max_version: 5, capabilities: 0 };
+
+        assert!(ours.negotiate(&old).is_err());
+    }
+
     #[test]
     fn decode_server_test() {
         let buff = Vec::new();
@@ -181,6 +520,95 @@ mod tests {
         };
     }
 
+    #[test]
+    fn insert_all_round_trip() {
+        let mut log = HashMap::new();
+
+        log.insert(String::from("hello"), LogValue::String(String::from("world")));
+
+        let request = RequestMessage::InsertAll(vec![log.clone(), log]);
+        let mut byte_mut = BytesMut::new();
+
+        ClientCodec::new().encode(request.clone(), &mut byte_mut).unwrap();
+
+        assert_eq!(ServerCodec::new().decode(&mut byte_mut).unwrap(), Some(request));
+
+        let response = ResponseMessage::InsertResults(vec![Ok(()), Err(String::from("bad log"))]);
+
+        ServerCodec::new().encode(response.clone(), &mut byte_mut).unwrap();
+
+        assert_eq!(ClientCodec::new().decode(&mut byte_mut).unwrap(), Some(response));
+    }
+
+    #[test]
+    fn error_round_trip() {
+        let response = ResponseMessage::Error {
+            code: ErrorCode::Storage,
+            message: String::from("disk full"),
+            retryable: false
+        };
+
+        let mut byte_mut = BytesMut::new();
+
+        ServerCodec::new().encode(response.clone(), &mut byte_mut).unwrap();
+
+        assert_eq!(ClientCodec::new().decode(&mut byte_mut).unwrap(), Some(response));
+    }
+
+    #[test]
+    fn frame_size_enforced() {
+        let request = RequestMessage::Get(String::from("hello"), LogValue::String(String::from("world")));
+        let mut byte_mut = BytesMut::new();
+
+        assert!(ClientCodec::with_max_frame_size(4).encode(request.clone(), &mut byte_mut).is_err());
+        assert_eq!(byte_mut.len(), 0);
+
+        ClientCodec::new().encode(request, &mut byte_mut).unwrap();
+
+        assert!(ServerCodec::with_max_frame_size(4).decode(&mut byte_mut).is_err());
+
+        // a huge length is rejected before waiting for the bytes
+        let mut huge = BytesMut::new();
+        huge.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        assert!(ServerCodec::new().decode(&mut huge).is_err());
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let mut log = HashMap::new();
+
+        log.insert(String::from("message"), LogValue::String("x".repeat(MIN_COMPRESS_SIZE * 4)));
+
+        let request = RequestMessage::InsertAll(vec![log.clone(), log]);
+        let uncompressed_len = to_vec(&request).unwrap().len();
+        let mut byte_mut = BytesMut::new();
+
+        ClientCodec::new().with_compression(true).encode(request.clone(), &mut byte_mut).unwrap();
+
+        assert!(byte_mut.len() < uncompressed_len);
+        assert_ne!(byte_mut[3] & 0x80, 0); // the compressed flag is set
+
+        // a peer that didn't agree to compression refuses the frame
+        assert!(ServerCodec::new().decode(&mut byte_mut.clone()).is_err());
+
+        assert_eq!(ServerCodec::new().with_compression(true).decode(&mut byte_mut).unwrap(), Some(request));
+        assert_eq!(byte_mut.len(), 0);
+
+        // small messages are sent as is
+        ServerCodec::new().with_compression(true).encode(ResponseMessage::Ok, &mut byte_mut).unwrap();
+
+        assert_eq!(byte_mut[3] & 0x80, 0);
+        assert_eq!(ClientCodec::new().decode(&mut byte_mut).unwrap(), Some(ResponseMessage::Ok));
+    }
+
+    #[test]
+    fn chunks_fit() {
+        let chunks = frame_chunks(vec![400, 400, 400, 2000], FRAME_OVERHEAD + 1000, |i| *i);
+
+        assert_eq!(chunks, vec![vec![400, 400], vec![400], vec![2000]]);
+    }
+
     #[test]
     fn encode_server_test() {
         let response = ResponseMessage::Ok;
@@ -1,36 +1,229 @@
 use std::io::{Error as IOError, ErrorKind};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::boxed::Box;
-
-use tokio_core::reactor::Core;
-use tokio_core::net::TcpStream;
-use tokio_io::{AsyncRead, AsyncWrite};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_io::AsyncRead;
 use tokio_io::codec::Framed;
-use tokio_proto::{TcpClient, TcpServer};
+use tokio_io::io::{read_exact, write_all};
+use tokio_proto::{BindServer, TcpClient};
 use tokio_proto::pipeline::{ClientService, ClientProto, ServerProto};
 use tokio_service::Service;
-use futures::{future, Future};
+use futures::{future, Future, Stream};
+use futures::future::{Either, Loop};
+use futures::stream;
+use futures_cpupool::{Builder, CpuPool};
+
+use cluster::{NodeHealth, NodeState, HEARTBEAT_INTERVAL_SECS, HEARTBEAT_TIMEOUT_SECS};
+use data_manager::{DataManager, StorageStats};
+use hint_file::{HintFile, store_hint};
+use log_value::LogValue;
+use mapping::{Mapping, MappingUpdate};
+use query::Query;
+use metrics::{Gauge, GaugeGuard, Metrics};
+use record_error::RecordError;
+use repair::{ids_for_range, summarize};
+use rpc_codec::{ClientCodec, ServerCodec, Handshake, HANDSHAKE_LEN, CAP_COMPRESSION};
+use rpc_codec::{frame_chunks, log_size, FRAME_OVERHEAD};
+use rpc_codec::{RequestMessage, ResponseMessage, ErrorCode, QuerySummary};
+use shutdown::Drain;
+use tls::{self, RpcStream, RpcTls};
+
+/// Most logs returned in a single page of a query
+const QUERY_PAGE_SIZE: usize = 1000;
+
+/// Most queries whose results are kept for the pages that follow
+const MAX_CURSORS: usize = 64;
+
+/// Results that haven't been paged through for this long are dropped
+const CURSOR_IDLE_SECS: u64 = 60;
+
+/// The locations matching the queries being paged through, shared by every connection,
+/// so a query is evaluated for its first page rather than again for every page.
+/// Logs are only appended, so a kept result is a prefix of the current one and an offset into it stays valid,
+/// until logs are expired and the rest move, so a result is only used with the DataManager generation it came from
+#[derive(Default)]
+pub struct QueryCursors {
+    results: HashMap<Query, (Arc<Vec<u64>>, usize, Instant)>
+}
+
+impl QueryCursors {
+    pub fn new() -> QueryCursors {
+        QueryCursors::default()
+    }
+
+    /// Returns the kept result of the query, if it's from the generation and has at least offset locations
+    fn get(&mut self, query: &Query, offset: u32, generation: usize) -> Option<Arc<Vec<u64>>> {
+        let entry = self.results.get_mut(query)?;
+
+        if entry.0.len() < offset as usize || entry.1 != generation {
+            return None;
+        }
+
+        entry.2 = Instant::now();
+
+        Some(entry.0.clone())
+    }
+
+    /// Keeps the result of the query, dropping idle results and then the least recently used beyond MAX_CURSORS
+    fn insert(&mut self, query: &Query, locs: Arc<Vec<u64>>, generation: usize) {
+        let idle = Duration::from_secs(CURSOR_IDLE_SECS);
 
-use data_manager::DataManager;
-use rpc_codec::{ClientCodec, ServerCodec};
-use rpc_codec::{RequestMessage, ResponseMessage};
+        self.results.retain(|_, &mut (_, _, used)| used.elapsed() < idle);
+        self.results.insert(query.clone(), (locs, generation, Instant::now()));
 
-pub struct MessageProto;
+        while self.results.len() > MAX_CURSORS {
+            let oldest = self.results.iter().min_by_key(|&(_, &(_, _, used))| used).map(|(q, _)| q.clone()).unwrap();
 
+            self.results.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, query: &Query) {
+        self.results.remove(query);
+    }
+}
+
+pub struct MessageProto {
+    max_frame_size: usize,
+    tls: Option<Arc<RpcTls>> // connections are plaintext when None
+}
+
+impl MessageProto {
+    pub fn new(max_frame_size: usize, tls: Option<Arc<RpcTls>>) -> MessageProto {
+        MessageProto { max_frame_size, tls }
+    }
+}
+
+/// Requests are handled on the pool, so a slow query on one connection doesn't hold up the others
+/// A service is created for each connection, and counted as an open connection until it's dropped
+#[derive(Clone)]
 pub struct RPCService {
-    data_manager: Arc<Mutex<DataManager>>,
+    data_manager: Arc<DataManager>,
+    max_frame_size: usize,
+    drain: Drain,
+    pool: CpuPool,
+    cursors: Arc<Mutex<QueryCursors>>,
+    metrics: Arc<Metrics>,
+    _connection: Arc<GaugeGuard>
 }
 
 impl RPCService {
-    pub fn new(data_manager: Arc<Mutex<DataManager>>) -> RPCService {
+    pub fn new(data_manager: Arc<DataManager>,
+               max_frame_size: usize,
+               drain: Drain,
+               pool: CpuPool,
+               cursors: Arc<Mutex<QueryCursors>>,
+               metrics: Arc<Metrics>) -> RPCService {
         RPCService {
             data_manager: data_manager,
+            max_frame_size,
+            drain,
+            pool,
+            cursors,
+            _connection: Arc::new(Gauge::track(&metrics.rpc_connections)),
+            metrics
+        }
+    }
+
+    /// Returns a page of the logs matching the query starting at offset
+    /// Only the logs in the page are read, so a query matching many logs never has to fit in memory
+    /// A Get whose logs all fit in one page is answered with Logs
+    /// The query is evaluated for the first page, later pages continue through the kept result
+    fn get_page(&self, query: &Query, offset: u32, is_get: bool) -> Result<ResponseMessage, RecordError> {
+        let start = Instant::now();
+        let dm = &self.data_manager;
+
+        let generation = dm.generation();
+
+        // the lock isn't held while the query is evaluated
+        let kept = if offset == 0 { None } else { self.cursors.lock().unwrap().get(query, offset, generation) };
+
+        let locs = match kept {
+            Some(locs) => locs,
+            None => Arc::new(dm.query(query)?)
+        };
+
+        let budget = self.max_frame_size.saturating_sub(FRAME_OVERHEAD);
+
+        let mut page = Vec::new();
+        let mut bytes = 0;
+
+        for loc in locs.iter().skip(offset as usize) {
+            let log = dm.get_log(*loc)?;
+            let size = log_size(&log);
+
+            // always send at least one log, so the query makes progress
+            if !page.is_empty() && (bytes + size > budget || page.len() >= QUERY_PAGE_SIZE) {
+                break;
+            }
+
+            bytes += size;
+            page.push(log);
+        }
+
+        let next = offset as usize + page.len();
+
+        debug!("Returning logs {} to {} of {}", offset, next, locs.len());
+
+        self.metrics.rpc_query_seconds.observe(start.elapsed());
+
+        if next < locs.len() {
+            self.cursors.lock().unwrap().insert(query, locs.clone(), generation);
+        } else if offset != 0 {
+            self.cursors.lock().unwrap().remove(query);
+        }
+
+        if next < locs.len() {
+            Ok(ResponseMessage::LogsPage(page, next as u32))
+        } else if is_get {
+            Ok(ResponseMessage::Logs(page))
+        } else {
+            Ok(ResponseMessage::LogsDone(page, QuerySummary { total: locs.len() as u32 }))
+        }
+    }
+
+    fn handle(&self, req: RequestMessage) -> Result<ResponseMessage, RecordError> {
+        match req {
+            RequestMessage::Insert(log) => self.data_manager
+                .insert(&log)
+                .map(|()| ResponseMessage::Ok),
+            RequestMessage::InsertAll(logs) => self.data_manager
+                .insert_all(&logs)
+                .map(|results| {
+                    ResponseMessage::InsertResults(results.into_iter().map(|r| r.map_err(|e| e.to_string())).collect())
+                }),
+            RequestMessage::Get(key, value) => self.get_page(&Query::Term(key, value), 0, true),
+            RequestMessage::GetFrom(key, value, offset) => self.get_page(&Query::Term(key, value), offset, false),
+            RequestMessage::Search(query, offset) => self.get_page(&query, offset, false),
+            RequestMessage::Ping => Ok(ResponseMessage::Pong),
+            RequestMessage::Summary(range) => self.data_manager
+                .ids_in_range(range.start, range.end)
+                .map(|ids| ResponseMessage::Summary(summarize(&ids_for_range(ids, &range)))),
+            RequestMessage::Ids(range) => self.data_manager
+                .ids_in_range(range.start, range.end)
+                .map(|ids| ResponseMessage::Ids(ids_for_range(ids, &range).into_iter().map(|(id, _)| id).collect())),
+            RequestMessage::Scan(location, limit) => self.data_manager
+                .scan(location, limit as usize, self.max_frame_size.saturating_sub(FRAME_OVERHEAD))
+                .map(|(logs, next)| ResponseMessage::Scan(logs, next)),
+            RequestMessage::Stats => self.data_manager
+                .stats()
+                .map(ResponseMessage::Stats),
+            RequestMessage::PutMapping(update) => self.data_manager
+                .put_mapping(&update)
+                .map(ResponseMessage::Mapping),
         }
     }
 }
 
-impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for MessageProto {
+impl ServerProto<TcpStream> for MessageProto {
     // For this protocol style, `Request` matches the `Item` type of the codec's `Decoder`
     type Request = RequestMessage;
 
@@ -38,23 +231,64 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for MessageProto {
     type Response = ResponseMessage;
 
     // A bit of boilerplate to hook in the codec:
-    type Transport = Framed<T, ServerCodec>;
-    type BindTransport = Result<Self::Transport, IOError>;
-
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(ServerCodec::new()))
+    type Transport = Framed<RpcStream, ServerCodec>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = IOError>>;
+
+    // Complete the TLS handshake if configured, then read the client's handshake,
+    // and reply with the agreed version before any messages
+    fn bind_transport(&self, io: TcpStream) -> Self::BindTransport {
+        let ours = Handshake::ours();
+
+        let max_frame_size = self.max_frame_size;
+
+        Box::new(tls::accept(&self.tls, io).and_then(|io| read_exact(io, [0; HANDSHAKE_LEN])).and_then(move |(io, buf)| {
+            let agreed = Handshake::from_bytes(&buf).and_then(|theirs| ours.negotiate(&theirs));
+
+            // on failure reply with our own handshake, so the client can report why
+            let reply = match agreed {
+                Ok(ref a) => a.to_bytes(),
+                Err(_) => ours.to_bytes()
+            };
+
+            write_all(io, reply).and_then(move |(io, _)| {
+                match agreed {
+                    Ok(a) => {
+                        debug!("Accepted connection with protocol version {}, capabilities {:#x}", a.max_version, a.capabilities);
+                        Ok(io.framed(ServerCodec::with_max_frame_size(max_frame_size).with_compression(a.has(CAP_COMPRESSION))))
+                    },
+                    Err(e) => {
+                        warn!("Rejected connection: {}", e);
+                        Err(e)
+                    }
+                }
+            })
+        }))
     }
 }
 
-impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for MessageProto {
+impl ClientProto<TcpStream> for MessageProto {
     type Request = RequestMessage;
     type Response = ResponseMessage;
 
-    type Transport = Framed<T, ClientCodec>;
-    type BindTransport = Result<Self::Transport, IOError>;
+    type Transport = Framed<RpcStream, ClientCodec>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = IOError>>;
+
+    // Complete the TLS handshake if configured, then send our handshake,
+    // and wait for the server to agree to a version before any messages
+    fn bind_transport(&self, io: TcpStream) -> Self::BindTransport {
+        let ours = Handshake::ours();
+        let max_frame_size = self.max_frame_size;
+
+        Box::new(tls::connect(&self.tls, io)
+            .and_then(move |io| write_all(io, ours.to_bytes()))
+            .and_then(|(io, _)| read_exact(io, [0; HANDSHAKE_LEN]))
+            .and_then(move |(io, buf)| {
+                let agreed = ours.negotiate(&Handshake::from_bytes(&buf)?)?;
+
+                debug!("Connected with protocol version {}, capabilities {:#x}", agreed.max_version, agreed.capabilities);
 
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(ClientCodec::new()))
+                Ok(io.framed(ClientCodec::with_max_frame_size(max_frame_size).with_compression(agreed.has(CAP_COMPRESSION))))
+            }))
     }
 }
 
@@ -73,78 +307,596 @@ impl Service for RPCService {
     fn call(&self, req: Self::Request) -> Self::Future {
         debug!("Request: {:?}", req);
 
-        let ret = match req {
-            RequestMessage::Insert(log) => self.data_manager
-                .lock()
-                .unwrap()
-                .insert(&log)
-                .map(|()| ResponseMessage::Ok),
-            RequestMessage::Get(key, value) => self.data_manager
-                .lock()
-                .unwrap()
-                .get(&key, &value)
-                .map(|v| {
-                    debug!("LOG: {:?}", v);
-                    ResponseMessage::Logs(v)
-                }),
-        }.map_err(|e| {
-            IOError::new(ErrorKind::InvalidData, format!("Error: {}", e.to_string()))
-        });
-
-        Box::new(future::result(ret))
+        // once shutting down, tell the client to try again elsewhere or later
+        let guard = match self.drain.start() {
+            Some(g) => g,
+            None => return Box::new(future::ok(ResponseMessage::Error {
+                code: ErrorCode::Overload,
+                message: String::from("Node is shutting down"),
+                retryable: true
+            }))
+        };
+
+        let service = self.clone();
+
+        Box::new(self.pool.spawn_fn(move || {
+            // failures are returned to the client, rather than tearing down the connection
+            let resp = match service.handle(req) {
+                Ok(resp) => resp,
+                Err(e) => {
+                    warn!("Error handling request: {}", e.to_string());
+                    error_response(&e)
+                }
+            };
+
+            drop(guard);
+
+            Ok::<ResponseMessage, IOError>(resp)
+        }))
     }
 }
 
-pub fn run_rpc_server(dm: Arc<Mutex<DataManager>>) {
-    let addr = "0.0.0.0:12345".parse().unwrap();
+/// Converts a RecordError into an Error response, classifying it for the client
+pub fn error_response(err: &RecordError) -> ResponseMessage {
+    let (code, retryable) = match err {
+        &RecordError::Io(ref e) => match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => (ErrorCode::Overload, true),
+            ErrorKind::InvalidInput => (ErrorCode::Validation, false),
+            _ => (ErrorCode::Storage, false)
+        },
+        &RecordError::Encode(_) => (ErrorCode::Validation, false),
+        &RecordError::Decode(_) => (ErrorCode::Storage, false),
+    };
+
+    ResponseMessage::Error { code, message: err.to_string(), retryable }
+}
+
+/// Serves RPC requests until shutdown resolves, then stops accepting connections
+/// and waits up to drain_timeout for the requests in flight to finish
+/// Requests that outlive the timeout are waited for before returning, so the DataManager isn't closed under them
+pub fn run_rpc_server<F>(dm: Arc<DataManager>,
+                         addr: SocketAddr,
+                         tls: Option<Arc<RpcTls>>,
+                         max_frame_size: usize,
+                         metrics: Arc<Metrics>,
+                         shutdown: F,
+                         drain_timeout: Duration) -> Result<(), IOError> where F: Future<Item=()> {
+    if tls.is_none() {
+        warn!("RPC server is not using TLS; logs are sent in plaintext");
+    }
+
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let listener = TcpListener::bind(&addr, &handle)?;
+    let proto = MessageProto::new(max_frame_size, tls);
+    let drain = Drain::new();
+    let workers = Arc::new((Mutex::new(0usize), Condvar::new())); // threads of the pool that are running
+    let pool = pool_with_count(workers.clone());
+    let cursors = Arc::new(Mutex::new(QueryCursors::new()));
+
+    debug!("Starting RPC server on {}", addr);
+
+    let server_drain = drain.clone();
+    let server_handle = handle.clone();
+
+    let server = listener.incoming().for_each(move |(socket, peer)| {
+        debug!("RPC connection from {}", peer);
+
+        proto.bind_server(&server_handle, socket, RPCService::new(dm.clone(), max_frame_size, server_drain.clone(), pool.clone(), cursors.clone(), metrics.clone()));
+
+        Ok(())
+    });
+
+    // dropping the server closes the listener
+    match core.run(server.select2(shutdown)) {
+        Ok(Either::A(_)) | Err(Either::A(_)) => warn!("RPC server stopped accepting connections"),
+        Ok(Either::B(_)) | Err(Either::B(_)) => info!("Shutting down RPC server")
+    }
+
+    drain.stop();
+
+    if core.run(drain.wait(&handle, drain_timeout)) == Ok(true) {
+        info!("RPC server finished all requests");
+    }
+
+    // dropping the connections drops the last handles to the pool, once its threads
+    // finish the requests they're running, they stop
+    drop(core);
+
+    let &(ref count, ref stopped) = &*workers;
+    let mut count = count.lock().unwrap();
 
-    let server = TcpServer::new(MessageProto, addr);
+    if *count > 0 {
+        info!("Waiting for the requests still running on the RPC pool");
+    }
+
+    while *count > 0 {
+        count = stopped.wait(count).unwrap();
+    }
+
+    Ok( () )
+}
 
+/// A pool that keeps count of its running threads, notifying the condition variable as each one stops
+fn pool_with_count(workers: Arc<(Mutex<usize>, Condvar)>) -> CpuPool {
+    let started = workers.clone();
 
-    debug!("Starting RPC server");
+    Builder::new()
+        .name_prefix("rpc-")
+        .after_start(move || *started.0.lock().unwrap() += 1)
+        .before_stop(move || {
+            let &(ref count, ref stopped) = &*workers;
 
-    server.serve(move || Ok(RPCService::new(dm.clone())));
+            *count.lock().unwrap() -= 1;
+            stopped.notify_all();
+        })
+        .create()
 }
 
 type Connection = ClientService<TcpStream, MessageProto>;
 
+/// Number of hints sent to a node in each InsertAll when replaying
+const HINT_REPLAY_BATCH_SIZE: usize = 500;
+
 pub struct RPCClient {
     address: String,
-    conn: Connection
+    socket_addr: SocketAddr,
+    handle: Handle,
+    conn: Rc<RefCell<Option<Connection>>>, // None when the node is down and we need to reconnect
+    health: Rc<RefCell<NodeHealth>>,
+    hints: Option<Rc<RefCell<HintFile>>>,   // logs to deliver once the node is back up
+    replaying: Rc<Cell<bool>>,              // true while hints are being replayed
+    max_frame_size: usize,
+    tls: Option<Arc<RpcTls>>
 }
 
 impl RPCClient {
-    pub fn new(address: String, core: &mut Core) -> RPCClient {
+    pub fn new(address: String, tls: Option<Arc<RpcTls>>, max_frame_size: usize, core: &mut Core) -> RPCClient {
         let socket_addr = address.parse().unwrap();
 
         // create a handle for the connection
         let handle = core.handle();
 
-        let connection_future = TcpClient::new(MessageProto).connect(&socket_addr, &handle);
+        let connection_future = TcpClient::new(MessageProto::new(max_frame_size, tls.clone())).connect(&socket_addr, &handle);
 
-        // establish this connection
-        let conn = core.run(connection_future).unwrap();
+        // establish this connection, a node that is down is retried by the heartbeat
+        let (conn, health) = match core.run(connection_future) {
+            Ok(conn) => (Some(conn), NodeHealth::new(NodeState::Up)),
+            Err(e) => {
+                warn!("Unable to connect to {}: {}", address, e);
+                (None, NodeHealth::new(NodeState::Down))
+            }
+        };
 
         RPCClient {
             address,
-            conn
+            socket_addr,
+            handle,
+            conn: Rc::new(RefCell::new(conn)),
+            health: Rc::new(RefCell::new(health)),
+            hints: None,
+            replaying: Rc::new(Cell::new(false)),
+            max_frame_size,
+            tls
         }
     }
 
+    /// The largest frame that can be sent to the node
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Stores hints for this node in the given directory while it is unavailable
+    pub fn enable_hints(&mut self, dir_path: &Path, node_id: u32) -> Result<(), RecordError> {
+        self.hints = Some(Rc::new(RefCell::new(HintFile::new(dir_path, node_id)?)));
+
+        Ok( () )
+    }
+
+    /// Stores a log to be replayed to this node once it is back up
+    /// Returns false if hints are not enabled or the hint could not be written
+    pub fn add_hint(&self, log: &HashMap<String, LogValue>) -> bool {
+        store_hint(&self.hints, log)
+    }
+
+    pub fn hints(&self) -> Option<Rc<RefCell<HintFile>>> {
+        self.hints.clone()
+    }
+
+    /// The number of hints waiting to be replayed to this node
+    pub fn pending_hints(&self) -> u32 {
+        match self.hints {
+            Some(ref h) => h.borrow().len(),
+            None => 0
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn state(&self) -> NodeState {
+        self.health.borrow().state()
+    }
+
+    pub fn health(&self) -> NodeHealth {
+        self.health.borrow().clone()
+    }
+
     pub fn make_request(&self, req: RequestMessage) -> Box<Future<Item=ResponseMessage, Error=IOError>> {
-        return Box::new(self.conn.call(req));
+        send_request(&self.conn, &self.health, &self.address, req)
+    }
+
+    /// Streams the logs matching the key and value a page at a time, so they never have to fit in memory
+    /// The summary is sent with the last page
+    pub fn stream_logs(&self, key: String, value: LogValue) -> Box<Stream<Item=(Vec<HashMap<String, LogValue>>, Option<QuerySummary>), Error=IOError>> {
+        self.stream_pages(move |offset| RequestMessage::GetFrom(key.clone(), value.clone(), offset))
+    }
+
+    /// Streams the logs matching the query a page at a time, like stream_logs
+    pub fn stream_query(&self, query: Query) -> Box<Stream<Item=(Vec<HashMap<String, LogValue>>, Option<QuerySummary>), Error=IOError>> {
+        self.stream_pages(move |offset| RequestMessage::Search(query.clone(), offset))
+    }
+
+    /// Sends the request for each page, made from its offset, until the last page is returned
+    fn stream_pages<F>(&self, page_request: F) -> Box<Stream<Item=(Vec<HashMap<String, LogValue>>, Option<QuerySummary>), Error=IOError>>
+        where F: Fn(u32) -> RequestMessage + 'static {
+        let conn = self.conn.clone();
+        let health = self.health.clone();
+        let address = self.address.clone();
+
+        Box::new(stream::unfold(Some(0), move |offset| {
+            let offset = offset?;
+            let req = page_request(offset);
+
+            Some(send_request(&conn, &health, &address, req).and_then(|resp| {
+                match resp {
+                    ResponseMessage::LogsPage(logs, next) => Ok( ((logs, None), Some(next)) ),
+                    ResponseMessage::LogsDone(logs, summary) => Ok( ((logs, Some(summary)), None) ),
+                    ResponseMessage::Error { message, .. } => Err(IOError::new(ErrorKind::Other, message)),
+                    r => Err(IOError::new(ErrorKind::InvalidData, format!("Unexpected response to a query: {:?}", r)))
+                }
+            }))
+        }))
+    }
+
+    /// Gets the state of the node's log file and indices
+    pub fn stats(&self) -> Box<Future<Item=StorageStats, Error=IOError>> {
+        Box::new(self.make_request(RequestMessage::Stats).and_then(|resp| {
+            match resp {
+                ResponseMessage::Stats(stats) => Ok(stats),
+                ResponseMessage::Error { message, .. } => Err(IOError::new(ErrorKind::Other, message)),
+                r => Err(IOError::new(ErrorKind::InvalidData, format!("Unexpected response to Stats: {:?}", r)))
+            }
+        }))
+    }
+
+    /// Changes the node's mapping, returning the new mapping
+    /// A mapping the node refuses is returned as an error of kind InvalidInput
+    pub fn put_mapping(&self, update: MappingUpdate) -> Box<Future<Item=Mapping, Error=IOError>> {
+        Box::new(self.make_request(RequestMessage::PutMapping(update)).and_then(|resp| {
+            match resp {
+                ResponseMessage::Mapping(mapping) => Ok(mapping),
+                ResponseMessage::Error { code: ErrorCode::Validation, message, .. } => Err(IOError::new(ErrorKind::InvalidInput, message)),
+                ResponseMessage::Error { message, .. } => Err(IOError::new(ErrorKind::Other, message)),
+                r => Err(IOError::new(ErrorKind::InvalidData, format!("Unexpected response to PutMapping: {:?}", r)))
+            }
+        }))
+    }
+
+    /// Gets all of the logs matching the key and value, reading every page
+    pub fn get_all(&self, key: String, value: LogValue) -> Box<Future<Item=Vec<HashMap<String, LogValue>>, Error=IOError>> {
+        Box::new(self.stream_logs(key, value).map(|(logs, _)| logs).concat2())
+    }
+
+    /// Gets all of the logs matching the query, reading every page
+    pub fn query_all(&self, query: Query) -> Box<Future<Item=Vec<HashMap<String, LogValue>>, Error=IOError>> {
+        Box::new(self.stream_query(query).map(|(logs, _)| logs).concat2())
+    }
+
+    /// Gets up to `size` of the logs matching the query, reading only the pages needed
+    /// Resolves to the logs, and whether more logs matched
+    pub fn query_first(&self, query: Query, size: usize) -> Box<Future<Item=(Vec<HashMap<String, LogValue>>, bool), Error=IOError>> {
+        let pages = self.stream_query(query);
+
+        Box::new(future::loop_fn((pages, Vec::new()), move |(pages, mut logs)| {
+            pages.into_future().map_err(|(e, _)| e).map(move |(page, pages)| {
+                match page {
+                    None => Loop::Break((logs, false)),
+                    Some((page, _)) => {
+                        logs.extend(page);
+
+                        if logs.len() > size {
+                            logs.truncate(size);
+                            Loop::Break((logs, true))
+                        } else {
+                            Loop::Continue((pages, logs))
+                        }
+                    }
+                }
+            })
+        }))
+    }
+
+    /// Sends a Ping to the node, or attempts to reconnect if the node is down
+    pub fn heartbeat(&self) -> Box<Future<Item=(), Error=()>> {
+        if self.conn.borrow().is_none() {
+            return self.reconnect();
+        }
+
+        let timeout = match Timeout::new(Duration::from_secs(HEARTBEAT_TIMEOUT_SECS), &self.handle) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Unable to create heartbeat timeout: {}", e);
+                return Box::new(future::ok(()));
+            }
+        };
+
+        let address = self.address.clone();
+        let conn = self.conn.clone();
+        let health = self.health.clone();
+
+        Box::new(self.make_request(RequestMessage::Ping).select2(timeout).then(move |res| {
+            match res {
+                Ok(Either::A(_)) => debug!("Heartbeat from {}", address),
+                Ok(Either::B(_)) => {
+                    warn!("Heartbeat to {} timed out", address);
+                    health.borrow_mut().record_failure();
+                },
+                Err(Either::A((e, _))) => warn!("Heartbeat to {} failed: {}", address, e),
+                Err(Either::B((e, _))) => error!("Heartbeat timer error: {}", e)
+            }
+
+            // drop the connection so the next heartbeat reconnects
+            if health.borrow().state() == NodeState::Down {
+                *conn.borrow_mut() = None;
+            }
+
+            Ok(())
+        }))
+    }
+
+    /// Sends the stored hints to the node a batch at a time, in the order they were stored
+    /// The hints are removed once the node acknowledges them, a batch it didn't get is left for the next replay
+    pub fn replay_hints(&self) -> Box<Future<Item=(), Error=()>> {
+        let hints = match self.hints {
+            Some(ref h) => h.clone(),
+            None => return Box::new(future::ok(()))
+        };
+
+        if self.replaying.get() || hints.borrow().len() == 0 {
+            return Box::new(future::ok(()));
+        }
+
+        let logs = match hints.borrow_mut().read() {
+            Ok(logs) => logs,
+            Err(e) => {
+                error!("Unable to read hints for {}: {}", self.address, e.to_string());
+                return Box::new(future::ok(()));
+            }
+        };
+
+        info!("Replaying {} hints to {}", logs.len(), self.address);
+
+        self.replaying.set(true);
+
+        let total = logs.len();
+        let max_frame_size = self.max_frame_size;
+
+        let batches = logs.chunks(HINT_REPLAY_BATCH_SIZE)
+            .flat_map(|batch| frame_chunks(batch.to_vec(), max_frame_size, log_size))
+            .collect::<Vec<_>>();
+
+        let conn = self.conn.clone();
+        let health = self.health.clone();
+        let address = self.address.clone();
+
+        // the error is the number of hints acknowledged before the batch the node didn't get
+        let replay = stream::iter_ok::<_, usize>(batches).fold(0, move |acked, batch| {
+            let len = batch.len();
+
+            send_request(&conn, &health, &address, RequestMessage::InsertAll(batch)).then(move |res| {
+                // a log the node rejected would be rejected again, so it's acknowledged too
+                let delivered = match res {
+                    Err(e) => { warn!("Error replaying hints: {}", e); false },
+                    Ok(ResponseMessage::Error { message, retryable, .. }) => { warn!("Error replaying hints: {}", message); !retryable },
+                    Ok(_) => true
+                };
+
+                if delivered { Ok(acked + len) } else { Err(acked) }
+            })
+        });
+
+        let address = self.address.clone();
+        let replaying = self.replaying.clone();
+
+        Box::new(replay.then(move |res| {
+            let acked = match res {
+                Ok(n) | Err(n) => n
+            };
+
+            let mut hint_file = hints.borrow_mut();
+
+            if acked > 0 {
+                if let Err(e) = hint_file.remove(acked as u32) {
+                    error!("Unable to remove the hints replayed to {}: {}", address, e.to_string());
+                }
+            }
+
+            info!("Replayed {} of {} hints to {}; {} remaining", acked, total, address, hint_file.len());
+            replaying.set(false);
+
+            Ok(())
+        }))
+    }
+
+    fn reconnect(&self) -> Box<Future<Item=(), Error=()>> {
+        let address = self.address.clone();
+        let conn = self.conn.clone();
+        let health = self.health.clone();
+
+        let connection_future = TcpClient::new(MessageProto::new(self.max_frame_size, self.tls.clone())).connect(&self.socket_addr, &self.handle);
+
+        Box::new(connection_future.then(move |res| {
+            match res {
+                Ok(c) => {
+                    info!("Reconnected to {}", address);
+                    *conn.borrow_mut() = Some(c);
+                    health.borrow_mut().record_success();
+                },
+                Err(e) => {
+                    debug!("Unable to reconnect to {}: {}", address, e);
+                    health.borrow_mut().record_failure();
+                }
+            }
+
+            Ok(())
+        }))
     }
 }
 
+/// Sends a request over the connection, tracking the health of the node with every request
+fn send_request(conn: &Rc<RefCell<Option<Connection>>>, health: &Rc<RefCell<NodeHealth>>, address: &str, req: RequestMessage) -> Box<Future<Item=ResponseMessage, Error=IOError>> {
+    let resp = match *conn.borrow() {
+        Some(ref conn) => conn.call(req),
+        None => {
+            let err = IOError::new(ErrorKind::NotConnected, format!("Not connected to {}", address));
+            return Box::new(future::err(err));
+        }
+    };
+
+    let health = health.clone();
+
+    Box::new(resp.then(move |res| {
+        match res {
+            Ok(_) => health.borrow_mut().record_success(),
+            Err(_) => { health.borrow_mut().record_failure(); }
+        }
+
+        res
+    }))
+}
+
+/// Periodically sends a heartbeat to every node, updating its health and replaying hints
+pub fn start_heartbeats(handle: &Handle, clients: Rc<HashMap<u32, RPCClient>>) {
+    let interval = Interval::new(Duration::from_secs(HEARTBEAT_INTERVAL_SECS), handle).unwrap();
+    let hb_handle = handle.clone();
+
+    handle.spawn(
+        interval
+            .for_each(move |_| {
+                for client in clients.values() {
+                    hb_handle.spawn(client.heartbeat());
+
+                    // deliver anything stored while the node was unavailable
+                    if client.state() == NodeState::Up && client.pending_hints() > 0 {
+                        hb_handle.spawn(client.replay_hints());
+                    }
+                }
+
+                Ok(())
+            })
+            .map_err(|e| error!("Heartbeat timer failed: {}", e)),
+    );
+}
+
 #[cfg(test)]
 mod tests {
-    use rpc_server::{run_rpc_server};
-    use std::{thread, time};
-    use futures::sync::mpsc;
-    use futures::Sink;
+    use rpc_server::{run_rpc_server, error_response, QueryCursors, RPCClient, MAX_CURSORS};
+    use rpc_codec::{RequestMessage, ResponseMessage, ErrorCode, DEFAULT_MAX_FRAME_SIZE};
+    use cluster::NodeState;
+    use data_manager::DataManager;
+    use metrics::Metrics;
+    use record_error::RecordError;
+    use log_value::LogValue;
+    use query::Query;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::io::{Error as IOError, ErrorKind};
+    use std::thread;
+    use std::time::Duration;
+    use futures::sync::oneshot;
+    use tokio_core::reactor::Core;
 
     #[test]
     fn test_server() {
-        run_rpc_server();
+        let dir = Path::new("/tmp/rpc_server_test");
+
+        remove_dir_all(dir).ok();
+        create_dir_all(dir).unwrap();
+
+        let dm = Arc::new(DataManager::new(dir).unwrap());
+        let addr = "127.0.0.1:24500".parse().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let server = thread::spawn(move || {
+            run_rpc_server(dm, addr, None, DEFAULT_MAX_FRAME_SIZE, Arc::new(Metrics::default()), stopped, Duration::from_secs(1)).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(300)); // let it start listening
+
+        let mut core = Core::new().unwrap();
+        let client = RPCClient::new(String::from("127.0.0.1:24500"), None, DEFAULT_MAX_FRAME_SIZE, &mut core);
+
+        match core.run(client.make_request(RequestMessage::Ping)).unwrap() {
+            ResponseMessage::Pong => (),
+            r => panic!("Unexpected response: {:?}", r)
+        }
+
+        assert_eq!(client.state(), NodeState::Up);
+
+        stop.send(()).unwrap();
+        server.join().unwrap();
+
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn classify_errors() {
+        let busy = RecordError::from(IOError::new(ErrorKind::TimedOut, "busy"));
+
+        match error_response(&busy) {
+            ResponseMessage::Error { code, retryable, .. } => {
+                assert_eq!(code, ErrorCode::Overload);
+                assert!(retryable);
+            },
+            r => panic!("Unexpected response: {:?}", r)
+        }
+
+        let disk = RecordError::from(IOError::new(ErrorKind::Other, "disk full"));
+
+        match error_response(&disk) {
+            ResponseMessage::Error { code, retryable, .. } => {
+                assert_eq!(code, ErrorCode::Storage);
+                assert!(!retryable);
+            },
+            r => panic!("Unexpected response: {:?}", r)
+        }
+    }
+
+    #[test]
+    fn cursors() {
+        let mut cursors = QueryCursors::new();
+        let query = Query::Term(String::from("key"), LogValue::String(String::from("value")));
+
+        assert_eq!(cursors.get(&query, 1, 0), None);
+
+        cursors.insert(&query, Arc::new(vec![1, 2, 3]), 0);
+
+        assert_eq!(cursors.get(&query, 3, 0), Some(Arc::new(vec![1, 2, 3])));
+        assert_eq!(cursors.get(&query, 4, 0), None); // a result shorter than the offset is evaluated again
+        assert_eq!(cursors.get(&query, 1, 1), None); // as is one from before logs were expired
+
+        cursors.remove(&query);
+
+        assert_eq!(cursors.get(&query, 1, 0), None);
+
+        // no more than MAX_CURSORS results are kept
+        for i in 0..MAX_CURSORS + 1 {
+            cursors.insert(&Query::Term(String::from("key"), LogValue::String(i.to_string())), Arc::new(vec![i as u64]), 0);
+        }
+
+        assert_eq!(cursors.results.len(), MAX_CURSORS);
     }
 }
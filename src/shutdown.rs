@@ -0,0 +1,186 @@
+use futures::{future, Async, Future, Poll, Stream};
+use futures::future::Either;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// How often a draining server checks for requests still in flight
+const DRAIN_POLL_MS: u64 = 50;
+
+/// Tracks the requests a server is handling, so it can finish them before shutting down
+/// Once stopped, new requests are refused
+#[derive(Clone, Default)]
+pub struct Drain {
+    stopping: Arc<AtomicBool>,
+    in_flight: Arc<AtomicUsize>
+}
+
+/// Counts a request as in flight until it's dropped
+pub struct RequestGuard {
+    in_flight: Arc<AtomicUsize>
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl RequestGuard {
+    /// Keeps the request in flight until the stream ends, for a response whose body is produced after its head
+    pub fn hold<S: Stream>(self, stream: S) -> GuardedStream<S> {
+        GuardedStream { stream, guard: Some(self) }
+    }
+}
+
+/// A stream that counts its request as in flight until it ends or is dropped
+pub struct GuardedStream<S> {
+    stream: S,
+    guard: Option<RequestGuard>
+}
+
+impl<S: Stream> Stream for GuardedStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let res = self.stream.poll();
+
+        match res {
+            Ok(Async::Ready(None)) | Err(_) => self.guard = None,
+            _ => ()
+        }
+
+        res
+    }
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        Drain::default()
+    }
+
+    /// Refuses any new requests
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Starts a request, or returns None if the server is stopping
+    pub fn start(&self) -> Option<RequestGuard> {
+        if self.is_stopping() {
+            return None;
+        }
+
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        Some(RequestGuard { in_flight: self.in_flight.clone() })
+    }
+
+    /// Counts more work for a request that was already started, even once the server is stopping
+    pub fn track(&self) -> RequestGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        RequestGuard { in_flight: self.in_flight.clone() }
+    }
+
+    /// Resolves once no requests have been in flight for two checks in a row, so their responses
+    /// have had a turn to be written, or after the timeout
+    /// Resolves to true if every request finished
+    pub fn wait(&self, handle: &Handle, timeout: Duration) -> Box<Future<Item=bool, Error=()>> {
+        let (interval, timer) = match (Interval::new(Duration::from_millis(DRAIN_POLL_MS), handle), Timeout::new(timeout, handle)) {
+            (Ok(i), Ok(t)) => (i, t),
+            _ => {
+                error!("Unable to create drain timers");
+                return Box::new(future::ok(self.in_flight() == 0));
+            }
+        };
+
+        let in_flight = self.in_flight.clone();
+        let mut idle_checks = 0;
+
+        let drained = interval
+            .take_while(move |_| {
+                idle_checks = if in_flight.load(Ordering::SeqCst) == 0 { idle_checks + 1 } else { 0 };
+
+                Ok(idle_checks < 2)
+            })
+            .for_each(|_| Ok(()));
+
+        let in_flight = self.in_flight.clone();
+
+        Box::new(drained.select2(timer).then(move |res| {
+            match res {
+                Ok(Either::A(_)) => Ok::<bool, ()>(true),
+                Ok(Either::B(_)) => {
+                    warn!("Timed out with {} requests in flight", in_flight.load(Ordering::SeqCst));
+                    Ok(false)
+                },
+                Err(_) => Ok(false)
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::shutdown::Drain;
+
+    use futures::{stream, Async, Stream};
+    use tokio_core::reactor::Core;
+    use std::time::Duration;
+
+    #[test]
+    fn refuses_when_stopped() {
+        let drain = Drain::new();
+        let guard = drain.start();
+
+        assert!(guard.is_some());
+        assert_eq!(drain.in_flight(), 1);
+
+        drain.stop();
+
+        assert!(drain.start().is_none());
+
+        drop(guard);
+
+        assert_eq!(drain.in_flight(), 0);
+    }
+
+    #[test]
+    fn wait_times_out() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let drain = Drain::new();
+
+        let guard = drain.start();
+
+        assert_eq!(core.run(drain.wait(&handle, Duration::from_millis(200))), Ok(false));
+
+        drop(guard);
+
+        assert_eq!(core.run(drain.wait(&handle, Duration::from_secs(5))), Ok(true));
+    }
+
+    #[test]
+    fn held_until_stream_ends() {
+        let drain = Drain::new();
+        let mut body = drain.start().unwrap().hold(stream::iter_ok::<_, ()>(vec![1, 2]));
+
+        assert_eq!(body.poll(), Ok(Async::Ready(Some(1))));
+        assert_eq!(body.poll(), Ok(Async::Ready(Some(2))));
+        assert_eq!(drain.in_flight(), 1);
+
+        assert_eq!(body.poll(), Ok(Async::Ready(None)));
+        assert_eq!(drain.in_flight(), 0);
+    }
+}
@@ -0,0 +1,273 @@
+use rustls::{Certificate, ClientConfig, ClientSession, PrivateKey, RootCertStore, ServerConfig, ServerSession};
+use rustls::AllowAnyAuthenticatedClient;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{ClientConfigExt, ServerConfigExt, TlsStream};
+use futures::{future, Future, Poll};
+use webpki::DNSNameRef;
+
+use std::fs::File;
+use std::io::{BufReader, Error as IOError, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The name every node's certificate is issued for, as nodes are addressed by IP
+pub const DEFAULT_SERVER_NAME: &str = "logstore";
+
+/// The TLS configuration for RPC connections, in both directions
+/// Both sides present a certificate signed by the cluster's CA, and verify the other's
+pub struct RpcTls {
+    server: Arc<ServerConfig>,
+    client: Arc<ClientConfig>,
+    server_name: String
+}
+
+impl RpcTls {
+    /// Loads the CA certificate, and this node's certificate chain and PKCS8 private key, all PEM encoded
+    /// `server_name` is the DNS name in the nodes' certificates that clients verify
+    pub fn load(ca_path: &Path, cert_path: &Path, key_path: &Path, server_name: &str) -> Result<RpcTls, IOError> {
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_pem_file(&mut BufReader::new(File::open(ca_path)?))
+            .map_err(|_| invalid(ca_path, "CA certificate"))?;
+
+        if added == 0 {
+            return Err(invalid(ca_path, "CA certificate"));
+        }
+
+        let cert_chain = load_certs(cert_path)?;
+        let key = load_key(key_path)?;
+
+        if DNSNameRef::try_from_ascii_str(server_name).is_err() {
+            return Err(IOError::new(ErrorKind::InvalidInput, format!("Invalid TLS server name: {}", server_name)));
+        }
+
+        // only accept clients with a certificate signed by our CA
+        let mut server = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots.clone()));
+
+        server.set_single_cert(cert_chain.clone(), key.clone());
+
+        let mut client = ClientConfig::new();
+
+        client.root_store = roots;
+        client.set_single_client_cert(cert_chain, key);
+
+        Ok(RpcTls {
+            server: Arc::new(server),
+            client: Arc::new(client),
+            server_name: server_name.to_owned()
+        })
+    }
+
+    /// Performs the server side of the TLS handshake on an accepted connection
+    pub fn accept(&self, io: TcpStream) -> Box<Future<Item=RpcStream, Error=IOError>> {
+        Box::new(self.server.accept_async(io).map(RpcStream::Server))
+    }
+
+    /// Performs the client side of the TLS handshake on a new connection
+    pub fn connect(&self, io: TcpStream) -> Box<Future<Item=RpcStream, Error=IOError>> {
+        // checked when loaded
+        let name = DNSNameRef::try_from_ascii_str(&self.server_name).unwrap();
+
+        Box::new(self.client.connect_async(name, io).map(RpcStream::Client))
+    }
+}
+
+fn invalid(path: &Path, what: &str) -> IOError {
+    IOError::new(ErrorKind::InvalidData, format!("No valid {} found in {}", what, path.display()))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, IOError> {
+    match certs(&mut BufReader::new(File::open(path)?)) {
+        Ok(ref c) if c.is_empty() => Err(invalid(path, "certificate")),
+        Ok(c) => Ok(c),
+        Err(_) => Err(invalid(path, "certificate"))
+    }
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, IOError> {
+    match pkcs8_private_keys(&mut BufReader::new(File::open(path)?)) {
+        Ok(mut keys) => keys.pop().ok_or_else(|| invalid(path, "PKCS8 private key")),
+        Err(_) => Err(invalid(path, "PKCS8 private key"))
+    }
+}
+
+/// Performs the server side of the handshake if TLS is configured, otherwise the connection is used as is
+pub fn accept(tls: &Option<Arc<RpcTls>>, io: TcpStream) -> Box<Future<Item=RpcStream, Error=IOError>> {
+    match *tls {
+        Some(ref tls) => tls.accept(io),
+        None => Box::new(future::ok(RpcStream::Plain(io)))
+    }
+}
+
+/// Performs the client side of the handshake if TLS is configured, otherwise the connection is used as is
+pub fn connect(tls: &Option<Arc<RpcTls>>, io: TcpStream) -> Box<Future<Item=RpcStream, Error=IOError>> {
+    match *tls {
+        Some(ref tls) => tls.connect(io),
+        None => Box::new(future::ok(RpcStream::Plain(io)))
+    }
+}
+
+/// A connection between nodes, either plaintext or TLS
+pub enum RpcStream {
+    Plain(TcpStream),
+    Server(TlsStream<TcpStream, ServerSession>),
+    Client(TlsStream<TcpStream, ClientSession>)
+}
+
+impl Read for RpcStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        match *self {
+            RpcStream::Plain(ref mut s) => s.read(buf),
+            RpcStream::Server(ref mut s) => s.read(buf),
+            RpcStream::Client(ref mut s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for RpcStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        match *self {
+            RpcStream::Plain(ref mut s) => s.write(buf),
+            RpcStream::Server(ref mut s) => s.write(buf),
+            RpcStream::Client(ref mut s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        match *self {
+            RpcStream::Plain(ref mut s) => s.flush(),
+            RpcStream::Server(ref mut s) => s.flush(),
+            RpcStream::Client(ref mut s) => s.flush()
+        }
+    }
+}
+
+impl AsyncRead for RpcStream {}
+
+impl AsyncWrite for RpcStream {
+    fn shutdown(&mut self) -> Poll<(), IOError> {
+        match *self {
+            RpcStream::Plain(ref mut s) => AsyncWrite::shutdown(s),
+            RpcStream::Server(ref mut s) => AsyncWrite::shutdown(s),
+            RpcStream::Client(ref mut s) => AsyncWrite::shutdown(s)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::tls::{RpcStream, RpcTls, DEFAULT_SERVER_NAME};
+
+    use futures::{Future, Stream};
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use tokio_io::io::{read_exact, write_all};
+    use tokio_rustls::ClientConfigExt;
+    use webpki::DNSNameRef;
+
+    use std::fs::{remove_dir_all, File};
+    use std::io::{BufReader, Error as IOError, ErrorKind};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::Arc;
+
+    /// Generates a CA and a node certificate with scripts/gen_certs.sh, so no private key is kept in the repository
+    fn gen_certs(dir: &str) -> PathBuf {
+        let dir = PathBuf::from(dir);
+
+        remove_dir_all(&dir).ok();
+
+        let output = Command::new("sh").arg("scripts/gen_certs.sh").arg(&dir).output().unwrap();
+
+        assert!(output.status.success(), "gen_certs.sh failed: {}", String::from_utf8_lossy(&output.stderr));
+
+        dir
+    }
+
+    fn load(dir: &Path) -> RpcTls {
+        RpcTls::load(&dir.join("ca.pem"), &dir.join("node.pem"), &dir.join("node.key"), DEFAULT_SERVER_NAME).unwrap()
+    }
+
+    /// Accepts a connection over a socket with the server's TLS, echoing 4 bytes back to a client that connects with `connect`
+    /// Returns the result of each side
+    fn handshake<F>(server: RpcTls, connect: F) -> (Result<(), IOError>, Result<(), IOError>)
+        where F: FnOnce(TcpStream) -> Box<Future<Item=RpcStream, Error=IOError>> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server_side = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(conn, _)| conn.ok_or_else(|| IOError::new(ErrorKind::UnexpectedEof, "No connection")))
+            .and_then(move |(io, _)| server.accept(io))
+            .and_then(|stream| read_exact(stream, [0u8; 4]))
+            .and_then(|(stream, buf)| write_all(stream, buf))
+            .map(|_| ());
+
+        let client_side = TcpStream::connect(&addr, &handle)
+            .and_then(connect)
+            .and_then(|stream| write_all(stream, *b"ping"))
+            .and_then(|(stream, _)| read_exact(stream, [0u8; 4]))
+            .map(|(_, buf)| assert_eq!(&buf, b"ping"));
+
+        core.run(server_side.then(Ok::<_, ()>).join(client_side.then(Ok::<_, ()>))).unwrap()
+    }
+
+    #[test]
+    fn load_certs() {
+        let dir = gen_certs("/tmp/tls_load_test");
+
+        assert!(RpcTls::load(&dir.join("ca.pem"), &dir.join("node.pem"), &dir.join("node.key"), DEFAULT_SERVER_NAME).is_ok());
+
+        // the certificate is not a key, and a missing file is an error not a panic
+        assert!(RpcTls::load(&dir.join("ca.pem"), &dir.join("node.pem"), &dir.join("node.pem"), DEFAULT_SERVER_NAME).is_err());
+        assert!(RpcTls::load(&dir.join("missing.pem"), &dir.join("node.pem"), &dir.join("node.key"), DEFAULT_SERVER_NAME).is_err());
+    }
+
+    #[test]
+    fn mutual_tls() {
+        let dir = gen_certs("/tmp/tls_mutual_test");
+        let client = load(&dir);
+
+        let (server, client) = handshake(load(&dir), move |io| client.connect(io));
+
+        assert!(server.is_ok(), "server failed: {:?}", server);
+        assert!(client.is_ok(), "client failed: {:?}", client);
+    }
+
+    #[test]
+    fn rejects_unauthenticated_clients() {
+        let dir = gen_certs("/tmp/tls_reject_test");
+
+        // a client that trusts the CA, but has no certificate of its own
+        let mut roots = RootCertStore::empty();
+
+        roots.add_pem_file(&mut BufReader::new(File::open(dir.join("ca.pem")).unwrap())).unwrap();
+
+        let mut config = ClientConfig::new();
+
+        config.root_store = roots;
+
+        let config = Arc::new(config);
+
+        let (server, _) = handshake(load(&dir), move |io| -> Box<Future<Item=RpcStream, Error=IOError>> {
+            let name = DNSNameRef::try_from_ascii_str(DEFAULT_SERVER_NAME).unwrap();
+
+            Box::new(config.connect_async(name, io).map(RpcStream::Client))
+        });
+
+        assert!(server.is_err());
+
+        // a client whose certificate is signed by another CA
+        let other = load(&gen_certs("/tmp/tls_other_ca_test"));
+
+        let (server, client) = handshake(load(&dir), move |io| other.connect(io));
+
+        assert!(server.is_err());
+        assert!(client.is_err());
+    }
+}
@@ -8,3 +8,36 @@ pub fn buf2string(buf: &[u8]) -> String {
 
     return ret;
 }
+
+/// A new, empty directory for a test, unique to the test and the process running it, removed when it's dropped
+/// Declare it before anything that writes to it on drop, so it's dropped last
+#[cfg(test)]
+pub struct TestDir(::std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let dir = ::std::env::temp_dir().join(format!("logstore_{}_{}", name, ::std::process::id()));
+
+        ::std::fs::remove_dir_all(&dir).ok();
+        ::std::fs::create_dir_all(&dir).unwrap();
+
+        TestDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl ::std::ops::Deref for TestDir {
+    type Target = ::std::path::Path;
+
+    fn deref(&self) -> &::std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        ::std::fs::remove_dir_all(&self.0).ok();
+    }
+}